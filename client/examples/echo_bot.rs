@@ -0,0 +1,33 @@
+//! Echoes every message sent to a room back to it.
+//!
+//! ```sh
+//! cargo run -p os3-chat-client --example echo_bot -- http://localhost:3000 <token> <room id>
+//! ```
+
+use os3_chat_client::Client;
+use os3_chat_client::event::Event;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(base_url), Some(token), Some(room_id)) = (args.next(), args.next(), args.next())
+    else {
+        eprintln!("usage: echo_bot <base url> <token> <room id>");
+        std::process::exit(2);
+    };
+
+    let client = Client::with_token(&base_url, &token)?;
+    let room_id = room_id.parse()?;
+    let mut socket = client.connect(room_id).await?;
+    while let Some(event) = socket.next_event().await {
+        match event? {
+            Event::Message(message) if !message.sender_is_bot && !message.is_system => {
+                if let Some(text) = &message.text {
+                    socket.send_message(text, Some(message.id)).await?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use reqwest::StatusCode;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid server URL")]
+    InvalidUrl(#[from] url::ParseError),
+    #[error("the username or password was rejected")]
+    LoginRejected,
+    #[error("the token was rejected")]
+    Unauthorized,
+    #[error("the invite can't be used anymore")]
+    InviteUnusable,
+    #[error("server responded with {0}")]
+    Status(StatusCode),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    WebSocket(#[from] Box<tokio_tungstenite::tungstenite::Error>),
+    #[error("malformed event: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(error))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,208 @@
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Debug)]
+#[must_use]
+pub struct Room {
+    pub room_id: i64,
+    pub room_name: String,
+    pub topic: Option<String>,
+    pub archived: bool,
+    pub unread_count: i64,
+    pub mention_count: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[must_use]
+pub struct ChatMessage {
+    pub id: i64,
+    pub sender: String,
+    pub sender_display_name: Option<String>,
+    pub sender_is_bot: bool,
+    pub room_id: i64,
+    /// The Markdown the message was written in.
+    pub text: Option<String>,
+    pub text_html: Option<String>,
+    /// Whether this records a change made to the room rather than something
+    /// the sender said.
+    pub is_system: bool,
+    pub sent_at: NaiveDateTime,
+    pub upload_filename: Option<String>,
+    pub upload_url: Option<String>,
+    pub reply_to: Option<ReplyPreview>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[must_use]
+pub struct ReplyPreview {
+    pub id: i64,
+    pub sender: String,
+    pub text_preview: String,
+}
+
+/// Everything the server sends over a room's socket.
+///
+/// Only the events bots are likely to care about are spelled out, anything
+/// else is [`Event::Other`].
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[must_use]
+pub enum Event {
+    Message(Box<ChatMessage>),
+    /// Someone mentioned this account, in any room.
+    Mention {
+        room_id: i64,
+        room_name: String,
+        message: Box<ChatMessage>,
+    },
+    MemberJoined {
+        room_id: i64,
+        username: String,
+    },
+    MemberLeft {
+        room_id: i64,
+        username: String,
+    },
+    RoomUpdated {
+        room_id: i64,
+        name: String,
+        topic: Option<String>,
+        archived: bool,
+    },
+    RoomDeleted {
+        room_id: i64,
+    },
+    Typing {
+        room_id: i64,
+        username: String,
+    },
+    /// A command's answer, only sent to the socket that ran it.
+    CommandReply {
+        room_id: i64,
+        text: String,
+    },
+    /// Something sent over the socket was rejected, e.g. an unknown command.
+    Error {
+        room_id: i64,
+        code: String,
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE: These are what the server actually sends, and the server checks
+    // that its serialization still matches them.
+    const MESSAGE_JSON: &str = include_str!("../fixtures/message.json");
+    const ROOM_LIST_JSON: &str = include_str!("../fixtures/room_list.json");
+
+    fn event(json: &str) -> Event {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn assert_is_sample_message(message: &ChatMessage) {
+        assert_eq!(message.id, 42);
+        assert_eq!(message.sender, "alice");
+        assert_eq!(message.sender_display_name.as_deref(), Some("Alice"));
+        assert!(!message.sender_is_bot);
+        assert_eq!(message.room_id, 1);
+        assert_eq!(message.text.as_deref(), Some("Hello **there**"));
+        assert!(!message.is_system);
+        assert_eq!(message.sent_at.to_string(), "2026-10-18 12:30:05");
+        assert_eq!(message.upload_filename.as_deref(), Some("cat.png"));
+        let reply_to = message.reply_to.as_ref().unwrap();
+        assert_eq!((reply_to.id, reply_to.sender.as_str()), (41, "bob"));
+    }
+
+    #[test]
+    fn messages_are_understood() {
+        let message: ChatMessage = serde_json::from_str(MESSAGE_JSON).unwrap();
+        assert_is_sample_message(&message);
+
+        let Event::Message(message) =
+            event(&MESSAGE_JSON.replacen('{', r#"{"type":"message","#, 1))
+        else {
+            panic!("expected a message");
+        };
+        assert_is_sample_message(&message);
+
+        let mention = format!(
+            r#"{{"type":"mention","room_id":1,"room_name":"general","message":{MESSAGE_JSON}}}"#
+        );
+        let Event::Mention {
+            room_id: 1,
+            room_name,
+            message,
+        } = event(&mention)
+        else {
+            panic!("expected a mention");
+        };
+        assert_eq!(room_name, "general");
+        assert_is_sample_message(&message);
+    }
+
+    #[test]
+    fn room_events_are_understood() {
+        assert!(matches!(
+            event(r#"{"type":"member_joined","room_id":1,"username":"carol"}"#),
+            Event::MemberJoined { room_id: 1, username } if username == "carol"
+        ));
+        assert!(matches!(
+            event(r#"{"type":"member_left","room_id":1,"username":"carol"}"#),
+            Event::MemberLeft { room_id: 1, username } if username == "carol"
+        ));
+        assert!(matches!(
+            event(
+                r#"{"type":"room_updated","room_id":1,"name":"general","topic":"Anything goes","description":null,"archived":false,"visibility":"open"}"#
+            ),
+            Event::RoomUpdated { room_id: 1, name, topic: Some(topic), archived: false }
+                if name == "general" && topic == "Anything goes"
+        ));
+        assert!(matches!(
+            event(r#"{"type":"room_deleted","room_id":1}"#),
+            Event::RoomDeleted { room_id: 1 }
+        ));
+        assert!(matches!(
+            event(r#"{"type":"typing","room_id":1,"username":"bob","expires_in_ms":5000}"#),
+            Event::Typing { room_id: 1, username } if username == "bob"
+        ));
+    }
+
+    #[test]
+    fn answers_to_the_socket_are_understood() {
+        assert!(matches!(
+            event(r#"{"type":"command_reply","room_id":1,"text":"You rolled 4"}"#),
+            Event::CommandReply { room_id: 1, text } if text == "You rolled 4"
+        ));
+        assert!(matches!(
+            event(
+                r#"{"type":"error","room_id":1,"code":"unknown_command","message":"no such command: /foo"}"#
+            ),
+            Event::Error { room_id: 1, code, .. } if code == "unknown_command"
+        ));
+    }
+
+    #[test]
+    fn other_events_are_tolerated() {
+        assert!(matches!(
+            event(r#"{"type":"presence","room_id":1,"username":"bob","status":"away"}"#),
+            Event::Other
+        ));
+    }
+
+    #[test]
+    fn room_lists_are_understood() {
+        let rooms: Vec<Room> = serde_json::from_str(ROOM_LIST_JSON).unwrap();
+        let [room] = &rooms[..] else {
+            panic!("expected a single room, got {rooms:?}");
+        };
+        assert_eq!((room.room_id, room.room_name.as_str()), (1, "general"));
+        assert_eq!(room.topic, None);
+        assert!(!room.archived);
+        assert_eq!((room.unread_count, room.mention_count), (3, 1));
+    }
+}
@@ -0,0 +1,310 @@
+//! A small client for `os3_chat`, meant for writing bots.
+//!
+//! Bots are created by an admin, who is handed the bot's token once:
+//!
+//! ```no_run
+//! # async fn run() -> os3_chat_client::error::Result<()> {
+//! use os3_chat_client::Client;
+//! use os3_chat_client::event::Event;
+//!
+//! let client = Client::with_token("https://chat.example.com", "<token>")?;
+//! let mut socket = client.connect(1).await?;
+//! while let Some(event) = socket.next_event().await {
+//!     if let Event::Message(message) = event? {
+//!         println!("{}: {:?}", message.sender, message.text);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#![allow(clippy::missing_errors_doc)]
+
+pub mod error;
+pub mod event;
+pub mod socket;
+
+use reqwest::header::{AUTHORIZATION, HeaderValue, LOCATION, SET_COOKIE};
+use reqwest::redirect::Policy;
+use reqwest::{Response, StatusCode};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::event::{ChatMessage, Room};
+use crate::socket::RoomSocket;
+
+/// Matches the name of the server's session cookie.
+const SESSION_COOKIE_NAME: &str = "session-token";
+
+/// Where the server sends requests without a valid session.
+const LOGIN_PAGE_PATH: &str = "/account";
+/// Where the server sends those who accepted an invite it can't honour.
+const INVITE_PAGE_PREFIX: &str = "/invite/";
+/// Where the server sends those who entered a room.
+const ROOM_PAGE_PREFIX: &str = "/chat/";
+
+/// The most messages [`Client::history`] can fetch at once.
+pub const MAX_HISTORY_LIMIT: u16 = 500;
+
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    token: String,
+}
+
+impl Client {
+    /// A client authenticating with a bot's token.
+    pub fn with_token(base_url: &str, token: &str) -> Result<Self> {
+        Ok(Self {
+            http: self::http_client()?,
+            base_url: Url::parse(base_url)?,
+            token: token.to_string(),
+        })
+    }
+
+    /// Logs in to a regular account with its password.
+    pub async fn login(base_url: &str, username: &str, password: &str) -> Result<Self> {
+        let base_url = Url::parse(base_url)?;
+        let http = self::http_client()?;
+        let response = http
+            .post(base_url.join("/account/form/submit")?)
+            .form(&[
+                ("username", username),
+                ("password", password),
+                ("action", "login"),
+            ])
+            .send()
+            .await?;
+
+        // NOTE: The login form answers with a redirect either way, only
+        // setting the session cookie if it succeeded.
+        let token = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(|cookie| {
+                let (name, rest) = cookie.split_once('=')?;
+                let value = rest.split(';').next()?;
+                (name.trim() == SESSION_COOKIE_NAME && !value.is_empty()).then(|| value.to_string())
+            })
+            .ok_or(Error::LoginRejected)?;
+        Ok(Self {
+            http,
+            base_url,
+            token,
+        })
+    }
+
+    /// The token the client authenticates with.
+    #[must_use]
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// The rooms the account is a member of.
+    pub async fn rooms(&self) -> Result<Vec<Room>> {
+        let response = self.get("/api/room/list").await?;
+        Ok(response.json().await?)
+    }
+
+    /// Joins a room that is open to everyone.
+    pub async fn join(&self, room_id: i64) -> Result<()> {
+        self.post(&format!("/api/room/{room_id}/join")).await?;
+        Ok(())
+    }
+
+    pub async fn leave(&self, room_id: i64) -> Result<()> {
+        self.post(&format!("/api/room/{room_id}/leave")).await?;
+        Ok(())
+    }
+
+    /// Joins a room through one of its invite codes. Invites that are used
+    /// up, expired or revoked, or lead to an archived room, are
+    /// [`Error::InviteUnusable`].
+    pub async fn accept_invite(&self, code: &str) -> Result<()> {
+        self.post(&format!("/invite/{code}")).await?;
+        Ok(())
+    }
+
+    /// Up to `limit` messages of the room, oldest first. Passing the ID of
+    /// the oldest one as `before` pages further back.
+    ///
+    /// The server hands out between 1 and [`MAX_HISTORY_LIMIT`] messages at
+    /// once, and `limit` is clamped to that range.
+    pub async fn history(
+        &self,
+        room_id: i64,
+        before: Option<i64>,
+        limit: u16,
+    ) -> Result<Vec<ChatMessage>> {
+        let mut url = self.url(&format!("/api/room/{room_id}/messages"))?;
+        let limit = limit.clamp(1, MAX_HISTORY_LIMIT);
+        url.query_pairs_mut()
+            .append_pair("limit", &limit.to_string());
+        if let Some(before) = before {
+            url.query_pairs_mut()
+                .append_pair("before", &before.to_string());
+        }
+        let response = self.request(self.http.get(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Opens the room's socket, over which events are received and messages
+    /// sent.
+    pub async fn connect(&self, room_id: i64) -> Result<RoomSocket> {
+        let mut url = self.url(&format!("/chat/{room_id}/websocket"))?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme)
+            .map_err(|()| Error::InvalidUrl(url::ParseError::EmptyHost))?;
+
+        let mut request = url.as_str().into_client_request()?;
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, self.authorization()?);
+        let (stream, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(RoomSocket { room_id, stream })
+    }
+
+    /// Sends a single message to the room, without keeping a socket open.
+    pub async fn send(&self, room_id: i64, text: &str) -> Result<()> {
+        let mut socket = self.connect(room_id).await?;
+        socket.send_message(text, None).await?;
+        socket.close().await
+    }
+
+    fn url(&self, path: &str) -> Result<Url> {
+        Ok(self.base_url.join(path)?)
+    }
+
+    fn authorization(&self) -> Result<HeaderValue> {
+        HeaderValue::from_str(&format!("Bearer {}", self.token)).map_err(|_| Error::Unauthorized)
+    }
+
+    async fn get(&self, path: &str) -> Result<Response> {
+        self.request(self.http.get(self.url(path)?)).await
+    }
+
+    async fn post(&self, path: &str) -> Result<Response> {
+        self.request(self.http.post(self.url(path)?)).await
+    }
+
+    async fn request(&self, request: reqwest::RequestBuilder) -> Result<Response> {
+        let response = request
+            .header(AUTHORIZATION, self.authorization()?)
+            .send()
+            .await?;
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok());
+        self.check_status(response.status(), location)?;
+        Ok(response)
+    }
+
+    fn check_status(&self, status: StatusCode, location: Option<&str>) -> Result<()> {
+        if !status.is_redirection() {
+            return match status {
+                StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+                status if status.is_success() => Ok(()),
+                status => Err(Error::Status(status)),
+            };
+        }
+
+        // NOTE: Redirects only count as success when they lead into a room,
+        // like the one after accepting an invite. An expired session isn't
+        // rejected outright but sent off to the login page, and an invite
+        // that can't be used back to its preview page.
+        let path = location
+            .and_then(|location| self.base_url.join(location).ok())
+            .map(|location| location.path().to_string())
+            .unwrap_or_default();
+        if path == LOGIN_PAGE_PATH {
+            Err(Error::Unauthorized)
+        } else if path.starts_with(INVITE_PAGE_PREFIX) {
+            Err(Error::InviteUnusable)
+        } else if path
+            .strip_prefix(ROOM_PAGE_PREFIX)
+            .is_some_and(|room_id| room_id.parse::<i64>().is_ok())
+        {
+            Ok(())
+        } else {
+            Err(Error::Status(status))
+        }
+    }
+}
+
+fn http_client() -> Result<reqwest::Client> {
+    // NOTE: Redirects are answers of their own here, like the one to the
+    // room after accepting an invite.
+    Ok(reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_status(status: StatusCode, location: Option<&str>) -> Result<()> {
+        Client::with_token("https://chat.example.com", "token")
+            .unwrap()
+            .check_status(status, location)
+    }
+
+    #[test]
+    fn successes_and_redirects_into_a_room_are_answers() {
+        assert!(check_status(StatusCode::OK, None).is_ok());
+        assert!(check_status(StatusCode::SEE_OTHER, Some("/chat/1")).is_ok());
+        assert!(
+            check_status(
+                StatusCode::SEE_OTHER,
+                Some("https://chat.example.com/chat/1")
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn redirects_back_to_an_invite_are_unusable_invites() {
+        assert!(matches!(
+            check_status(StatusCode::SEE_OTHER, Some("/invite/AbCd1234")),
+            Err(Error::InviteUnusable)
+        ));
+    }
+
+    #[test]
+    fn rejected_sessions_are_unauthorized() {
+        for (status, location) in [
+            (StatusCode::UNAUTHORIZED, None),
+            (StatusCode::SEE_OTHER, Some("/account")),
+            (
+                StatusCode::SEE_OTHER,
+                Some("https://chat.example.com/account"),
+            ),
+        ] {
+            assert!(
+                matches!(check_status(status, location), Err(Error::Unauthorized)),
+                "{status} {location:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn other_failures_keep_their_status() {
+        for (status, location) in [
+            (StatusCode::FORBIDDEN, None),
+            (StatusCode::SEE_OTHER, Some("/account/settings")),
+            (StatusCode::SEE_OTHER, Some("/chat/general")),
+            (StatusCode::SEE_OTHER, None),
+        ] {
+            assert!(
+                matches!(check_status(status, location), Err(Error::Status(s)) if s == status),
+                "{status} {location:?}"
+            );
+        }
+    }
+}
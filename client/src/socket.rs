@@ -0,0 +1,84 @@
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::error::Result;
+use crate::event::Event;
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutgoingEvent<'a> {
+    Message {
+        room_id: i64,
+        text: &'a str,
+        reply_to: Option<i64>,
+    },
+    Typing {
+        room_id: i64,
+    },
+}
+
+/// A connection to a room's socket, see [`crate::Client::connect`].
+///
+/// Besides the events of its room, it receives the mentions of the account
+/// from any room.
+#[must_use]
+pub struct RoomSocket {
+    pub(crate) room_id: i64,
+    pub(crate) stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl RoomSocket {
+    #[must_use]
+    pub const fn room_id(&self) -> i64 {
+        self.room_id
+    }
+
+    /// Sends a message to the room, optionally as a reply to another one.
+    pub async fn send_message(&mut self, text: &str, reply_to: Option<i64>) -> Result<()> {
+        self.send(&OutgoingEvent::Message {
+            room_id: self.room_id,
+            text,
+            reply_to,
+        })
+        .await
+    }
+
+    /// Shows the account as typing for a few seconds.
+    pub async fn typing(&mut self) -> Result<()> {
+        self.send(&OutgoingEvent::Typing {
+            room_id: self.room_id,
+        })
+        .await
+    }
+
+    /// Waits for the next event. Returns `None` once the server closed the
+    /// socket.
+    pub async fn next_event(&mut self) -> Option<Result<Event>> {
+        while let Some(message) = self.stream.next().await {
+            match message {
+                Ok(Message::Text(text)) => {
+                    return Some(serde_json::from_str(&text).map_err(Into::into));
+                }
+                Ok(Message::Close(_)) => return None,
+                // NOTE: Pings are answered by tungstenite itself.
+                Ok(_) => {}
+                Err(error) => return Some(Err(error.into())),
+            }
+        }
+        None
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.stream.close(None).await?;
+        Ok(())
+    }
+
+    async fn send(&mut self, event: &OutgoingEvent<'_>) -> Result<()> {
+        let text = serde_json::to_string(event)?;
+        self.stream.send(Message::text(text)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,3 @@
+-- Pixel dimensions of uploaded images, NULL for anything that isn't one.
+ALTER TABLE file_uploads ADD COLUMN width INTEGER;
+ALTER TABLE file_uploads ADD COLUMN height INTEGER;
@@ -0,0 +1,13 @@
+-- Track who uploaded a file, where, and how much space it takes up.
+ALTER TABLE file_uploads ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE file_uploads ADD COLUMN uploader TEXT REFERENCES accounts(username) ON DELETE SET NULL;
+ALTER TABLE file_uploads ADD COLUMN room_id INTEGER REFERENCES rooms(id) ON DELETE SET NULL;
+
+-- NOTE: Sizes of existing uploads are unknown at this point and stay at 0,
+-- but their ownership can be recovered from the messages that carry them.
+UPDATE file_uploads SET
+    uploader = (SELECT m.sender FROM messages m WHERE m.file_upload_uuid = file_uploads.uuid),
+    room_id = (SELECT m.room_id FROM messages m WHERE m.file_upload_uuid = file_uploads.uuid);
+
+CREATE INDEX file_uploads_by_uploader ON file_uploads(uploader);
+CREATE INDEX file_uploads_by_room ON file_uploads(room_id);
@@ -0,0 +1,7 @@
+-- Uploads whose file has been removed from disk keep their row as a
+-- tombstone, so that the messages referencing them stay intact.
+ALTER TABLE file_uploads ADD COLUMN deleted_at DATETIME;
+
+-- Number of days after which attachments sent into a room are removed,
+-- NULL to keep them forever.
+ALTER TABLE rooms ADD COLUMN attachment_retention_days INTEGER;
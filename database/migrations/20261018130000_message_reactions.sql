@@ -0,0 +1,10 @@
+CREATE TABLE message_reactions (
+    message_id INTEGER NOT NULL,
+    account TEXT NOT NULL,
+    emoji TEXT NOT NULL,
+    reacted_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY(message_id, account, emoji),
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(account) REFERENCES accounts(username) ON DELETE CASCADE
+);
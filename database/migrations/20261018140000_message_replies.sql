@@ -0,0 +1,4 @@
+-- The message this one is a reply to, within the same room.
+ALTER TABLE messages ADD COLUMN reply_to INTEGER REFERENCES messages(id) ON DELETE SET NULL;
+
+CREATE INDEX messages_by_reply_to ON messages(reply_to);
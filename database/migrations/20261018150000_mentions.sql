@@ -0,0 +1,11 @@
+CREATE TABLE mentions (
+    message_id INTEGER NOT NULL,
+    account TEXT NOT NULL,
+    read_at DATETIME,
+
+    PRIMARY KEY(message_id, account),
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(account) REFERENCES accounts(username) ON DELETE CASCADE
+);
+
+CREATE INDEX mentions_by_account ON mentions(account, read_at);
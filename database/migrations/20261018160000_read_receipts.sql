@@ -0,0 +1,6 @@
+-- The newest message in the room a member has seen, NULL if none yet.
+--
+-- NOTE: This deliberately isn't a foreign key. A marker whose message got
+-- deleted would otherwise be cleared, making the whole room unread again,
+-- while message IDs only ever grow, so it still says what has been read.
+ALTER TABLE room_membership ADD COLUMN last_read_message_id INTEGER;
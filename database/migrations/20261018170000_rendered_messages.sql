@@ -0,0 +1,3 @@
+-- Sanitized HTML rendered from the Markdown in `text`. Messages stored before
+-- this column existed are rendered on the fly instead.
+ALTER TABLE messages ADD COLUMN text_html TEXT;
@@ -0,0 +1,3 @@
+-- Code blocks are now highlighted, so drop the HTML rendered without it and
+-- let messages get rendered (and cached) again on their next read.
+UPDATE messages SET text_html = NULL;
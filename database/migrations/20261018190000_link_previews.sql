@@ -0,0 +1,20 @@
+-- NOTE: Previews are cached by URL, failed fetches included, so that a link
+-- posted in many messages is only fetched once in a while.
+CREATE TABLE link_previews (
+    url TEXT PRIMARY KEY NOT NULL,
+    title TEXT,
+    description TEXT,
+    image_url TEXT,
+    fetch_failed BOOLEAN NOT NULL DEFAULT FALSE,
+    fetched_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE message_link_previews (
+    message_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    position INTEGER NOT NULL,
+
+    PRIMARY KEY(message_id, url),
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(url) REFERENCES link_previews(url) ON DELETE CASCADE
+);
@@ -0,0 +1,24 @@
+ALTER TABLE room_membership ADD COLUMN role TEXT NOT NULL DEFAULT 'member'
+    CHECK (role IN ('owner', 'moderator', 'member'));
+
+-- NOTE: Rooms used to have no owner, but their creator was always the first
+-- one to join them. The public room stays without an owner.
+UPDATE room_membership SET role = 'owner'
+WHERE room_id != 1 AND (member, joined_at) = (
+    SELECT first.member, first.joined_at FROM room_membership first
+    WHERE first.room_id = room_membership.room_id
+    ORDER BY first.joined_at, first.member
+    LIMIT 1
+);
+
+CREATE TABLE pinned_messages (
+    room_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    pinned_by TEXT NOT NULL,
+    pinned_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY(room_id, message_id),
+    FOREIGN KEY(room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(pinned_by) REFERENCES accounts(username) ON DELETE CASCADE
+);
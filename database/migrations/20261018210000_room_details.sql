@@ -0,0 +1,8 @@
+ALTER TABLE rooms ADD COLUMN topic TEXT;
+ALTER TABLE rooms ADD COLUMN description TEXT;
+-- NOTE: Archived rooms stay readable, but nothing new can be posted in them.
+ALTER TABLE rooms ADD COLUMN archived_at DATETIME;
+
+-- NOTE: System messages record changes made to the room itself, such as a new
+-- name or topic, with the member who made the change as their sender.
+ALTER TABLE messages ADD COLUMN is_system BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+-- NOTE: Private rooms can only be entered by invitation, listed rooms show up
+-- in the directory but still need one, and open rooms can be joined by anyone.
+ALTER TABLE rooms ADD COLUMN visibility TEXT NOT NULL DEFAULT 'private'
+    CHECK (visibility IN ('private', 'listed', 'open'));
+
+UPDATE rooms SET visibility = 'open' WHERE id = 1;
@@ -0,0 +1,23 @@
+CREATE TABLE room_invites (
+    code TEXT NOT NULL PRIMARY KEY,
+    room_id INTEGER NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- NOTE: Either limit being NULL means that there is no such limit.
+    max_uses INTEGER,
+    expires_at DATETIME,
+    revoked_at DATETIME,
+
+    FOREIGN KEY(room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY(created_by) REFERENCES accounts(username) ON DELETE CASCADE
+);
+
+CREATE TABLE room_invite_uses (
+    code TEXT NOT NULL,
+    account TEXT NOT NULL,
+    joined_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY(code, account),
+    FOREIGN KEY(code) REFERENCES room_invites(code) ON DELETE CASCADE,
+    FOREIGN KEY(account) REFERENCES accounts(username) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+CREATE TABLE signup_codes (
+    code TEXT NOT NULL PRIMARY KEY,
+    created_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- NOTE: A code is spent once `used_at` is set, even if the account that
+    -- used it is deleted later on.
+    used_by TEXT,
+    used_at DATETIME,
+
+    FOREIGN KEY(created_by) REFERENCES accounts(username) ON DELETE CASCADE,
+    FOREIGN KEY(used_by) REFERENCES accounts(username) ON DELETE SET NULL
+);
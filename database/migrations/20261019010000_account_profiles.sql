@@ -0,0 +1,5 @@
+ALTER TABLE accounts ADD COLUMN display_name TEXT;
+ALTER TABLE accounts ADD COLUMN bio TEXT;
+-- NOTE: Avatars are regular uploads that don't belong to any room.
+ALTER TABLE accounts ADD COLUMN avatar_upload_uuid TEXT
+    REFERENCES file_uploads(uuid) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+-- NOTE: Placeholders stand in for accounts that don't exist anymore (or never
+-- did here), so that their messages can stay. Nobody can log in as one.
+ALTER TABLE accounts ADD COLUMN is_placeholder BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,16 @@
+CREATE TABLE incoming_webhooks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    room_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    -- NOTE: The secret part of the webhook's URL.
+    token TEXT NOT NULL UNIQUE,
+    -- NOTE: The placeholder account messages are posted as. It outlives the
+    -- webhook, so that its messages keep a sender.
+    bot_username TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY(room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY(bot_username) REFERENCES accounts(username) ON DELETE CASCADE,
+    FOREIGN KEY(created_by) REFERENCES accounts(username) ON DELETE CASCADE
+);
@@ -0,0 +1,32 @@
+CREATE TABLE outgoing_webhooks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    room_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    -- NOTE: Signs every delivery, so the receiver can tell that it came from us.
+    secret TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY(room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY(created_by) REFERENCES accounts(username) ON DELETE CASCADE
+);
+
+-- NOTE: Doubles as the queue of pending deliveries and, once a delivery has
+-- been given up on, the dead-letter log.
+CREATE TABLE webhook_deliveries (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    webhook_id INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    delivered_at DATETIME,
+    failed_at DATETIME,
+
+    FOREIGN KEY(webhook_id) REFERENCES outgoing_webhooks(id) ON DELETE CASCADE
+);
+
+CREATE INDEX webhook_deliveries_pending ON webhook_deliveries(next_attempt_at)
+WHERE delivered_at IS NULL AND failed_at IS NULL;
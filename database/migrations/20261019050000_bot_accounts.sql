@@ -0,0 +1,5 @@
+ALTER TABLE accounts ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- NOTE: Webhooks used to post as placeholders, they are bots now.
+UPDATE accounts SET is_bot = TRUE, is_placeholder = FALSE
+WHERE username IN (SELECT bot_username FROM incoming_webhooks);
@@ -0,0 +1,3 @@
+-- NOTE: Preview images used to be shown straight from the linked site, which
+-- told it who was reading the room. They are no longer fetched or kept.
+ALTER TABLE link_previews DROP COLUMN image_url;
@@ -0,0 +1,6 @@
+-- When the file was stored, so that uploads whose message or profile isn't
+-- saved yet aren't mistaken for leftovers.
+--
+-- NOTE: SQLite can't add a column defaulting to the current time, so new rows
+-- set it themselves. Existing uploads are old enough not to need it.
+ALTER TABLE file_uploads ADD COLUMN uploaded_at DATETIME;
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{Report, eyre};
+use os3_chat::import::{self, ImportOptions};
+use os3_chat::layers::ErrorLayer;
+use os3_chat::repository::Repository;
+use sqlx::SqlitePool;
+
+/// Administrative tasks run directly against the database.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[arg(long("sqlite-db"), default_value_t = env!("DATABASE_URL").to_string())]
+    database_url: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Import chat history exported from another service into new rooms.
+    Import {
+        #[arg(value_enum)]
+        source: Source,
+
+        /// A Slack export zip, or a `DiscordChatExporter` JSON file.
+        path: PathBuf,
+
+        /// The account owning the created rooms.
+        #[arg(long)]
+        owner: String,
+
+        /// Attribute a foreign user's messages to an existing account, given
+        /// as `FOREIGN=ACCOUNT` with the user's ID or name. Everyone else gets
+        /// a placeholder account.
+        #[arg(long = "map", value_parser = parse_mapping)]
+        user_map: Vec<(String, String)>,
+
+        /// Import into this existing room instead of creating one. Only
+        /// possible for a single channel.
+        #[arg(long = "room")]
+        room_id: Option<i64>,
+
+        /// Only import the named Slack channels.
+        #[arg(long = "channel", value_delimiter = ',')]
+        channels: Vec<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Source {
+    Slack,
+    Discord,
+}
+
+fn parse_mapping(mapping: &str) -> Result<(String, String), String> {
+    mapping
+        .split_once('=')
+        .map(|(foreign, account)| (foreign.to_string(), account.to_string()))
+        .ok_or_else(|| format!("expected FOREIGN=ACCOUNT, got '{mapping}'"))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Report> {
+    ErrorLayer.setup()?;
+
+    let cli = Cli::parse();
+    let db_pool = SqlitePool::connect(&cli.database_url).await?;
+    let repository = Repository::new(db_pool.clone());
+
+    match cli.command {
+        Command::Import {
+            source,
+            path,
+            owner,
+            user_map,
+            room_id,
+            channels,
+        } => {
+            let export = match source {
+                Source::Slack => import::slack::read(&path, &channels)?,
+                Source::Discord if !channels.is_empty() => {
+                    return Err(eyre!("Discord exports hold a single channel"));
+                }
+                Source::Discord => import::discord::read(&path)?,
+            };
+            let options = ImportOptions {
+                owner,
+                user_map: user_map.into_iter().collect::<HashMap<_, _>>(),
+                room_id,
+            };
+            let report = import::import(&repository, &db_pool, export, &options).await?;
+            println!(
+                "Imported {} messages with {} attachments into {} new rooms, created {} placeholder accounts",
+                report.messages, report.attachments, report.rooms, report.placeholders
+            );
+            if report.missing_attachments > 0 {
+                println!(
+                    "{} attachments were not included in the export",
+                    report.missing_attachments
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
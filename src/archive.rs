@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::path::PathBuf;
+
+use axum::body::Body;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use chrono::NaiveDateTime;
+use tokio_util::io::ReaderStream;
+use tracing::instrument;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::repository::upload::Upload;
+
+/// Something to put into a zip archive.
+#[derive(Debug)]
+#[must_use]
+pub enum ArchiveEntry {
+    /// Contents generated on the fly, like a JSON document.
+    Generated { name: String, data: Vec<u8> },
+    /// A file from the upload store, which is read while writing the archive.
+    Stored {
+        name: String,
+        path: PathBuf,
+        modified_at: Option<NaiveDateTime>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Archiving was aborted")]
+    Aborted,
+}
+
+/// A finished archive, ready to be streamed to the client.
+#[derive(Debug)]
+#[must_use]
+pub struct Archive {
+    file: tokio::fs::File,
+    length: u64,
+    filename: String,
+}
+
+impl ArchiveEntry {
+    /// The entry for an upload's stored file, named so that uploads with the
+    /// same filename don't collide. `None` if the file is gone.
+    #[must_use]
+    pub fn for_upload(upload: &Upload) -> Option<Self> {
+        let path = upload.store_path().ok()?;
+        Some(Self::Stored {
+            name: self::upload_entry_name(upload),
+            path,
+            modified_at: None,
+        })
+    }
+}
+
+/// Where [`ArchiveEntry::for_upload`] puts an upload within the archive.
+#[must_use]
+pub fn upload_entry_name(upload: &Upload) -> String {
+    // NOTE: Filenames come from clients, so keep them from naming directories.
+    let filename = upload.filename.to_string_lossy().replace(['/', '\\'], "_");
+    format!("attachments/{}_{filename}", upload.uuid)
+}
+
+impl Archive {
+    /// Writes the entries into a zip file. The archive is written to an
+    /// anonymous temporary file rather than kept in memory, as attachments
+    /// can add up to a lot.
+    #[instrument(skip(entries), fields(entries = entries.len()), err(Debug))]
+    pub async fn build(filename: String, entries: Vec<ArchiveEntry>) -> Result<Self, ArchiveError> {
+        let (file, length) = tokio::task::spawn_blocking(move || self::write_zip(entries))
+            .await
+            .map_err(|_| ArchiveError::Aborted)??;
+        tracing::debug!(length, "Built archive");
+
+        Ok(Self {
+            file: tokio::fs::File::from_std(file),
+            length,
+            filename,
+        })
+    }
+}
+
+fn write_zip(entries: Vec<ArchiveEntry>) -> Result<(File, u64), ArchiveError> {
+    let mut zip = ZipWriter::new(tempfile::tempfile()?);
+    for entry in entries {
+        match entry {
+            ArchiveEntry::Generated { name, data } => {
+                let options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+                zip.start_file(name, options)?;
+                zip.write_all(&data)?;
+            }
+            ArchiveEntry::Stored {
+                name,
+                path,
+                modified_at,
+            } => {
+                // NOTE: Attachments tend to be compressed already.
+                let mut options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+                if let Some(modified_at) = modified_at.and_then(|time| time.try_into().ok()) {
+                    options = options.last_modified_time(modified_at);
+                }
+                let mut source = match File::open(&path) {
+                    Ok(source) => source,
+                    Err(error) => {
+                        tracing::warn!(?path, ?error, "Skipping missing file");
+                        continue;
+                    }
+                };
+                zip.start_file(name, options.large_file(true))?;
+                std::io::copy(&mut source, &mut zip)?;
+            }
+        }
+    }
+
+    let mut file = zip.finish()?;
+    let length = file.stream_position()?;
+    file.rewind()?;
+    Ok((file, length))
+}
+
+impl IntoResponse for Archive {
+    fn into_response(self) -> Response {
+        let disposition = format!("attachment; filename=\"{}\"", self.filename);
+        let Ok(disposition) = HeaderValue::from_str(&disposition) else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let headers = [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/zip"),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CONTENT_LENGTH, self.length.into()),
+        ];
+        (headers, Body::from_stream(ReaderStream::new(self.file))).into_response()
+    }
+}
@@ -1,5 +1,6 @@
 use axum::extract::{FromRef, FromRequestParts};
 use axum::http::StatusCode;
+use axum::http::header::AUTHORIZATION;
 use axum::http::request::Parts;
 use axum::response::{IntoResponse, Redirect};
 use axum_extra::extract::CookieJar;
@@ -35,12 +36,29 @@ where
     #[instrument(name = "auth_layer", skip_all, err(Debug, level = Level::WARN))]
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let state = SharedState::from_ref(state);
-        let cookies = CookieJar::from_headers(&parts.headers);
-        let token: Uuid = cookies
-            .get(SESSION_COOKIE_NAME)
-            .map(Cookie::value_trimmed)
-            .and_then(|v| v.parse().ok())
-            .ok_or(RejectionCause::InvalidSession)?;
+        // NOTE: Bots send their token in a header instead of a cookie. Since
+        // they can't follow a redirect to the login page, they are told that
+        // the token was rejected instead.
+        let bearer_token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token: Uuid = match bearer_token {
+            Some(bearer_token) => bearer_token
+                .trim()
+                .parse()
+                .map_err(|_| RejectionCause::InvalidToken)?,
+            None => CookieJar::from_headers(&parts.headers)
+                .get(SESSION_COOKIE_NAME)
+                .map(Cookie::value_trimmed)
+                .and_then(|v| v.parse().ok())
+                .ok_or(RejectionCause::InvalidSession)?,
+        };
+        let rejection = |cookie_cause| match bearer_token {
+            Some(_) => RejectionCause::InvalidToken,
+            None => cookie_cause,
+        };
 
         let token_string = token.to_string();
         let session = query!(
@@ -49,12 +67,12 @@ where
         )
         .fetch_one(&state.db_pool)
         .await
-        .map_err(|_| RejectionCause::ExpiredSession)?;
+        .map_err(|_| rejection(RejectionCause::ExpiredSession))?;
 
         let account_record = query!("SELECT * FROM accounts WHERE username = ?", session.account)
             .fetch_one(&state.db_pool)
             .await
-            .map_err(|_| RejectionCause::InvalidSession)?;
+            .map_err(|_| rejection(RejectionCause::InvalidSession))?;
 
         let authorized_account = AuthorizedAccount {
             username: account_record.username,
@@ -62,7 +80,7 @@ where
             session_token: token,
         };
 
-        tracing::trace!(?authorized_account, "Cookie-based auth completed");
+        tracing::trace!(?authorized_account, "Session auth completed");
 
         Ok(Self(authorized_account))
     }
@@ -74,6 +92,7 @@ pub enum RejectionCause {
     NoSessionCookie,
     InvalidSession,
     ExpiredSession,
+    InvalidToken,
     InternalServerError,
 }
 
@@ -82,6 +101,7 @@ impl IntoResponse for RejectionCause {
         let redirect = Redirect::to("/account");
         match self {
             Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Self::InvalidToken => StatusCode::UNAUTHORIZED.into_response(),
             Self::InvalidSession | Self::NoSessionCookie | Self::ExpiredSession => {
                 redirect.into_response()
             }
@@ -0,0 +1,453 @@
+//! Slash commands, typed into the message box like `/topic Release day`.
+//!
+//! Messages starting with a slash never reach the room as they are. They are
+//! looked up in [`COMMANDS`] instead, whose handlers may post to the room,
+//! change it, or answer only the sender. A message starting with two slashes
+//! is sent as a regular one, with the first slash removed.
+
+use futures::future::{self, BoxFuture};
+use rand_core::{OsRng, RngCore};
+use tracing::instrument;
+
+use crate::endpoints::chat::{self, RoomEvent};
+use crate::markdown;
+use crate::repository::room::Room;
+use crate::room_actions::{self, RoomActionError};
+use crate::state::SharedState;
+
+/// Dice rolls are limited to this many dice, with at most this many sides.
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+const SHRUG: &str = r"¯\_(ツ)_/¯";
+
+/// A command being run by a member of a room.
+#[derive(Debug)]
+#[must_use]
+pub struct Invocation<'a> {
+    pub state: &'a SharedState,
+    pub room: &'a Room,
+    pub sender: &'a str,
+    /// Everything after the command's name, trimmed.
+    pub args: &'a str,
+    /// The message the command was sent as a reply to, if any.
+    pub reply_to: Option<i64>,
+}
+
+/// What a handler has to tell the sender, if anything. Everyone else only
+/// learns about what the command did.
+pub type CommandResult = Result<Option<String>, CommandError>;
+
+type Handler = for<'a> fn(&'a Invocation<'a>) -> BoxFuture<'a, CommandResult>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    handler: Handler,
+}
+
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "/help",
+        summary: "Lists the available commands",
+        handler: |_| Box::pin(future::ready(Ok(Some(self::help())))),
+    },
+    Command {
+        name: "me",
+        usage: "/me <action>",
+        summary: "Describes what you are doing",
+        handler: |invocation| Box::pin(self::me(invocation)),
+    },
+    Command {
+        name: "shrug",
+        usage: "/shrug [message]",
+        summary: r"Appends ¯\_(ツ)_/¯ to the message",
+        handler: |invocation| Box::pin(self::shrug(invocation)),
+    },
+    Command {
+        name: "roll",
+        usage: "/roll [<count>d<sides>]",
+        summary: "Rolls dice, a single six-sided one by default",
+        handler: |invocation| Box::pin(self::roll(invocation)),
+    },
+    Command {
+        name: "topic",
+        usage: "/topic [topic]",
+        summary: "Sets the room's topic, or clears it",
+        handler: |invocation| Box::pin(self::topic(invocation)),
+    },
+    Command {
+        name: "invite",
+        usage: "/invite <username>",
+        summary: "Adds someone to the room",
+        handler: |invocation| Box::pin(self::invite(invocation)),
+    },
+    Command {
+        name: "kick",
+        usage: "/kick <username>",
+        summary: "Removes someone from the room",
+        handler: |invocation| Box::pin(self::kick(invocation)),
+    },
+    Command {
+        name: "leave",
+        usage: "/leave",
+        summary: "Leaves the room",
+        handler: |invocation| Box::pin(self::leave(invocation)),
+    },
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("there is no /{0} command, see /help (start with // to send a message as it is)")]
+    Unknown(String),
+    #[error("usage: {0}")]
+    Usage(&'static str),
+    #[error("{0}")]
+    Forbidden(&'static str),
+    #[error("{0} is not a member of this room")]
+    NotAMember(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("something went wrong, try again later")]
+    Internal,
+}
+
+impl CommandError {
+    /// Identifies the kind of error to clients.
+    #[must_use]
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::Unknown(_) => "unknown_command",
+            Self::Usage(_) => "invalid_arguments",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotAMember(_) => "not_found",
+            Self::Conflict(_) => "conflict",
+            Self::Internal => "internal_error",
+        }
+    }
+}
+
+impl From<RoomActionError> for CommandError {
+    fn from(error: RoomActionError) -> Self {
+        match error {
+            RoomActionError::NotAMember(username) => Self::NotAMember(username),
+            RoomActionError::Forbidden(reason) => Self::Forbidden(reason),
+            RoomActionError::NoSuchAccount(_) | RoomActionError::Conflict(_) => {
+                Self::Conflict(error.to_string())
+            }
+            RoomActionError::Internal => Self::Internal,
+        }
+    }
+}
+
+impl From<sqlx::Error> for CommandError {
+    fn from(error: sqlx::Error) -> Self {
+        tracing::error!(?error, "Command failed");
+        Self::Internal
+    }
+}
+
+/// What is left of a message once its command is dealt with.
+#[derive(Debug, PartialEq, Eq)]
+#[must_use]
+pub enum Parsed<'a> {
+    /// Not a command, to be sent as it is.
+    Message(&'a str),
+    Command {
+        name: &'a str,
+        args: &'a str,
+    },
+}
+
+/// Tells commands apart from messages. Only a slash followed by a letter
+/// starts a command, so that e.g. `/` or `/) ` are sent as they are.
+pub fn parse(text: &str) -> Parsed<'_> {
+    if text.starts_with("//") {
+        return Parsed::Message(&text[1..]);
+    }
+    let Some(command) = text
+        .strip_prefix('/')
+        .filter(|command| command.starts_with(|c: char| c.is_ascii_alphabetic()))
+    else {
+        return Parsed::Message(text);
+    };
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    Parsed::Command {
+        name,
+        args: args.trim(),
+    }
+}
+
+/// Runs the named command. Returns the event to send back to the socket the
+/// command came from, if there is anything to tell.
+#[instrument(skip_all, fields(room.id = room.id, sender = sender, command = name))]
+pub async fn dispatch(
+    state: &SharedState,
+    room: &Room,
+    sender: &str,
+    name: &str,
+    args: &str,
+    reply_to: Option<i64>,
+) -> Option<RoomEvent> {
+    let invocation = Invocation {
+        state,
+        room,
+        sender,
+        args,
+        reply_to,
+    };
+    let name = name.to_ascii_lowercase();
+    let result = match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.handler)(&invocation).await,
+        None => Err(CommandError::Unknown(name)),
+    };
+
+    match result {
+        Ok(reply) => reply.map(|text| RoomEvent::CommandReply {
+            room_id: room.id,
+            text,
+        }),
+        Err(error) => {
+            tracing::debug!(%error, "Command was rejected");
+            Some(RoomEvent::Error {
+                room_id: room.id,
+                code: error.code(),
+                message: error.to_string(),
+            })
+        }
+    }
+}
+
+fn help() -> String {
+    let lines: Vec<String> = COMMANDS
+        .iter()
+        .map(|command| format!("{} - {}", command.usage, command.summary))
+        .collect();
+    lines.join("\n")
+}
+
+async fn me(invocation: &Invocation<'_>) -> CommandResult {
+    if invocation.args.is_empty() {
+        return Err(CommandError::Usage("/me <action>"));
+    }
+    let text = format!("{} {}", invocation.sender, invocation.args);
+    self::post_system_message(invocation, &text).await?;
+    Ok(None)
+}
+
+async fn shrug(invocation: &Invocation<'_>) -> CommandResult {
+    let text = match invocation.args {
+        "" => markdown::escape(SHRUG),
+        args => format!("{args} {}", markdown::escape(SHRUG)),
+    };
+    chat::publish_message(
+        invocation.state,
+        invocation.room,
+        invocation.sender,
+        Some(text),
+        invocation.reply_to,
+    )
+    .await?;
+    Ok(None)
+}
+
+async fn roll(invocation: &Invocation<'_>) -> CommandResult {
+    let usage = || CommandError::Usage("/roll [<count>d<sides>], e.g. /roll 2d20");
+    let (count, sides) = self::parse_dice(invocation.args).ok_or_else(usage)?;
+
+    let rolls: Vec<u32> = (0..count).map(|_| OsRng.next_u32() % sides + 1).collect();
+    let total: u32 = rolls.iter().sum();
+    let text = match rolls.as_slice() {
+        [roll] => format!("{} rolled {count}d{sides}: {roll}", invocation.sender),
+        rolls => {
+            let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+            format!(
+                "{} rolled {count}d{sides}: {} = {total}",
+                invocation.sender,
+                rolls.join(" + ")
+            )
+        }
+    };
+    self::post_system_message(invocation, &text).await?;
+    Ok(None)
+}
+
+/// Parses dice like `2d20` into how many there are and how many sides they
+/// have. Without a count there is one, and nothing at all is a single `d6`.
+fn parse_dice(dice: &str) -> Option<(u32, u32)> {
+    let dice = match dice {
+        "" => "1d6",
+        dice => dice,
+    };
+    dice.to_ascii_lowercase()
+        .split_once('d')
+        .and_then(|(count, sides)| {
+            let count = match count {
+                "" => 1,
+                count => count.parse().ok()?,
+            };
+            Some((count, sides.parse().ok()?))
+        })
+        .filter(|&(count, sides)| {
+            (1..=MAX_DICE).contains(&count) && (2..=MAX_SIDES).contains(&sides)
+        })
+}
+
+async fn topic(invocation: &Invocation<'_>) -> CommandResult {
+    room_actions::set_topic(
+        invocation.state,
+        invocation.room,
+        invocation.sender,
+        Some(invocation.args),
+    )
+    .await?;
+    Ok(None)
+}
+
+async fn invite(invocation: &Invocation<'_>) -> CommandResult {
+    let username = self::single_argument(invocation, "/invite <username>")?;
+    room_actions::invite(
+        invocation.state,
+        invocation.room,
+        invocation.sender,
+        username,
+    )
+    .await?;
+    Ok(None)
+}
+
+async fn kick(invocation: &Invocation<'_>) -> CommandResult {
+    let username = self::single_argument(invocation, "/kick <username>")?;
+    room_actions::kick(
+        invocation.state,
+        invocation.room,
+        invocation.sender,
+        username,
+    )
+    .await?;
+    Ok(None)
+}
+
+/// Leaves the room without a reply, as the sender's socket closes once it
+/// learns about the departure anyway.
+async fn leave(invocation: &Invocation<'_>) -> CommandResult {
+    if !invocation.args.is_empty() {
+        return Err(CommandError::Usage("/leave"));
+    }
+    room_actions::leave(invocation.state, invocation.room, invocation.sender).await?;
+    Ok(None)
+}
+
+fn single_argument<'a>(
+    invocation: &Invocation<'a>,
+    usage: &'static str,
+) -> Result<&'a str, CommandError> {
+    let argument = invocation.args.trim_start_matches('@');
+    if argument.is_empty() || argument.contains(char::is_whitespace) {
+        return Err(CommandError::Usage(usage));
+    }
+    Ok(argument)
+}
+
+/// Records what the sender did in the room's timeline.
+async fn post_system_message(invocation: &Invocation<'_>, text: &str) -> sqlx::Result<()> {
+    room_actions::post_system_message(invocation.state, invocation.room, invocation.sender, text)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("/roll  2d20 "),
+            Parsed::Command {
+                name: "roll",
+                args: "2d20"
+            }
+        );
+        assert_eq!(
+            parse("/help"),
+            Parsed::Command {
+                name: "help",
+                args: ""
+            }
+        );
+        assert_eq!(
+            parse("/me\tdances"),
+            Parsed::Command {
+                name: "me",
+                args: "dances"
+            }
+        );
+    }
+
+    #[test]
+    fn leaves_messages_alone() {
+        for text in [
+            "hello",
+            "",
+            "/",
+            "/) smiley",
+            "/ spaced",
+            "a /roll",
+            " /roll",
+        ] {
+            assert_eq!(parse(text), Parsed::Message(text), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn double_slashes_escape_commands() {
+        assert_eq!(parse("//roll 2d6"), Parsed::Message("/roll 2d6"));
+        assert_eq!(parse("//"), Parsed::Message("/"));
+    }
+
+    #[test]
+    fn every_command_has_a_unique_name() {
+        for (index, command) in COMMANDS.iter().enumerate() {
+            assert!(
+                COMMANDS[..index]
+                    .iter()
+                    .all(|other| other.name != command.name),
+                "/{} is registered twice",
+                command.name
+            );
+        }
+    }
+
+    #[test]
+    fn parses_dice() {
+        assert_eq!(parse_dice(""), Some((1, 6)));
+        assert_eq!(parse_dice("2d20"), Some((2, 20)));
+        assert_eq!(parse_dice("D8"), Some((1, 8)));
+        assert_eq!(
+            parse_dice(&format!("{MAX_DICE}d{MAX_SIDES}")),
+            Some((MAX_DICE, MAX_SIDES))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_dice() {
+        for dice in [
+            "d",
+            "2d",
+            "0d6",
+            "2d1",
+            "-1d6",
+            "2x6",
+            "1d6d6",
+            "two d6",
+            &format!("{}d6", MAX_DICE + 1),
+            &format!("1d{}", MAX_SIDES + 1),
+        ] {
+            assert_eq!(parse_dice(dice), None, "{dice:?}");
+        }
+    }
+}
@@ -1,27 +1,48 @@
 use askama::Template;
-use axum::extract::State;
+use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::response::{Html, IntoResponse, Redirect};
-use axum::{Form, debug_handler};
+use axum::{Form, Json, debug_handler};
 use axum_extra::extract::CookieJar;
 use axum_extra::extract::cookie::{Cookie, SameSite};
 use axum_valid::Valid;
-use serde::Deserialize;
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 use validator::Validate;
 
+use crate::RegistrationPolicy;
+use crate::archive::{self, Archive, ArchiveEntry};
 use crate::auth::{SESSION_COOKIE_NAME, Session};
-use crate::repository::account::{LoginError, RegistrationError};
+use crate::endpoints::chat::EchoedMessage;
+use crate::repository::account::{Account, DeletedMessages, LoginError, RegistrationError};
+use crate::repository::room::Membership;
 use crate::state::SharedState;
 
-#[derive(Template)]
+#[derive(Template, Debug)]
 #[template(path = "account.html")]
-pub struct AccountTemplate;
+#[must_use]
+pub struct AccountTemplate {
+    pub registration_open: bool,
+    pub signup_code_required: bool,
+    /// Why the last attempt to register was rejected, if it was.
+    pub problem: Option<&'static str>,
+}
+
+impl AccountTemplate {
+    fn new(policy: RegistrationPolicy, problem: Option<&'static str>) -> Self {
+        Self {
+            registration_open: policy != RegistrationPolicy::Closed,
+            signup_code_required: policy == RegistrationPolicy::InviteCode,
+            problem,
+        }
+    }
+}
 
 #[instrument(skip_all)]
 #[debug_handler]
-pub async fn page() -> Result<impl IntoResponse, StatusCode> {
-    AccountTemplate
+pub async fn page(State(state): State<SharedState>) -> Result<impl IntoResponse, StatusCode> {
+    AccountTemplate::new(state.settings.registration_policy, None)
         .render()
         .map(Html)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
@@ -34,6 +55,9 @@ pub struct CredentialsForm {
     username: String,
     #[validate(length(min = 8, max = 64))]
     password: String,
+    /// Only needed to register while registration is invite-only.
+    #[validate(length(max = 64))]
+    signup_code: Option<String>,
     action: SubmitAction,
 }
 
@@ -50,6 +74,8 @@ pub enum SubmitAction {
 pub enum AuthResult {
     Registered(Redirect),
     LoggedIn(CookieJar, Redirect),
+    /// Registration was refused for a reason worth explaining on the page.
+    Rejected(StatusCode, AccountTemplate),
     Error(StatusCode),
 }
 
@@ -60,14 +86,51 @@ pub async fn submit(
     Valid(credentials): Valid<Form<CredentialsForm>>,
 ) -> AuthResult {
     if credentials.action == SubmitAction::Register {
+        let policy = state.settings.registration_policy;
+        let reject = |status, problem| {
+            AuthResult::Rejected(status, AccountTemplate::new(policy, Some(problem)))
+        };
+        // NOTE: Blank fields are submitted as empty strings rather than omitted.
+        let signup_code = credentials
+            .signup_code
+            .as_deref()
+            .map(str::trim)
+            .filter(|code| !code.is_empty());
+        let signup_code = match policy {
+            RegistrationPolicy::Open => None,
+            RegistrationPolicy::InviteCode => match signup_code {
+                Some(code) => Some(code),
+                None => {
+                    tracing::debug!("Rejecting registration: signup code missing");
+                    return reject(
+                        StatusCode::FORBIDDEN,
+                        "A signup code is required to register.",
+                    );
+                }
+            },
+            RegistrationPolicy::Closed => {
+                tracing::debug!("Rejecting registration: registration is closed");
+                return reject(
+                    StatusCode::FORBIDDEN,
+                    "Registration is closed. Ask an administrator to create an account for you.",
+                );
+            }
+        };
+
         match state
             .repository
             .accounts
-            .register(&credentials.username, &credentials.password)
+            .register(&credentials.username, &credentials.password, signup_code)
             .await
         {
             Ok(_account) => { /* continue to automatic login */ }
             Err(RegistrationError::NameTaken) => return AuthResult::Error(StatusCode::CONFLICT),
+            Err(RegistrationError::InvalidSignupCode) => {
+                return reject(
+                    StatusCode::FORBIDDEN,
+                    "This signup code is invalid or has already been used.",
+                );
+            }
             Err(_internal) => return AuthResult::Error(StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
@@ -109,11 +172,304 @@ pub async fn logout(
     Ok(Redirect::to("/"))
 }
 
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct InboxEntry {
+    pub room_id: i64,
+    pub room_name: String,
+    pub message: EchoedMessage,
+}
+
+#[instrument(skip_all, fields(username = account.username), err(Debug))]
+#[debug_handler]
+pub async fn inbox(
+    State(state): State<SharedState>,
+    Session(account): Session,
+) -> Result<Json<Vec<InboxEntry>>, StatusCode> {
+    let unread = state
+        .repository
+        .mentions
+        .find_unread(&account.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut entries = vec![];
+    for mention in unread {
+        let Some(message) = state
+            .repository
+            .messages
+            .find_by_id(mention.message_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        else {
+            continue;
+        };
+        let message = message
+            .to_echoed_message(&state)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        entries.push(InboxEntry {
+            room_id: mention.room_id,
+            room_name: mention.room_name,
+            message,
+        });
+    }
+
+    tracing::debug!(count = entries.len(), "Returning unread mentions");
+    Ok(Json(entries))
+}
+
+#[derive(Deserialize, Debug)]
+#[must_use]
+pub struct MarkReadForm {
+    /// Omitted to mark all mentions as read.
+    message_id: Option<i64>,
+}
+
+#[instrument(skip_all, fields(username = account.username, form = ?form), err(Debug))]
+#[debug_handler]
+pub async fn mark_read(
+    State(state): State<SharedState>,
+    Session(account): Session,
+    Form(form): Form<MarkReadForm>,
+) -> Result<StatusCode, StatusCode> {
+    state
+        .repository
+        .mentions
+        .mark_read(&account.username, form.message_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct UserProfile {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub is_bot: bool,
+    pub registered_at: NaiveDateTime,
+}
+
+impl From<Account> for UserProfile {
+    fn from(account: Account) -> Self {
+        Self {
+            avatar_url: account.avatar_url(),
+            is_bot: account.is_bot,
+            username: account.username,
+            display_name: account.display_name,
+            bio: account.bio,
+            registered_at: account.registered_at,
+        }
+    }
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, username = username))]
+#[debug_handler]
+pub async fn profile(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(username): Path<String>,
+) -> Result<Json<UserProfile>, StatusCode> {
+    let account = state
+        .repository
+        .accounts
+        .find(&username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(account.into()))
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct ProfileForm {
+    /// Left blank to go by the username.
+    #[validate(length(max = 64))]
+    display_name: Option<String>,
+    #[validate(length(max = 500))]
+    bio: Option<String>,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn update_profile(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<ProfileForm>>,
+) -> Result<Json<UserProfile>, StatusCode> {
+    let non_blank = |field: &Option<String>| {
+        field
+            .as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    let accounts = &state.repository.accounts;
+    accounts
+        .update_profile(
+            &requester.username,
+            non_blank(&form.display_name).as_deref(),
+            non_blank(&form.bio).as_deref(),
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let account = accounts
+        .find(&requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(account.into()))
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username))]
+#[debug_handler]
+pub async fn remove_avatar(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+) -> Result<StatusCode, StatusCode> {
+    state
+        .repository
+        .accounts
+        .set_avatar(&requester.username, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct AccountExport {
+    pub exported_at: NaiveDateTime,
+    pub account: UserProfile,
+    pub memberships: Vec<Membership>,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct ExportedMessage {
+    pub id: i64,
+    pub room_id: i64,
+    pub text: Option<String>,
+    pub sent_at: NaiveDateTime,
+    pub reply_to: Option<i64>,
+    /// Where the attachment is within the archive, if it is still around.
+    pub attachment: Option<String>,
+}
+
+/// Bundles everything the requester has shared into a zip archive: their
+/// profile, memberships and messages as `account.json`, and their uploads
+/// under `attachments/`.
+#[instrument(skip_all, fields(requester.username = requester.username))]
+#[debug_handler]
+pub async fn export(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+) -> Result<Archive, StatusCode> {
+    let repository = &state.repository;
+    let account = repository
+        .accounts
+        .find(&requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let memberships = repository
+        .rooms
+        .find_memberships(&requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let uploads = repository
+        .uploads
+        .find_by_uploader(&requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let messages = repository
+        .messages
+        .find_by_sender(&requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(|message| ExportedMessage {
+            attachment: uploads
+                .iter()
+                .find(|upload| message.file_upload_uuid.as_ref() == Some(&upload.uuid))
+                .map(archive::upload_entry_name),
+            id: message.id,
+            room_id: message.room_id,
+            text: message.text,
+            sent_at: message.sent_at,
+            reply_to: message.reply_to,
+        })
+        .collect();
+
+    let export = AccountExport {
+        exported_at: Utc::now().naive_utc(),
+        account: account.into(),
+        memberships,
+        messages,
+    };
+    let json = serde_json::to_vec_pretty(&export).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut entries = vec![ArchiveEntry::Generated {
+        name: "account.json".to_string(),
+        data: json,
+    }];
+    entries.extend(uploads.iter().filter_map(ArchiveEntry::for_upload));
+
+    let filename = format!("{}-export.zip", requester.username);
+    Archive::build(filename, entries)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct DeleteAccountForm {
+    #[validate(length(min = 1, max = 64))]
+    password: String,
+    messages: DeletedMessages,
+}
+
+/// Deletes the requester's account for good, after checking their password.
+#[instrument(skip_all, fields(requester.username = requester.username, messages = ?form.messages))]
+#[debug_handler]
+pub async fn delete(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    jar: CookieJar,
+    Valid(form): Valid<Form<DeleteAccountForm>>,
+) -> Result<(CookieJar, Redirect), StatusCode> {
+    let accounts = &state.repository.accounts;
+    accounts
+        .verify_password(&requester.username, &form.password)
+        .await
+        .map_err(|error| match error {
+            LoginError::InvalidCredentials => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    accounts
+        .delete(&requester.username, form.messages)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to delete account"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let jar = jar.remove(Cookie::build(SESSION_COOKIE_NAME).path("/"));
+    Ok((jar, Redirect::to("/account")))
+}
+
 impl IntoResponse for AuthResult {
     fn into_response(self) -> axum::response::Response {
         match self {
             Self::Registered(redirect) => redirect.into_response(),
             Self::LoggedIn(cookie_jar, redirect) => (cookie_jar, redirect).into_response(),
+            Self::Rejected(status_code, template) => template.render().map_or_else(
+                |_| StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                |html| (status_code, Html(html)).into_response(),
+            ),
             Self::Error(status_code) => status_code.into_response(),
         }
     }
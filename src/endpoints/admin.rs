@@ -0,0 +1,206 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::{Form, Json, debug_handler};
+use axum_valid::Valid;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use validator::Validate;
+
+use crate::auth::Session;
+use crate::repository::account::{Account, RegistrationError};
+use crate::repository::signup_code::SignupCode;
+use crate::state::SharedState;
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct SignupCodeResponse {
+    pub code: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub used_by: Option<String>,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+impl From<SignupCode> for SignupCodeResponse {
+    fn from(code: SignupCode) -> Self {
+        Self {
+            code: code.code,
+            created_by: code.created_by,
+            created_at: code.created_at,
+            used_by: code.used_by,
+            used_at: code.used_at,
+        }
+    }
+}
+
+/// Mints a single-use code for registering while registration is restricted.
+#[instrument(skip_all, fields(requester.username = requester.username))]
+#[debug_handler]
+pub async fn create_signup_code(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+) -> Result<(StatusCode, Json<SignupCodeResponse>), StatusCode> {
+    self::require_admin(&state, &requester.username)?;
+    let code = state
+        .repository
+        .signup_codes
+        .create(&requester.username)
+        .await
+        .inspect(|code| tracing::debug!(code.code, "Minted signup code"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to mint signup code"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((StatusCode::CREATED, Json(code.into())))
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username))]
+#[debug_handler]
+pub async fn list_signup_codes(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+) -> Result<Json<Vec<SignupCodeResponse>>, StatusCode> {
+    self::require_admin(&state, &requester.username)?;
+    let codes = state
+        .repository
+        .signup_codes
+        .find_all()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(codes.into_iter().map(Into::into).collect()))
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct CreateBotForm {
+    #[validate(length(min = 1, max = 64))]
+    username: String,
+    #[validate(length(min = 1, max = 64))]
+    display_name: Option<String>,
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct BotForm {
+    #[validate(length(min = 1, max = 64))]
+    username: String,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct BotResponse {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub registered_at: NaiveDateTime,
+}
+
+impl From<Account> for BotResponse {
+    fn from(account: Account) -> Self {
+        Self {
+            username: account.username,
+            display_name: account.display_name,
+            registered_at: account.registered_at,
+        }
+    }
+}
+
+/// A bot's token, sent as `Authorization: Bearer <token>`. It is only ever
+/// shown once.
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct BotTokenResponse {
+    pub username: String,
+    pub token: String,
+}
+
+/// Creates a bot account along with its first token.
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn create_bot(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<CreateBotForm>>,
+) -> Result<(StatusCode, Json<BotTokenResponse>), StatusCode> {
+    self::require_admin(&state, &requester.username)?;
+    let bot = state
+        .repository
+        .accounts
+        .create_bot(&form.username, form.display_name.as_deref())
+        .await
+        .inspect_err(|error| tracing::warn!(?error, "Failed to create bot"))
+        .map_err(|error| match error {
+            RegistrationError::NameTaken => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+    let session = state
+        .repository
+        .accounts
+        .reset_bot_token(&bot.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    tracing::debug!(bot.username, "Created bot");
+
+    let response = BotTokenResponse {
+        username: bot.username,
+        token: session.token,
+    };
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username))]
+#[debug_handler]
+pub async fn list_bots(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+) -> Result<Json<Vec<BotResponse>>, StatusCode> {
+    self::require_admin(&state, &requester.username)?;
+    let bots = state
+        .repository
+        .accounts
+        .find_bots()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(bots.into_iter().map(Into::into).collect()))
+}
+
+/// Replaces the bot's token, e.g. after it leaked.
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn reset_bot_token(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<BotForm>>,
+) -> Result<Json<BotTokenResponse>, StatusCode> {
+    self::require_admin(&state, &requester.username)?;
+    let is_bot = state
+        .repository
+        .accounts
+        .find(&form.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .is_some_and(|account| account.is_bot);
+    if !is_bot {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let session = state
+        .repository
+        .accounts
+        .reset_bot_token(&form.username)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to reset bot token"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let response = BotTokenResponse {
+        username: session.account,
+        token: session.token,
+    };
+    Ok(Json(response))
+}
+
+fn require_admin(state: &SharedState, username: &str) -> Result<(), StatusCode> {
+    if state.settings.is_admin(username) {
+        Ok(())
+    } else {
+        tracing::warn!("Requester is not an admin, rejecting");
+        Err(StatusCode::FORBIDDEN)
+    }
+}
@@ -1,23 +1,61 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 use askama::Template;
 use axum::body::Body;
 use axum::debug_handler;
 use axum::extract::ws::Utf8Bytes;
 use axum::extract::{Path, State, WebSocketUpgrade, ws};
-use axum::http::{Response, StatusCode};
+use axum::http::{Response, StatusCode, header};
 use axum::response::{Html, IntoResponse};
 use chrono::NaiveDateTime;
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::{RecvError, SendError};
+use tokio::sync::mpsc;
 use tracing::instrument;
 
 use crate::auth::Session;
+use crate::commands::{self, Parsed};
+use crate::endpoints::rooms;
+use crate::markdown;
+use crate::presence::{MemberPresence, PresenceStatus, SocketId};
+use crate::repository::message::Message;
+use crate::repository::room::{MemberRole, Room, RoomVisibility};
 use crate::state::SharedState;
+use crate::workers::webhook_delivery;
+
+/// Read receipts are only tracked and shared in rooms up to this size.
+pub const READ_RECEIPTS_MAX_MEMBERS: usize = 32;
+
+/// How long a typing indicator is shown for, unless it's refreshed.
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(5);
+
+/// Typing events from a single socket are relayed at most this often.
+pub const TYPING_MIN_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How often an idle websocket checks that its session is still active, so
+/// that logging out or deleting the account cuts it off.
+pub const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Everything a client may send over its websocket.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[must_use]
+pub enum IncomingEvent {
+    Message(IncomingMessage),
+    ReadUpTo { room_id: i64, message_id: i64 },
+    Typing { room_id: i64 },
+    Away { away: bool },
+}
 
 #[derive(Deserialize, Clone, Debug)]
 #[must_use]
 pub struct IncomingMessage {
     pub room_id: i64,
     pub text: Option<String>,
+    #[serde(default)]
+    pub reply_to: Option<i64>,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -25,11 +63,199 @@ pub struct IncomingMessage {
 pub struct EchoedMessage {
     pub id: i64,
     pub sender: String,
+    pub sender_display_name: Option<String>,
+    pub sender_avatar_url: Option<String>,
+    /// Whether the sender is a bot, shown as a badge next to its name.
+    pub sender_is_bot: bool,
     pub room_id: i64,
     pub text: Option<String>,
+    pub text_html: Option<String>,
+    /// Whether this records a change made to the room rather than something
+    /// the sender said.
+    pub is_system: bool,
     pub sent_at: NaiveDateTime,
     pub upload_filename: Option<String>,
     pub upload_url: Option<String>,
+    pub upload_width: Option<i64>,
+    pub upload_height: Option<i64>,
+    pub upload_thumbnail_url: Option<String>,
+    pub upload_deleted: bool,
+    pub reactions: Vec<ReactionSummary>,
+    pub reply_to: Option<ReplyPreview>,
+    pub link_previews: Vec<LinkPreviewCard>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[must_use]
+pub struct ReplyPreview {
+    pub id: i64,
+    pub sender: String,
+    pub text_preview: String,
+    pub has_upload: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[must_use]
+pub struct PinnedMessage {
+    pub pinned_by: String,
+    pub pinned_at: NaiveDateTime,
+    pub message: EchoedMessage,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[must_use]
+pub struct LinkPreviewCard {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[must_use]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: usize,
+    pub reactors: Vec<String>,
+}
+
+/// Everything that is fanned out to the websockets of a room.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[must_use]
+pub enum RoomEvent {
+    Message(Box<EchoedMessage>),
+    Reactions {
+        room_id: i64,
+        message_id: i64,
+        reactions: Vec<ReactionSummary>,
+    },
+    /// Previews fetched for a message after it was delivered.
+    LinkPreviews {
+        room_id: i64,
+        message_id: i64,
+        link_previews: Vec<LinkPreviewCard>,
+    },
+    /// The room's name or other details have changed.
+    RoomUpdated {
+        room_id: i64,
+        name: String,
+        topic: Option<String>,
+        description: Option<String>,
+        archived: bool,
+        visibility: RoomVisibility,
+    },
+    RoomDeleted {
+        room_id: i64,
+    },
+    MemberJoined {
+        room_id: i64,
+        username: String,
+    },
+    MemberLeft {
+        room_id: i64,
+        username: String,
+    },
+    Pinned {
+        room_id: i64,
+        pin: Box<PinnedMessage>,
+    },
+    Unpinned {
+        room_id: i64,
+        message_id: i64,
+        unpinned_by: String,
+    },
+    ReadReceipt {
+        room_id: i64,
+        reader: String,
+        message_id: i64,
+    },
+    Typing {
+        room_id: i64,
+        username: String,
+        expires_in_ms: u64,
+    },
+    Presence {
+        room_id: i64,
+        username: String,
+        status: PresenceStatus,
+    },
+    /// Sent only to a freshly connected socket, never broadcast.
+    PresenceSnapshot {
+        room_id: i64,
+        members: Vec<MemberPresence>,
+    },
+    /// A command's answer, sent only to the socket it came from.
+    CommandReply {
+        room_id: i64,
+        text: String,
+    },
+    /// Something sent over the socket was rejected, e.g. an unknown command.
+    /// Sent only to the socket it came from.
+    Error {
+        room_id: i64,
+        code: &'static str,
+        message: String,
+    },
+}
+
+/// Events addressed to a single account, delivered to all of its websockets
+/// regardless of the room they were opened for.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[must_use]
+pub enum Notification {
+    Mention {
+        #[serde(skip)]
+        recipient: String,
+        room_id: i64,
+        room_name: String,
+        message: Box<EchoedMessage>,
+    },
+}
+
+impl Notification {
+    #[must_use]
+    pub fn recipient(&self) -> &str {
+        match self {
+            Self::Mention { recipient, .. } => recipient,
+        }
+    }
+}
+
+impl RoomEvent {
+    #[must_use]
+    pub const fn room_id(&self) -> i64 {
+        match self {
+            Self::Message(message) => message.room_id,
+            Self::Reactions { room_id, .. }
+            | Self::LinkPreviews { room_id, .. }
+            | Self::RoomUpdated { room_id, .. }
+            | Self::RoomDeleted { room_id }
+            | Self::MemberJoined { room_id, .. }
+            | Self::MemberLeft { room_id, .. }
+            | Self::Pinned { room_id, .. }
+            | Self::Unpinned { room_id, .. }
+            | Self::ReadReceipt { room_id, .. }
+            | Self::Typing { room_id, .. }
+            | Self::Presence { room_id, .. }
+            | Self::PresenceSnapshot { room_id, .. }
+            | Self::CommandReply { room_id, .. }
+            | Self::Error { room_id, .. } => *room_id,
+        }
+    }
+
+    /// Whether `username` can no longer follow the room after this event,
+    /// because they left or were removed, or the room is gone.
+    #[must_use]
+    pub fn cuts_off(&self, username: &str) -> bool {
+        match self {
+            Self::MemberLeft {
+                username: member, ..
+            } => member == username,
+            Self::RoomDeleted { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Template)]
@@ -38,8 +264,15 @@ pub struct ChatTemplate<'a> {
     pub title: &'a str,
     pub logged_in_as: &'a str,
     pub room_name: &'a str,
+    pub room_topic: &'a str,
+    pub room_description: &'a str,
+    pub room_archived: bool,
     pub room_id: i64,
     pub initial_messages_json: String,
+    pub initial_pins_json: String,
+    pub history_page_size: i64,
+    pub can_moderate: bool,
+    pub is_owner: bool,
 }
 
 #[instrument(skip_all, fields(account = ?account))]
@@ -59,39 +292,48 @@ pub async fn page(
         .ok_or(StatusCode::NOT_FOUND)?;
 
     let mut echoed_messages = vec![];
+    let mut pins = vec![];
+    let role = room
+        .get_role(&state.db_pool, &account.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let can_moderate = role.is_some_and(MemberRole::can_moderate);
+    let is_owner = role == Some(MemberRole::Owner);
     let is_member = room
-        .get_members(&state.db_pool)
+        .has_member(&state.db_pool, &account.username)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .into_iter()
-        .any(|m| m.username == account.username);
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     if is_member {
-        for message in room
-            .get_messages(&state.db_pool)
+        let messages = room
+            .get_messages_before(&state.db_pool, None, rooms::HISTORY_PAGE_SIZE)
             .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .into_iter()
-            .filter(|msg| msg.room_id == room_id)
-        {
-            let echoed_message = message
-                .to_echoed_message(&state)
-                .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            echoed_messages.push(echoed_message);
-        }
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        echoed_messages = Message::to_echoed_messages(messages, &state)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        pins = self::pinned_messages(&state, &room)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     } else {
         tracing::warn!("User is not a member of this room, retuning no messages");
     }
 
-    let initial_messages_json =
-        serde_json::to_string(&echoed_messages).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let initial_messages_json = self::to_script_json(&echoed_messages)?;
+    let initial_pins_json = self::to_script_json(&pins)?;
 
     let template = ChatTemplate {
         logged_in_as: &account.username,
         title: env!("CARGO_CRATE_NAME"),
         room_name: &room.name,
+        room_topic: room.topic.as_deref().unwrap_or_default(),
+        room_description: room.description.as_deref().unwrap_or_default(),
+        room_archived: room.is_archived(),
         room_id,
         initial_messages_json,
+        initial_pins_json,
+        history_page_size: rooms::HISTORY_PAGE_SIZE,
+        can_moderate,
+        is_owner,
     };
 
     template
@@ -100,6 +342,21 @@ pub async fn page(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+/// Serializes data to be embedded into the page.
+fn to_script_json<T: Serialize>(value: &T) -> Result<String, StatusCode> {
+    // NOTE: This ends up inside a `<script>` tag unescaped, so a raw `</script>`
+    // in someone's message must not be able to close it early.
+    serde_json::to_string(value)
+        .map(|json| json.replace('<', "\\u003c"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Colors for the classes used in highlighted code blocks.
+#[debug_handler]
+pub async fn highlight_stylesheet() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/css")], markdown::stylesheet())
+}
+
 #[debug_handler]
 #[instrument(skip_all, fields(username = account.username, room_id = room_id))]
 pub async fn websocket(
@@ -130,17 +387,104 @@ pub async fn websocket(
     let callback = move |socket: ws::WebSocket| async move {
         let broadcast_tx = state.broadcast_tx.clone();
         let mut broadcast_rx = broadcast_tx.subscribe();
+        let mut notification_rx = state.notification_tx.subscribe();
         let (mut websocket_tx, mut websocket_rx) = socket.split();
+        // NOTE: Events meant only for this socket, like answers to commands.
+        let (direct_tx, mut direct_rx) = mpsc::unbounded_channel::<RoomEvent>();
+        let username = account.username.clone();
 
+        let (socket_id, presence_change) = state.presence.connect(&username);
+        if let Some(status) = presence_change {
+            self::broadcast_presence(&state, &username, status).await;
+        }
+        let _presence_guard = PresenceGuard {
+            state: state.clone(),
+            username: username.clone(),
+            socket_id,
+        };
+        let snapshot = RoomEvent::PresenceSnapshot {
+            room_id,
+            members: self::room_presence(&state, &room).await.unwrap_or_default(),
+        };
+
+        let (session_state, session_token) = (state.clone(), account.session_token);
         tokio::spawn(async move {
-            while let Ok(message) = broadcast_rx.recv().await {
-                if message.room_id != room_id {
-                    tracing::debug!("Message does not belong to this room, skipping");
-                    continue;
-                }
+            let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+            let snapshot_json = serde_json::to_string(&snapshot).unwrap();
+            if websocket_tx
+                .send(ws::Message::Text(snapshot_json.into()))
+                .await
+                .is_err()
+            {
+                tracing::warn!("Failed to send presence snapshot (likely disconnect)");
+                return;
+            }
+
+            loop {
+                let json_repr = tokio::select! {
+                    event = broadcast_rx.recv() => {
+                        let event = match event {
+                            Ok(event) => event,
+                            Err(RecvError::Lagged(skipped)) => {
+                                tracing::warn!(skipped, "Websocket lagged behind, skipping events");
+                                continue;
+                            }
+                            Err(RecvError::Closed) => break,
+                        };
+                        if event.room_id() != room_id {
+                            tracing::debug!("Event does not belong to this room, skipping");
+                            continue;
+                        }
+                        tracing::trace!(data = ?event, "RECV on local broadcast");
+                        if event.cuts_off(&username) {
+                            tracing::debug!("No longer in the room, closing websocket");
+                            let json_repr = serde_json::to_string(&event).unwrap();
+                            let _ = websocket_tx.send(ws::Message::Text(json_repr.into())).await;
+                            let _ = websocket_tx.send(ws::Message::Close(None)).await;
+                            break;
+                        }
+                        serde_json::to_string(&event).unwrap()
+                    }
+
+                    notification = notification_rx.recv() => {
+                        let notification = match notification {
+                            Ok(notification) => notification,
+                            Err(RecvError::Lagged(skipped)) => {
+                                tracing::warn!(
+                                    skipped,
+                                    "Websocket lagged behind, skipping notifications"
+                                );
+                                continue;
+                            }
+                            Err(RecvError::Closed) => break,
+                        };
+                        if notification.recipient() != username {
+                            continue;
+                        }
+                        tracing::trace!(data = ?notification, "RECV on notification broadcast");
+                        serde_json::to_string(&notification).unwrap()
+                    }
+
+                    event = direct_rx.recv() => {
+                        let Some(event) = event else { break };
+                        serde_json::to_string(&event).unwrap()
+                    }
+
+                    _ = session_check.tick() => {
+                        let is_active = session_state
+                            .repository
+                            .accounts
+                            .is_session_active(session_token)
+                            .await;
+                        if is_active.is_ok_and(|is_active| !is_active) {
+                            tracing::debug!("Session has ended, closing websocket");
+                            let _ = websocket_tx.send(ws::Message::Close(None)).await;
+                            break;
+                        }
+                        continue;
+                    }
+                };
 
-                tracing::trace!(data = ?message, "RECV on local broadcast");
-                let json_repr = serde_json::to_string(&message).unwrap();
                 let utf8_bytes = Utf8Bytes::from(json_repr);
                 match websocket_tx.send(ws::Message::Text(utf8_bytes)).await {
                     Ok(()) => tracing::trace!("Websocket TX ok"),
@@ -152,35 +496,447 @@ pub async fn websocket(
             }
         });
 
+        let mut last_typing_relayed: Option<Instant> = None;
         while let Some(Ok(ws::Message::Text(incoming_json))) = websocket_rx.next().await {
             tracing::trace!(data = ?incoming_json, "RECV on websocket");
 
-            // NOTE: Здесь мы декодируем сырое сообщение через WebSocket от клиента. В нём
-            // известно только содержимое сообщения и ID комнаты, в которой должно оказаться
-            // это сообщение. ID отправителя мы уже знаем по сессии.
-            let incoming_message = serde_json::from_str::<IncomingMessage>(&incoming_json).unwrap();
+            let is_active = state
+                .repository
+                .accounts
+                .is_session_active(account.session_token)
+                .await;
+            if is_active.is_ok_and(|is_active| !is_active) {
+                tracing::debug!("Session has ended, closing websocket");
+                break;
+            }
 
-            assert_eq!(incoming_message.room_id, room.id);
+            let incoming_event = match serde_json::from_str::<IncomingEvent>(&incoming_json) {
+                Ok(incoming_event) => incoming_event,
+                Err(error) => {
+                    tracing::warn!(?error, "Failed to decode incoming event, skipping");
+                    continue;
+                }
+            };
 
-            // NOTE: Сохраняем полученные данные в БД, получая обратно полноценное
-            // отображение новой строки со временем отправки и другими данными.
-            let repo_message = room
-                .send_new_message(&state.db_pool, &account.username, incoming_message.text)
-                .await
-                .unwrap();
+            match incoming_event {
+                IncomingEvent::Message(incoming_message) => {
+                    let answer = self::handle_incoming_message(
+                        &state,
+                        &room,
+                        &account.username,
+                        incoming_message,
+                    )
+                    .await;
+                    if let Some(answer) = answer {
+                        let _ = direct_tx.send(answer);
+                    }
+                }
+                IncomingEvent::ReadUpTo {
+                    room_id,
+                    message_id,
+                } => {
+                    if room_id != room.id {
+                        tracing::warn!(room_id, "Read marker is for another room, skipping");
+                        continue;
+                    }
+                    self::handle_read_up_to(&state, &room, &account.username, message_id).await;
+                }
+                IncomingEvent::Typing { room_id } => {
+                    if room_id != room.id {
+                        tracing::warn!(room_id, "Typing event is for another room, skipping");
+                        continue;
+                    }
+                    if last_typing_relayed.is_some_and(|at| at.elapsed() < TYPING_MIN_INTERVAL) {
+                        continue;
+                    }
+                    last_typing_relayed = Some(Instant::now());
+                    let event = RoomEvent::Typing {
+                        room_id,
+                        username: account.username.clone(),
+                        expires_in_ms: TYPING_EXPIRY.as_millis().try_into().unwrap_or(u64::MAX),
+                    };
+                    let _ = state.broadcast_tx.send(event);
+                }
+                IncomingEvent::Away { away } => {
+                    if let Some(status) =
+                        state.presence.set_away(&account.username, socket_id, away)
+                    {
+                        self::broadcast_presence(&state, &account.username, status).await;
+                    }
+                }
+            }
+        }
+    };
+
+    Ok(websocket_upgrade.on_upgrade(callback))
+}
+
+/// Takes a websocket out of the presence tracker once it is done with, even
+/// if it ends with a panic.
+struct PresenceGuard {
+    state: SharedState,
+    username: String,
+    socket_id: SocketId,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        let Some(status) = self
+            .state
+            .presence
+            .disconnect(&self.username, self.socket_id)
+        else {
+            return;
+        };
+        let state = self.state.clone();
+        let username = std::mem::take(&mut self.username);
+        tokio::spawn(async move {
+            self::broadcast_presence(&state, &username, status).await;
+        });
+    }
+}
+
+/// Sends a mention notification to every websocket of each mentioned account.
+pub fn notify_mentioned(
+    state: &SharedState,
+    room: &Room,
+    echoed_message: &EchoedMessage,
+    mentioned: Vec<String>,
+) {
+    for recipient in mentioned {
+        let notification = Notification::Mention {
+            recipient,
+            room_id: room.id,
+            room_name: room.name.clone(),
+            message: Box::new(echoed_message.clone()),
+        };
+        let _ = state
+            .notification_tx
+            .send(notification)
+            .inspect(|recv_count| tracing::trace!(?recv_count, "Sent notification"))
+            .inspect_err(|error| tracing::debug!(?error, "No one is listening for notifications"));
+    }
+}
+
+/// Returns the event to send back to the sender's socket, if any.
+#[instrument(skip_all, fields(room.id = room.id))]
+async fn handle_incoming_message(
+    state: &SharedState,
+    room: &Room,
+    sender: &str,
+    incoming_message: IncomingMessage,
+) -> Option<RoomEvent> {
+    // NOTE: Здесь мы декодируем сырое сообщение через WebSocket от клиента. В нём
+    // известно только содержимое сообщения и ID комнаты, в которой должно оказаться
+    // это сообщение. ID отправителя мы уже знаем по сессии.
+    if incoming_message.room_id != room.id {
+        tracing::warn!(
+            incoming_message.room_id,
+            "Message is for another room, dropping it"
+        );
+        return None;
+    }
+
+    // NOTE: The room may have been archived or deleted since the socket was
+    // opened, so its current state is looked up again.
+    let is_writable = state
+        .repository
+        .rooms
+        .find_by_id(room.id)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to look up room"))
+        .ok()?
+        .is_some_and(|room| !room.is_archived());
+    if !is_writable {
+        tracing::warn!("Room is archived or gone, dropping message");
+        return None;
+    }
+    // NOTE: Same goes for the sender, who may have left, been removed or
+    // deleted their account since (which removes their memberships too).
+    // Neither commands nor messages are taken from them any longer.
+    let is_member = room
+        .has_member(&state.db_pool, sender)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to check room membership"))
+        .ok()?;
+    if !is_member {
+        tracing::warn!("Sender is no longer a member of the room, rejecting message");
+        return Some(RoomEvent::Error {
+            room_id: room.id,
+            code: "forbidden",
+            message: "you are not a member of this room anymore".to_string(),
+        });
+    }
 
-            // NOTE: Дополняем "строчку из БД", полученную ранее всеми данными, которые
-            // необходимы клиенту для отрисовки сообщения. Далее оно отправится в локальный
-            // поток сообщений, где все активные слушатели данной комнаты получат его и
-            // отправят в соответствующие WebSocketы.
-            let echoed_message = repo_message.to_echoed_message(&state).await.unwrap();
+    if let Some(parent_id) = incoming_message.reply_to {
+        let parent = state
+            .repository
+            .messages
+            .find_by_id(parent_id)
+            .await
+            .inspect_err(|error| tracing::error!(?error, "Failed to look up reply target"))
+            .ok()?;
+        if parent.is_none_or(|parent| parent.room_id != room.id) {
+            tracing::warn!(
+                parent_id,
+                "Reply target is not in this room, rejecting message"
+            );
+            return Some(RoomEvent::Error {
+                room_id: room.id,
+                code: "invalid_reply",
+                message: "the message you replied to is not in this room".to_string(),
+            });
+        }
+    }
 
-            let _ = broadcast_tx
-                .send(echoed_message)
-                .inspect(|recv_count| tracing::trace!(?recv_count, "Sent data to local broadcast"))
-                .inspect_err(|error| tracing::error!(?error, "Local broadcast TX failed"));
+    // NOTE: Commands are run instead of being sent, see `commands`.
+    let text = match incoming_message.text.as_deref().map(commands::parse) {
+        Some(Parsed::Command { name, args }) => {
+            return commands::dispatch(state, room, sender, name, args, incoming_message.reply_to)
+                .await;
         }
+        Some(Parsed::Message(text)) => Some(text.to_string()),
+        None => None,
     };
 
-    Ok(websocket_upgrade.on_upgrade(callback))
+    // NOTE: Failures are logged by `publish_message` itself.
+    let _ = self::publish_message(state, room, sender, text, incoming_message.reply_to).await;
+    None
+}
+
+/// Sends the event to everyone in the room. Events that webhooks are told
+/// about are queued for the room's outgoing webhooks first, so that they get
+/// them even when the broadcast is missed.
+pub async fn broadcast(
+    state: &SharedState,
+    event: RoomEvent,
+) -> Result<usize, SendError<RoomEvent>> {
+    // NOTE: Failures are logged by `enqueue` itself.
+    let _ = webhook_delivery::enqueue(state, &event).await;
+    state.broadcast_tx.send(event)
+}
+
+/// Stores a new message and delivers it to everyone in the room, like one
+/// sent over a websocket.
+#[instrument(skip_all, fields(room.id = room.id, sender = sender), err(Debug))]
+pub async fn publish_message(
+    state: &SharedState,
+    room: &Room,
+    sender: &str,
+    text: Option<String>,
+    reply_to: Option<i64>,
+) -> sqlx::Result<()> {
+    // NOTE: Сохраняем полученные данные в БД, получая обратно полноценное
+    // отображение новой строки со временем отправки и другими данными.
+    let repo_message = room
+        .send_new_message(&state.db_pool, sender, text, reply_to)
+        .await?;
+
+    // NOTE: Дополняем "строчку из БД", полученную ранее всеми данными, которые
+    // необходимы клиенту для отрисовки сообщения. Далее оно отправится в локальный
+    // поток сообщений, где все активные слушатели данной комнаты получат его и
+    // отправят в соответствующие WebSocketы.
+    let mentioned = repo_message.get_mentions(&state.db_pool).await?;
+    let echoed_message = repo_message.to_echoed_message(state).await?;
+    self::notify_mentioned(state, room, &echoed_message, mentioned);
+
+    let _ = self::broadcast(state, RoomEvent::Message(Box::new(echoed_message)))
+        .await
+        .inspect(|recv_count| tracing::trace!(?recv_count, "Sent data to local broadcast"))
+        .inspect_err(|error| tracing::error!(?error, "Local broadcast TX failed"));
+    Ok(())
+}
+
+#[instrument(skip_all, fields(room.id = room.id, message_id = message_id))]
+async fn handle_read_up_to(state: &SharedState, room: &Room, reader: &str, message_id: i64) {
+    let Ok(moved) = room.mark_read(&state.db_pool, reader, message_id).await else {
+        return;
+    };
+    if !moved {
+        return;
+    }
+
+    let Ok(members) = room.get_members(&state.db_pool).await else {
+        return;
+    };
+    if members.len() <= READ_RECEIPTS_MAX_MEMBERS {
+        let event = RoomEvent::ReadReceipt {
+            room_id: room.id,
+            reader: reader.to_string(),
+            message_id,
+        };
+        let _ = state
+            .broadcast_tx
+            .send(event)
+            .inspect(|recv_count| tracing::trace!(?recv_count, "Sent data to local broadcast"))
+            .inspect_err(|error| tracing::error!(?error, "Local broadcast TX failed"));
+    }
+}
+
+/// Current presence of every member of the room.
+pub async fn room_presence(state: &SharedState, room: &Room) -> sqlx::Result<Vec<MemberPresence>> {
+    let members = room
+        .get_members(&state.db_pool)
+        .await?
+        .into_iter()
+        .map(|member| MemberPresence {
+            status: state.presence.status(&member.username),
+            username: member.username,
+        })
+        .collect();
+    Ok(members)
+}
+
+/// The room's pinned messages, most recently pinned first.
+pub async fn pinned_messages(state: &SharedState, room: &Room) -> sqlx::Result<Vec<PinnedMessage>> {
+    let pins = room.get_pins(&state.db_pool).await?;
+    let message_ids: Vec<i64> = pins.iter().map(|pin| pin.message_id).collect();
+    let messages = state.repository.messages.find_by_ids(&message_ids).await?;
+    let mut echoed_messages: HashMap<i64, EchoedMessage> =
+        Message::to_echoed_messages(messages, state)
+            .await?
+            .into_iter()
+            .map(|message| (message.id, message))
+            .collect();
+    Ok(pins
+        .into_iter()
+        .filter_map(|pin| {
+            Some(PinnedMessage {
+                message: echoed_messages.remove(&pin.message_id)?,
+                pinned_by: pin.pinned_by,
+                pinned_at: pin.pinned_at,
+            })
+        })
+        .collect())
+}
+
+/// Announces a change of the account's presence in every room it is in.
+#[instrument(skip(state))]
+async fn broadcast_presence(state: &SharedState, username: &str, status: PresenceStatus) {
+    let Ok(rooms) = state.repository.rooms.find_by_member(username).await else {
+        return;
+    };
+    for room in rooms {
+        let event = RoomEvent::Presence {
+            room_id: room.id,
+            username: username.to_string(),
+            status,
+        };
+        let _ = state.broadcast_tx.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::repository;
+
+    /// What the client library expects a message to look like, see its tests.
+    const CLIENT_MESSAGE_JSON: &str = include_str!("../../client/fixtures/message.json");
+
+    /// A state where `bob` said hi in room 1, and `alice` replied to him with
+    /// an attachment that `bob` reacted to.
+    async fn state_with_conversation() -> SharedState {
+        let state = repository::test_state(&[]).await;
+        for (username, display_name) in [("alice", Some("Alice")), ("bob", None)] {
+            state
+                .repository
+                .accounts
+                .create_placeholder(username, None)
+                .await
+                .unwrap();
+            state
+                .repository
+                .accounts
+                .update_profile(username, display_name, None)
+                .await
+                .unwrap();
+        }
+        let avatar = "6f1c0a4e-8d2b-4f7e-9a3c-2b5d7e9f1a3c";
+        let attachment = "0b9e4f2a-3c1d-4e5f-8a7b-6c5d4e3f2a1b";
+        sqlx::query(
+            "
+                INSERT INTO file_uploads (uuid, filename, width, height, size, uploader, room_id)
+                VALUES (?, 'me.png', 64, 64, 100, 'alice', NULL), (?, 'cat.png', 640, 480, 100, 'alice', 1);
+                INSERT INTO messages (id, sender, room_id, text, sent_at)
+                VALUES (41, 'bob', 1, 'Hi', '2026-10-18 12:30:00');
+                INSERT INTO messages (id, sender, room_id, text, sent_at, file_upload_uuid, reply_to)
+                VALUES (42, 'alice', 1, 'Hello **there**', '2026-10-18 12:30:05', ?, 41);
+            ",
+        )
+        .bind(avatar)
+        .bind(attachment)
+        .bind(attachment)
+        .execute(&state.db_pool)
+        .await
+        .unwrap();
+        state
+            .repository
+            .accounts
+            .set_avatar("alice", Some(avatar))
+            .await
+            .unwrap();
+        let stored = state
+            .repository
+            .messages
+            .find_by_id(42)
+            .await
+            .unwrap()
+            .unwrap();
+        stored
+            .add_reaction(&state.db_pool, "bob", "👍")
+            .await
+            .unwrap();
+        state
+    }
+
+    #[tokio::test]
+    async fn messages_are_sent_the_way_the_client_expects() {
+        let state = state_with_conversation().await;
+        let stored = state
+            .repository
+            .messages
+            .find_by_id(42)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let message = stored.to_echoed_message(&state).await.unwrap();
+        let expected: Value = serde_json::from_str(CLIENT_MESSAGE_JSON).unwrap();
+        assert_eq!(serde_json::to_value(&message).unwrap(), expected);
+
+        let Value::Object(mut event) =
+            serde_json::to_value(RoomEvent::Message(Box::new(message))).unwrap()
+        else {
+            panic!("expected an object");
+        };
+        assert_eq!(event.remove("type"), Some(Value::from("message")));
+        assert_eq!(Value::Object(event), expected);
+    }
+
+    #[tokio::test]
+    async fn latest_messages_are_echoed_together_without_caching_their_html() {
+        let state = state_with_conversation().await;
+        let room = state.repository.rooms.find_by_id(1).await.unwrap().unwrap();
+        let messages = room
+            .get_messages_before(&state.db_pool, None, 10)
+            .await
+            .unwrap();
+
+        let echoed = Message::to_echoed_messages(messages, &state).await.unwrap();
+        let expected: Value = serde_json::from_str(CLIENT_MESSAGE_JSON).unwrap();
+        assert_eq!(echoed.iter().map(|m| m.id).collect::<Vec<_>>(), [41, 42]);
+        assert_eq!(echoed[0].sender_display_name, None);
+        assert!(echoed[0].reply_to.is_none() && echoed[0].reactions.is_empty());
+        assert_eq!(serde_json::to_value(&echoed[1]).unwrap(), expected);
+
+        let cached: Vec<Option<String>> =
+            sqlx::query_scalar("SELECT text_html FROM messages ORDER BY id")
+                .fetch_all(&state.db_pool)
+                .await
+                .unwrap();
+        assert_eq!(cached, [None, None]);
+    }
 }
@@ -0,0 +1,239 @@
+use std::fmt::Write;
+use std::pin::pin;
+
+use askama::Template;
+use axum::debug_handler;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use chrono::{NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::archive::{self, Archive, ArchiveEntry};
+use crate::auth::Session;
+use crate::markdown;
+use crate::repository::room::{ExportRow, Room};
+use crate::repository::upload::Upload;
+use crate::state::SharedState;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+#[must_use]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Html,
+    Txt,
+}
+
+#[derive(Deserialize, Debug)]
+#[must_use]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct RoomExport {
+    pub room_id: i64,
+    pub room_name: String,
+    pub room_topic: Option<String>,
+    pub exported_at: NaiveDateTime,
+    pub messages: Vec<ExportedRoomMessage>,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct ExportedRoomMessage {
+    pub id: i64,
+    pub sender: String,
+    pub sender_display_name: Option<String>,
+    pub sent_at: NaiveDateTime,
+    pub text: Option<String>,
+    #[serde(skip)]
+    pub text_html: Option<String>,
+    pub is_system: bool,
+    pub reply_to: Option<i64>,
+    /// Where the attachment is within the archive.
+    pub attachment: Option<String>,
+    pub attachment_filename: Option<String>,
+    #[serde(skip)]
+    pub attachment_is_image: bool,
+    /// Whether the message had an attachment that was deleted since.
+    pub attachment_deleted: bool,
+}
+
+#[derive(Template)]
+#[template(path = "room_export.html")]
+pub struct RoomExportTemplate<'a> {
+    pub room_name: &'a str,
+    pub room_topic: Option<&'a str>,
+    pub exported_at: NaiveDateTime,
+    pub messages: &'a [ExportedRoomMessage],
+    pub highlight_css: &'static str,
+}
+
+/// Archives the room's whole history as a zip: a transcript in the requested
+/// format, along with every attachment that is still around.
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id, format = ?query.format))]
+#[debug_handler]
+pub async fn room(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Archive, StatusCode> {
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let is_member = room
+        .has_member(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        tracing::warn!("User is not a member of this room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let (export, uploads) = self::collect(&state, &room)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let transcript = match query.format {
+        ExportFormat::Json => ArchiveEntry::Generated {
+            name: "messages.json".to_string(),
+            data: serde_json::to_vec_pretty(&export)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        },
+        ExportFormat::Html => {
+            let template = RoomExportTemplate {
+                room_name: &export.room_name,
+                room_topic: export.room_topic.as_deref(),
+                exported_at: export.exported_at,
+                messages: &export.messages,
+                highlight_css: markdown::stylesheet(),
+            };
+            ArchiveEntry::Generated {
+                name: "messages.html".to_string(),
+                data: template
+                    .render()
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                    .into_bytes(),
+            }
+        }
+        ExportFormat::Txt => ArchiveEntry::Generated {
+            name: "messages.txt".to_string(),
+            data: self::to_plain_text(&export).into_bytes(),
+        },
+    };
+
+    let mut entries = vec![transcript];
+    entries.extend(uploads.iter().filter_map(|(upload, sent_at)| {
+        let mut entry = ArchiveEntry::for_upload(upload)?;
+        if let ArchiveEntry::Stored { modified_at, .. } = &mut entry {
+            *modified_at = Some(*sent_at);
+        }
+        Some(entry)
+    }));
+
+    let filename = format!(
+        "room-{}-{}.zip",
+        room.id,
+        export.exported_at.format("%Y%m%d")
+    );
+    tracing::debug!(messages = export.messages.len(), "Exporting room");
+    Archive::build(filename, entries)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Gathers the room's messages, along with the live uploads attached to them
+/// and when they were sent.
+async fn collect(
+    state: &SharedState,
+    room: &Room,
+) -> sqlx::Result<(RoomExport, Vec<(Upload, NaiveDateTime)>)> {
+    let mut uploads = vec![];
+    let mut messages = vec![];
+
+    let mut rows = pin!(room.stream_export_rows(&state.db_pool));
+    while let Some(row) = rows.try_next().await? {
+        let ExportRow {
+            message,
+            sender_display_name,
+            upload,
+        } = row;
+        // NOTE: Messages that were never shown are rendered here instead of
+        // being cached one by one.
+        let text_html = message
+            .text_html
+            .or_else(|| message.text.as_deref().map(markdown::render));
+        let attachment_deleted = upload.as_ref().is_some_and(Upload::is_deleted);
+        let live_upload = upload.filter(|upload| !upload.is_deleted());
+
+        messages.push(ExportedRoomMessage {
+            id: message.id,
+            sender: message.sender,
+            sender_display_name,
+            sent_at: message.sent_at,
+            text: message.text,
+            text_html,
+            is_system: message.is_system,
+            reply_to: message.reply_to,
+            attachment: live_upload.as_ref().map(archive::upload_entry_name),
+            attachment_filename: live_upload
+                .as_ref()
+                .map(|upload| upload.filename.to_string_lossy().to_string()),
+            attachment_is_image: live_upload.as_ref().is_some_and(Upload::is_image),
+            attachment_deleted,
+        });
+        uploads.extend(live_upload.map(|upload| (upload, message.sent_at)));
+    }
+
+    let export = RoomExport {
+        room_id: room.id,
+        room_name: room.name.clone(),
+        room_topic: room.topic.clone(),
+        exported_at: Utc::now().naive_utc(),
+        messages,
+    };
+    Ok((export, uploads))
+}
+
+fn to_plain_text(export: &RoomExport) -> String {
+    let mut text = format!("# {}\n", export.room_name);
+    if let Some(topic) = &export.room_topic {
+        let _ = writeln!(text, "# {topic}");
+    }
+    let _ = writeln!(
+        text,
+        "# Exported {}\n",
+        export.exported_at.format("%Y-%m-%d %H:%M:%S")
+    );
+
+    for message in &export.messages {
+        let sent_at = message.sent_at.format("%Y-%m-%d %H:%M:%S");
+        let body = message.text.as_deref().unwrap_or_default();
+        if message.is_system {
+            let _ = writeln!(text, "[{sent_at}] * {body}");
+            continue;
+        }
+        let sender = message.sender_display_name.as_ref().map_or_else(
+            || message.sender.clone(),
+            |display_name| format!("{display_name} ({})", message.sender),
+        );
+        let _ = writeln!(text, "[{sent_at}] <{sender}> {body}");
+        if let Some(attachment) = &message.attachment {
+            let _ = writeln!(text, "    attachment: {attachment}");
+        } else if message.attachment_deleted {
+            let _ = writeln!(text, "    attachment: (deleted)");
+        }
+    }
+
+    text
+}
@@ -0,0 +1,305 @@
+use askama::Template;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::{Form, Json, debug_handler};
+use axum_valid::Valid;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use validator::Validate;
+
+use crate::auth::Session;
+use crate::endpoints::chat::{self, RoomEvent};
+use crate::endpoints::rooms::find_room_as_owner;
+use crate::repository::invite::InviteCode;
+use crate::state::SharedState;
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct CreateInviteForm {
+    room_id: i64,
+    /// Omitted for an invite that can be used any number of times.
+    #[validate(range(min = 1, max = 1000))]
+    max_uses: Option<i64>,
+    /// Omitted for an invite that never expires.
+    #[validate(range(min = 1, max = 720))]
+    expires_in_hours: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct InviteResponse {
+    pub code: String,
+    pub url: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub max_uses: Option<i64>,
+    pub use_count: i64,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked: bool,
+    pub joined: Vec<InviteUseEntry>,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct InviteUseEntry {
+    pub username: String,
+    pub joined_at: NaiveDateTime,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn create(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<CreateInviteForm>>,
+) -> Result<(StatusCode, Json<InviteResponse>), StatusCode> {
+    let room = find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    let expires_at = form
+        .expires_in_hours
+        .map(|hours| Utc::now().naive_utc() + TimeDelta::hours(hours));
+
+    let invite = state
+        .repository
+        .invites
+        .create(room.id, &requester.username, form.max_uses, expires_at)
+        .await
+        .inspect(|invite| tracing::debug!(invite.code, "Created invite"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to create invite"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let response = self::to_response(&state, invite).await?;
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+/// Lists the room's invites along with who joined through each of them.
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn list(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<Json<Vec<InviteResponse>>, StatusCode> {
+    let room = find_room_as_owner(&state, room_id, &requester.username).await?;
+    let invites = state
+        .repository
+        .invites
+        .find_by_room(room.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut response = vec![];
+    for invite in invites {
+        response.push(self::to_response(&state, invite).await?);
+    }
+    Ok(Json(response))
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct RevokeInviteForm {
+    #[validate(length(min = 1, max = 64))]
+    code: String,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn revoke(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<RevokeInviteForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let invite = state
+        .repository
+        .invites
+        .find(&form.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    find_room_as_owner(&state, invite.room_id, &requester.username).await?;
+
+    state
+        .repository
+        .invites
+        .revoke(&invite.code)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to revoke invite"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Template)]
+#[template(path = "invite.html")]
+pub struct InviteTemplate<'a> {
+    pub code: &'a str,
+    pub room: Option<InvitedRoom>,
+    pub problem: Option<String>,
+}
+
+pub struct InvitedRoom {
+    pub name: String,
+    pub topic: Option<String>,
+    pub member_count: usize,
+    pub invited_by: String,
+}
+
+/// Shows what room the invite is for, and whether it can still be used.
+#[instrument(skip_all, fields(requester.username = requester.username, code = code))]
+#[debug_handler]
+pub async fn preview(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(code): Path<String>,
+) -> Result<Response, StatusCode> {
+    let Some(invite) = state
+        .repository
+        .invites
+        .find(&code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        let problem = "This invite doesn't exist.".to_string();
+        return self::render_preview(StatusCode::NOT_FOUND, &code, None, Some(problem));
+    };
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(invite.room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let member_count = room
+        .get_members(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+    let is_member = room
+        .has_member(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let problem = match invite.check_usable() {
+        _ if is_member => None,
+        Ok(()) if room.is_archived() => Some("This room has been archived.".to_string()),
+        Ok(()) => None,
+        Err(unusable) => Some(unusable.to_string()),
+    };
+    let status = match problem {
+        Some(_) => StatusCode::GONE,
+        None => StatusCode::OK,
+    };
+    let invited_room = InvitedRoom {
+        name: room.name,
+        topic: room.topic,
+        member_count,
+        invited_by: invite.created_by,
+    };
+    self::render_preview(status, &code, Some(invited_room), problem)
+}
+
+/// Joins the room through the invite, unless the requester is already in it.
+#[instrument(skip_all, fields(requester.username = requester.username, code = code))]
+#[debug_handler]
+pub async fn accept(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(code): Path<String>,
+) -> Result<Response, StatusCode> {
+    let invite = state
+        .repository
+        .invites
+        .find(&code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(invite.room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let chat_url = format!("/chat/{}", room.id);
+
+    let is_member = room
+        .has_member(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if is_member {
+        return Ok(Redirect::to(&chat_url).into_response());
+    }
+
+    // NOTE: Redeeming checks the invite's limits in the same statement that
+    // claims a use, so two people can't both take the last one. Rejections
+    // are explained on the preview page.
+    let redeemed = !room.is_archived()
+        && invite
+            .redeem(&state.db_pool, &requester.username)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !redeemed {
+        tracing::warn!("Invite can't be used, rejecting");
+        return Ok(Redirect::to(&format!("/invite/{code}")).into_response());
+    }
+
+    room.add_member(&state.db_pool, &requester.username)
+        .await
+        .inspect(|()| tracing::debug!(room.id, "Joined room through invite"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to add member to room"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let _ = chat::broadcast(
+        &state,
+        RoomEvent::MemberJoined {
+            room_id: room.id,
+            username: requester.username,
+        },
+    )
+    .await;
+    Ok(Redirect::to(&chat_url).into_response())
+}
+
+fn render_preview(
+    status: StatusCode,
+    code: &str,
+    room: Option<InvitedRoom>,
+    problem: Option<String>,
+) -> Result<Response, StatusCode> {
+    let template = InviteTemplate {
+        code,
+        room,
+        problem,
+    };
+    let html = template
+        .render()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((status, Html(html)).into_response())
+}
+
+async fn to_response(
+    state: &SharedState,
+    invite: InviteCode,
+) -> Result<InviteResponse, StatusCode> {
+    let joined = invite
+        .get_uses(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(|invite_use| InviteUseEntry {
+            username: invite_use.account,
+            joined_at: invite_use.joined_at,
+        })
+        .collect();
+
+    Ok(InviteResponse {
+        url: format!("/invite/{}", invite.code),
+        revoked: invite.revoked_at.is_some(),
+        code: invite.code,
+        created_by: invite.created_by,
+        created_at: invite.created_at,
+        max_uses: invite.max_uses,
+        use_count: invite.use_count,
+        expires_at: invite.expires_at,
+        joined,
+    })
+}
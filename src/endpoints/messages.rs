@@ -0,0 +1,601 @@
+use axum::extract::{Path, State};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::{Form, Json, debug_handler};
+use axum_valid::Valid;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use unicode_segmentation::UnicodeSegmentation;
+use validator::{Validate, ValidationError};
+
+use crate::auth::Session;
+use crate::endpoints::chat::{
+    self, EchoedMessage, PinnedMessage, READ_RECEIPTS_MAX_MEMBERS, RoomEvent,
+};
+use crate::markdown;
+use crate::repository::message::Message;
+use crate::repository::room::{MemberRole, Room};
+use crate::state::SharedState;
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct ReactionForm {
+    message_id: i64,
+    #[validate(length(min = 1, max = 32), custom(function = "validate_emoji"))]
+    emoji: String,
+}
+
+/// Even the longest emoji sequences, like families with skin tones, fit
+/// comfortably.
+const MAX_EMOJI_BYTES: usize = 64;
+const COMBINING_KEYCAP: char = '\u{20E3}';
+
+/// Accepts a single emoji, possibly a sequence of several joined into one,
+/// without trying to be a full implementation of the Unicode emoji grammar.
+fn validate_emoji(emoji: &str) -> Result<(), ValidationError> {
+    // NOTE: Keycaps like 1️⃣ are the only emoji that start out as plain ASCII.
+    let symbols = match emoji.chars().next() {
+        Some('0'..='9' | '#' | '*') if emoji.ends_with(COMBINING_KEYCAP) => &emoji[1..],
+        _ => emoji,
+    };
+    let is_keycap = symbols.len() < emoji.len();
+    let is_valid = emoji.len() <= MAX_EMOJI_BYTES
+        && emoji.graphemes(true).count() == 1
+        && (is_keycap || symbols.chars().any(is_emoji_symbol))
+        && symbols
+            .chars()
+            .all(|c| is_emoji_symbol(c) || is_emoji_component(c));
+    if !is_valid {
+        return Err(ValidationError::new("emoji"));
+    }
+    Ok(())
+}
+
+/// Whether the character is in one of the blocks that emoji are taken from.
+const fn is_emoji_symbol(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00A9}'
+            | '\u{00AE}'
+            | '\u{203C}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{21AA}'
+            | '\u{231A}'..='\u{23FF}'
+            | '\u{24C2}'
+            | '\u{25AA}'..='\u{25FE}'
+            | '\u{2600}'..='\u{27BF}'
+            | '\u{2934}'..='\u{2935}'
+            | '\u{2B05}'..='\u{2B55}'
+            | '\u{3030}'
+            | '\u{303D}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1F000}'..='\u{1FAFF}'
+    )
+}
+
+/// Whether the character only modifies or joins emoji: zero width joiners,
+/// variation selectors, keycaps and the tags used in subdivision flags.
+const fn is_emoji_component(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200D}' | '\u{FE0E}' | '\u{FE0F}' | COMBINING_KEYCAP | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn react(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<ReactionForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let (message, _room) =
+        self::find_message_to_change(&state, form.message_id, &requester.username).await?;
+
+    let added = message
+        .add_reaction(&state.db_pool, &requester.username, &form.emoji)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to add reaction"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if added {
+        self::broadcast_reactions(&state, &message).await?;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn unreact(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<ReactionForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let (message, _room) =
+        self::find_message_to_change(&state, form.message_id, &requester.username).await?;
+
+    let removed = message
+        .remove_reaction(&state.db_pool, &requester.username, &form.emoji)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to remove reaction"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if removed {
+        self::broadcast_reactions(&state, &message).await?;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct ThreadResponse {
+    pub root: EchoedMessage,
+    pub replies: Vec<EchoedMessage>,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, message_id = message_id))]
+#[debug_handler]
+pub async fn thread(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(message_id): Path<i64>,
+) -> Result<Json<ThreadResponse>, StatusCode> {
+    let _message = self::find_message_as_member(&state, message_id, &requester.username).await?;
+
+    let messages = state
+        .repository
+        .messages
+        .find_thread(message_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut thread = Message::to_echoed_messages(messages, &state)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // NOTE: The root is the only message in the thread that isn't a reply. It
+    // always exists, since the requested message itself is part of the thread.
+    let root_index = thread
+        .iter()
+        .position(|message| message.reply_to.is_none())
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let root = thread.remove(root_index);
+    tracing::debug!(root.id, replies = thread.len(), "Returning thread");
+
+    Ok(Json(ThreadResponse {
+        root,
+        replies: thread,
+    }))
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, message_id = message_id))]
+#[debug_handler]
+pub async fn read_by(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(message_id): Path<i64>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    let (message, room) =
+        self::find_message_as_member(&state, message_id, &requester.username).await?;
+
+    let member_count = room
+        .get_members(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+    if member_count > READ_RECEIPTS_MAX_MEMBERS {
+        tracing::debug!(member_count, "Room is too large for read receipts");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let read_by = room
+        .get_read_by(&state.db_pool, message.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(read_by))
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct PinForm {
+    message_id: i64,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn pin(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<PinForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let (message, room) =
+        self::find_message_as_moderator(&state, form.message_id, &requester.username).await?;
+
+    let Some(pin) = room
+        .pin(&state.db_pool, message.id, &requester.username)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to pin message"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(StatusCode::OK);
+    };
+
+    let pin = PinnedMessage {
+        pinned_by: pin.pinned_by,
+        pinned_at: pin.pinned_at,
+        message: message
+            .to_echoed_message(&state)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    };
+    let _ = chat::broadcast(
+        &state,
+        RoomEvent::Pinned {
+            room_id: room.id,
+            pin: Box::new(pin),
+        },
+    )
+    .await;
+
+    Ok(StatusCode::CREATED)
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn unpin(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<PinForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let (message, room) =
+        self::find_message_as_moderator(&state, form.message_id, &requester.username).await?;
+
+    let unpinned = room
+        .unpin(&state.db_pool, message.id)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to unpin message"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if unpinned {
+        let _ = chat::broadcast(
+            &state,
+            RoomEvent::Unpinned {
+                room_id: room.id,
+                message_id: message.id,
+                unpinned_by: requester.username,
+            },
+        )
+        .await;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Serves a single code block from a message's source, unhighlighted.
+/// Blocks are counted from zero in order of appearance.
+#[instrument(skip_all, fields(requester.username = requester.username, message_id, index))]
+#[debug_handler]
+pub async fn snippet(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path((message_id, index)): Path<(i64, usize)>,
+) -> Result<Response, StatusCode> {
+    let (message, _room) =
+        self::find_message_as_member(&state, message_id, &requester.username).await?;
+
+    let text = message.text.as_deref().unwrap_or_default();
+    let block = markdown::code_blocks(text)
+        .into_iter()
+        .nth(index)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let disposition = format!(
+        "attachment; filename=\"snippet-{message_id}-{index}.{}\"",
+        block.file_extension()
+    );
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        ),
+        (
+            header::CONTENT_DISPOSITION,
+            disposition
+                .parse()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        ),
+    ];
+    Ok((headers, block.code).into_response())
+}
+
+/// Looks up a message along with its room, making sure that `username` can
+/// see it. Messages in other rooms are reported as missing.
+async fn find_message_as_member(
+    state: &SharedState,
+    message_id: i64,
+    username: &str,
+) -> Result<(Message, Room), StatusCode> {
+    let message = state
+        .repository
+        .messages
+        .find_by_id(message_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(message.room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let is_member = room
+        .has_member(&state.db_pool, username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        tracing::warn!("User is not a member of the message's room, rejecting");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok((message, room))
+}
+
+/// Like [`find_message_as_member`], for changes to the message. Archived
+/// rooms are read-only, so their messages are reported as gone.
+async fn find_message_to_change(
+    state: &SharedState,
+    message_id: i64,
+    username: &str,
+) -> Result<(Message, Room), StatusCode> {
+    let (message, room) = self::find_message_as_member(state, message_id, username).await?;
+    if room.is_archived() {
+        tracing::warn!("Room is archived, rejecting change to the message");
+        return Err(StatusCode::GONE);
+    }
+    Ok((message, room))
+}
+
+async fn broadcast_reactions(state: &SharedState, message: &Message) -> Result<(), StatusCode> {
+    let reactions = message
+        .get_reaction_summary(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let event = RoomEvent::Reactions {
+        room_id: message.room_id,
+        message_id: message.id,
+        reactions,
+    };
+
+    let _ = state
+        .broadcast_tx
+        .send(event)
+        .inspect(|recv_count| tracing::trace!(?recv_count, "Sent data to local broadcast"))
+        .inspect_err(|error| tracing::debug!(?error, "No one is listening on local broadcast"));
+    Ok(())
+}
+
+/// Like [`find_message_to_change`], but also requires `username` to be an
+/// owner or moderator of the room.
+async fn find_message_as_moderator(
+    state: &SharedState,
+    message_id: i64,
+    username: &str,
+) -> Result<(Message, Room), StatusCode> {
+    let (message, room) = self::find_message_to_change(state, message_id, username).await?;
+    let can_moderate = room
+        .get_role(&state.db_pool, username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .is_some_and(MemberRole::can_moderate);
+    if !can_moderate {
+        tracing::warn!("User is not a moderator of the message's room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok((message, room))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use axum::body::to_bytes;
+    use chrono::Utc;
+
+    use super::*;
+    use crate::auth::AuthorizedAccount;
+    use crate::repository;
+
+    /// A state backed by a fresh in-memory database, with a room that `alice`
+    /// is in and `bob` is not.
+    async fn state_with_room() -> (SharedState, Room) {
+        let state = repository::test_state(&[]).await;
+        for username in ["alice", "bob"] {
+            state
+                .repository
+                .accounts
+                .create_placeholder(username, None)
+                .await
+                .unwrap();
+        }
+        let room = state.repository.rooms.create("general").await.unwrap();
+        room.add_member(&state.db_pool, "alice").await.unwrap();
+        (state, room)
+    }
+
+    fn session(username: &str) -> Session {
+        Session(AuthorizedAccount {
+            username: username.to_string(),
+            registered_at: Utc::now().naive_utc(),
+            session_token: uuid::Uuid::new_v4(),
+        })
+    }
+
+    async fn get_snippet(
+        state: &SharedState,
+        username: &str,
+        message_id: i64,
+        index: usize,
+    ) -> Result<(String, String), StatusCode> {
+        let response = snippet(
+            State(state.clone()),
+            session(username),
+            Path((message_id, index)),
+        )
+        .await?;
+        let disposition = response.headers()[header::CONTENT_DISPOSITION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        Ok((disposition, String::from_utf8(body.to_vec()).unwrap()))
+    }
+
+    #[test]
+    fn single_emoji_are_accepted() {
+        for emoji in [
+            "👍",
+            "☀",
+            "❤️",
+            "👍🏽",
+            "👨‍👩‍👧‍👦",
+            "🇫🇮",
+            "1️⃣",
+            "#⃣",
+            "🏴\u{E0067}\u{E0062}\u{E0073}\u{E0063}\u{E0074}\u{E007F}",
+        ] {
+            assert!(validate_emoji(emoji).is_ok(), "{emoji}");
+        }
+    }
+
+    #[test]
+    fn anything_else_is_rejected() {
+        let combining_marks = "\u{301}".repeat(50);
+        let emoji_with_combining_marks = format!("😀{}", "\u{301}".repeat(10));
+        for text in [
+            "",
+            "a",
+            "ok",
+            "1",
+            "中文",
+            "…",
+            "😀😀",
+            "😀 ",
+            "👍a",
+            "\u{FE0F}",
+            "\u{200D}",
+            &combining_marks,
+            &emoji_with_combining_marks,
+            &"👍🏽".repeat(20),
+        ] {
+            assert!(validate_emoji(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reactions_are_rejected_in_archived_rooms() {
+        let (state, room) = state_with_room().await;
+        let message = room
+            .send_new_message(&state.db_pool, "alice", Some("hi".to_string()), None)
+            .await
+            .unwrap();
+        room.set_archived(&state.db_pool, true).await.unwrap();
+
+        let form = || {
+            Valid(Form(ReactionForm {
+                message_id: message.id,
+                emoji: "👍".to_string(),
+            }))
+        };
+        let status = react(State(state.clone()), session("alice"), form()).await;
+        assert_eq!(status, Err(StatusCode::GONE));
+        let status = unreact(State(state.clone()), session("alice"), form()).await;
+        assert_eq!(status, Err(StatusCode::GONE));
+        assert!(
+            message
+                .get_reaction_summary(&state.db_pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn pins_are_rejected_in_archived_rooms() {
+        let (state, room) = state_with_room().await;
+        room.set_role(&state.db_pool, "alice", MemberRole::Owner)
+            .await
+            .unwrap();
+        let message = room
+            .send_new_message(&state.db_pool, "alice", Some("hi".to_string()), None)
+            .await
+            .unwrap();
+        room.pin(&state.db_pool, message.id, "alice").await.unwrap();
+        room.set_archived(&state.db_pool, true).await.unwrap();
+
+        let form = || {
+            Valid(Form(PinForm {
+                message_id: message.id,
+            }))
+        };
+        let status = unpin(State(state.clone()), session("alice"), form()).await;
+        assert_eq!(status, Err(StatusCode::GONE));
+        assert_eq!(room.get_pins(&state.db_pool).await.unwrap().len(), 1);
+        let status = pin(State(state.clone()), session("alice"), form()).await;
+        assert_eq!(status, Err(StatusCode::GONE));
+    }
+
+    #[tokio::test]
+    async fn snippets_serve_a_single_code_block() {
+        let (state, room) = state_with_room().await;
+        let text = "first:\n\n```rust\nfn main() {}\n```\n\nsecond:\n\n```\nplain\n```";
+        let message = room
+            .send_new_message(&state.db_pool, "alice", Some(text.to_string()), None)
+            .await
+            .unwrap();
+
+        let (disposition, code) = get_snippet(&state, "alice", message.id, 0).await.unwrap();
+        assert_eq!(
+            disposition,
+            format!("attachment; filename=\"snippet-{}-0.rs\"", message.id)
+        );
+        assert_eq!(code, "fn main() {}\n");
+
+        let (disposition, code) = get_snippet(&state, "alice", message.id, 1).await.unwrap();
+        assert_eq!(
+            disposition,
+            format!("attachment; filename=\"snippet-{}-1.txt\"", message.id)
+        );
+        assert_eq!(code, "plain\n");
+    }
+
+    #[tokio::test]
+    async fn missing_snippets_are_not_found() {
+        let (state, room) = state_with_room().await;
+        let message = room
+            .send_new_message(
+                &state.db_pool,
+                "alice",
+                Some("```\ncode\n```".to_string()),
+                None,
+            )
+            .await
+            .unwrap();
+
+        for (username, message_id, index) in [
+            ("alice", message.id, 1),
+            ("alice", message.id, usize::MAX),
+            ("alice", message.id + 1, 0),
+            ("bob", message.id, 0),
+        ] {
+            assert_eq!(
+                get_snippet(&state, username, message_id, index)
+                    .await
+                    .unwrap_err(),
+                StatusCode::NOT_FOUND,
+                "{username} {message_id} {index}"
+            );
+        }
+    }
+}
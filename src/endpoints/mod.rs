@@ -1,4 +1,9 @@
 pub mod account;
+pub mod admin;
 pub mod chat;
+pub mod export;
+pub mod invites;
+pub mod messages;
 pub mod rooms;
 pub mod upload;
+pub mod webhooks;
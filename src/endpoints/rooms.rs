@@ -1,4 +1,6 @@
-use axum::extract::State;
+use std::collections::HashMap;
+
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::{Form, Json, debug_handler};
 use axum_valid::Valid;
@@ -7,6 +9,11 @@ use tracing::instrument;
 use validator::Validate;
 
 use crate::auth::Session;
+use crate::endpoints::chat::{self, EchoedMessage, PinnedMessage, RoomEvent};
+use crate::presence::MemberPresence;
+use crate::repository::message::Message;
+use crate::repository::room::{MemberRole, Room, RoomActivity, RoomVisibility};
+use crate::room_actions::{self, MAX_TOPIC_CHARS};
 use crate::state::SharedState;
 
 #[derive(Serialize, Debug)]
@@ -14,6 +21,10 @@ use crate::state::SharedState;
 pub struct RoomResponseEntry {
     pub room_id: i64,
     pub room_name: String,
+    pub topic: Option<String>,
+    pub archived: bool,
+    pub unread_count: i64,
+    pub mention_count: i64,
 }
 
 pub type RoomResponse = Json<Vec<RoomResponseEntry>>;
@@ -24,6 +35,17 @@ pub async fn list(
     State(state): State<SharedState>,
     Session(requester): Session,
 ) -> Result<RoomResponse, StatusCode> {
+    let activity: HashMap<i64, RoomActivity> = state
+        .repository
+        .rooms
+        .find_activity(&requester.username)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to get user's room activity"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(|activity| (activity.room_id, activity))
+        .collect();
+
     let rooms = state
         .repository
         .rooms
@@ -33,9 +55,16 @@ pub async fn list(
         .inspect_err(|error| tracing::error!(?error, "Failed to get user's rooms"))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .into_iter()
-        .map(|db_room| RoomResponseEntry {
-            room_id: db_room.id,
-            room_name: db_room.name,
+        .map(|db_room| {
+            let activity = activity.get(&db_room.id);
+            RoomResponseEntry {
+                room_id: db_room.id,
+                archived: db_room.is_archived(),
+                room_name: db_room.name,
+                topic: db_room.topic,
+                unread_count: activity.map_or(0, |a| a.unread_count),
+                mention_count: activity.map_or(0, |a| a.mention_count),
+            }
         })
         .collect();
 
@@ -70,6 +99,10 @@ pub async fn create(
         .inspect(|()| tracing::debug!("Added member to room"))
         .inspect_err(|error| tracing::error!(?error, "Failed to add member to room"))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    room.set_role(&state.db_pool, &requester.username, MemberRole::Owner)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to make creator the room's owner"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(StatusCode::CREATED)
 }
@@ -89,29 +122,96 @@ pub async fn invite(
     Session(requester): Session,
     Valid(form): Valid<Form<MemberModificationForm>>,
 ) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room(&state, form.room_id).await?;
+    room_actions::invite(&state, &room, &requester.username, &form.username).await?;
+    Ok(StatusCode::CREATED)
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn kick_out(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<MemberModificationForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room(&state, form.room_id).await?;
+    room_actions::kick(&state, &room, &requester.username, &form.username).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct RetentionForm {
+    room_id: i64,
+    /// Omitted to keep attachments forever.
+    #[validate(range(min = 1, max = 3650))]
+    days: Option<i64>,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn set_retention(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<RetentionForm>>,
+) -> Result<StatusCode, StatusCode> {
+    // NOTE: Shortening the retention tombstones older attachments for good.
+    let room = self::find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    room.set_attachment_retention(&state.db_pool, form.days)
+        .await
+        .inspect(|()| tracing::debug!("Updated attachment retention"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to update attachment retention"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn members(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<Json<Vec<MemberPresence>>, StatusCode> {
     let room = state
         .repository
         .rooms
-        .find_by_id(form.room_id)
+        .find_by_id(room_id)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::NOT_FOUND)?;
+    let is_member = room
+        .has_member(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        tracing::warn!("User is not a member of this room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
 
-    room.add_member(&state.db_pool, &form.username)
+    let members = chat::room_presence(&state, &room)
         .await
-        .inspect(|()| tracing::debug!("Added member to room"))
-        .inspect_err(|error| tracing::error!(?error, "Failed to add member to room"))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(members))
+}
 
-    Ok(StatusCode::CREATED)
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct RoleForm {
+    room_id: i64,
+    #[validate(length(min = 1, max = 64))]
+    username: String,
+    role: MemberRole,
 }
 
+/// Lets the owner appoint and dismiss moderators. Ownership itself can't be
+/// handed over this way.
 #[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
 #[debug_handler]
-pub async fn kick_out(
+pub async fn set_role(
     State(state): State<SharedState>,
     Session(requester): Session,
-    Valid(form): Valid<Form<MemberModificationForm>>,
+    Valid(form): Valid<Form<RoleForm>>,
 ) -> Result<StatusCode, StatusCode> {
     let room = state
         .repository
@@ -120,12 +220,498 @@ pub async fn kick_out(
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::NOT_FOUND)?;
+    let requester_role = room
+        .get_role(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if requester_role != Some(MemberRole::Owner) {
+        tracing::warn!("User is not the owner of this room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if form.role == MemberRole::Owner || form.username == requester.username {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let updated = room
+        .set_role(&state.db_pool, &form.username, form.role)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to update member's role"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn pins(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<Json<Vec<PinnedMessage>>, StatusCode> {
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let is_member = room
+        .has_member(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        tracing::warn!("User is not a member of this room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let pins = chat::pinned_messages(&state, &room)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(pins))
+}
+
+/// How many messages are loaded at a time, unless a client asks otherwise.
+pub const HISTORY_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct HistoryQuery {
+    /// Only messages older than this one, for paging back through history.
+    before: Option<i64>,
+    #[validate(range(min = 1, max = 500))]
+    #[serde(default = "HistoryQuery::default_limit")]
+    limit: i64,
+}
+
+impl HistoryQuery {
+    const fn default_limit() -> i64 {
+        HISTORY_PAGE_SIZE
+    }
+}
+
+/// The room's messages, for clients that don't render the chat page.
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id, query = ?query))]
+#[debug_handler]
+pub async fn messages(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+    Valid(Query(query)): Valid<Query<HistoryQuery>>,
+) -> Result<Json<Vec<EchoedMessage>>, StatusCode> {
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let is_member = room
+        .has_member(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        tracing::warn!("User is not a member of this room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
 
-    room.remove_member(&state.db_pool, &form.username)
+    let messages = room
+        .get_messages_before(&state.db_pool, query.before, query.limit)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let echoed_messages = Message::to_echoed_messages(messages, &state)
         .await
-        .inspect(|()| tracing::debug!("Deleted member from room"))
-        .inspect_err(|error| tracing::error!(?error, "Failed to delete user from room"))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(echoed_messages))
+}
 
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct RenameRoomForm {
+    room_id: i64,
+    #[validate(length(min = 1, max = 64))]
+    room_name: String,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn rename(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<RenameRoomForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    room.rename(&state.db_pool, &form.room_name)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to rename room"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let change = format!(
+        "{} renamed the room from “{}” to “{}”",
+        requester.username, room.name, form.room_name
+    );
+    self::announce_change(&state, &room, &requester.username, &change).await?;
     Ok(StatusCode::OK)
 }
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct TopicForm {
+    room_id: i64,
+    /// Omitted or left empty to clear the topic.
+    #[validate(length(max = MAX_TOPIC_CHARS))]
+    topic: Option<String>,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn set_topic(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<TopicForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room(&state, form.room_id).await?;
+    room_actions::set_topic(&state, &room, &requester.username, form.topic.as_deref()).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct DescriptionForm {
+    room_id: i64,
+    /// Omitted or left empty to clear the description.
+    #[validate(length(max = 2048))]
+    description: Option<String>,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form.room_id = form.room_id))]
+#[debug_handler]
+pub async fn set_description(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<DescriptionForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    let description = form
+        .description
+        .as_deref()
+        .map(str::trim)
+        .filter(|d| !d.is_empty());
+    room.set_description(&state.db_pool, description)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to update description"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let change = match description {
+        Some(_) => format!("{} updated the room description", requester.username),
+        None => format!("{} cleared the room description", requester.username),
+    };
+    self::announce_change(&state, &room, &requester.username, &change).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct ArchiveForm {
+    room_id: i64,
+    /// `false` to bring an archived room back.
+    archived: bool,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn set_archived(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<ArchiveForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    if room.is_archived() == form.archived {
+        return Ok(StatusCode::OK);
+    }
+    room.set_archived(&state.db_pool, form.archived)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to update archival"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let change = match form.archived {
+        true => format!("{} archived the room", requester.username),
+        false => format!("{} unarchived the room", requester.username),
+    };
+    self::announce_change(&state, &room, &requester.username, &change).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct DeleteRoomForm {
+    room_id: i64,
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct VisibilityForm {
+    room_id: i64,
+    visibility: RoomVisibility,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn set_visibility(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<VisibilityForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    if room.visibility == form.visibility {
+        return Ok(StatusCode::OK);
+    }
+    room.set_visibility(&state.db_pool, form.visibility)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to update visibility"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let change = format!("{} made the room {}", requester.username, form.visibility);
+    self::announce_change(&state, &room, &requester.username, &change).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Deletes the room for good, along with its whole history.
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn delete(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<DeleteRoomForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    state
+        .repository
+        .rooms
+        .delete(room.id)
+        .await
+        .inspect(|()| tracing::info!(room.id, room.name, "Deleted room"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to delete room"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let _ = state
+        .broadcast_tx
+        .send(RoomEvent::RoomDeleted { room_id: room.id });
+    Ok(StatusCode::OK)
+}
+
+/// Looks up a room that `username` owns. Rooms the user isn't a member of
+/// are reported as missing.
+pub async fn find_room_as_owner(
+    state: &SharedState,
+    room_id: i64,
+    username: &str,
+) -> Result<Room, StatusCode> {
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let role = room
+        .get_role(&state.db_pool, username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if role != MemberRole::Owner {
+        tracing::warn!("User is not the owner of this room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(room)
+}
+
+/// Finds a room by its ID, leaving who may do what in it to the caller.
+pub async fn find_room(state: &SharedState, room_id: i64) -> Result<Room, StatusCode> {
+    state
+        .repository
+        .rooms
+        .find_by_id(room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Records a change to the room in its timeline and lets its sockets know
+/// about both the timeline entry and the room's new details.
+pub async fn announce_change(
+    state: &SharedState,
+    room: &Room,
+    actor: &str,
+    change: &str,
+) -> Result<(), StatusCode> {
+    let message = room
+        .send_system_message(&state.db_pool, actor, change)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let echoed_message = message
+        .to_echoed_message(state)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let _ = chat::broadcast(state, RoomEvent::Message(Box::new(echoed_message))).await;
+
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(room.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let _ = state.broadcast_tx.send(RoomEvent::RoomUpdated {
+        room_id: room.id,
+        archived: room.is_archived(),
+        visibility: room.visibility,
+        name: room.name,
+        topic: room.topic,
+        description: room.description,
+    });
+    Ok(())
+}
+
+/// How many rooms the directory returns at most.
+const DIRECTORY_LIMIT: i64 = 50;
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct DirectoryQuery {
+    /// Only rooms whose name or topic contains this are returned.
+    #[validate(length(max = 64))]
+    q: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct DirectoryEntry {
+    pub room_id: i64,
+    pub room_name: String,
+    pub topic: Option<String>,
+    pub visibility: RoomVisibility,
+    pub member_count: i64,
+    pub is_member: bool,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, query = ?query))]
+#[debug_handler]
+pub async fn directory(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(Query(query)): Valid<Query<DirectoryQuery>>,
+) -> Result<Json<Vec<DirectoryEntry>>, StatusCode> {
+    let search = query.q.as_deref().map(str::trim).unwrap_or_default();
+    let rooms = state
+        .repository
+        .rooms
+        .find_listed(search, &requester.username, DIRECTORY_LIMIT)
+        .await
+        .inspect(|rooms| tracing::debug!(count = rooms.len(), "Returning room directory"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(|room| DirectoryEntry {
+            room_id: room.id,
+            room_name: room.name,
+            topic: room.topic,
+            visibility: room.visibility,
+            member_count: room.member_count,
+            is_member: room.is_member,
+        })
+        .collect();
+    Ok(Json(rooms))
+}
+
+/// Joins an open room. Private rooms are reported as missing to anyone who
+/// isn't already in them.
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn join(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let is_member = room
+        .has_member(&state.db_pool, &requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if is_member {
+        return Ok(StatusCode::OK);
+    }
+
+    match room.visibility {
+        RoomVisibility::Private => return Err(StatusCode::NOT_FOUND),
+        RoomVisibility::Listed => {
+            tracing::warn!("Room needs an invitation to join, rejecting");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        RoomVisibility::Open if room.is_archived() => {
+            tracing::warn!("Room is archived, rejecting");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        RoomVisibility::Open => {}
+    }
+
+    room.add_member(&state.db_pool, &requester.username)
+        .await
+        .inspect(|()| tracing::debug!("Joined room"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to join room"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let _ = chat::broadcast(
+        &state,
+        RoomEvent::MemberJoined {
+            room_id: room.id,
+            username: requester.username,
+        },
+    )
+    .await;
+    Ok(StatusCode::CREATED)
+}
+
+/// Leaves a room, see [`room_actions::leave`].
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn leave(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let room = self::find_room(&state, room_id).await?;
+    room_actions::leave(&state, &room, &requester.username).await?;
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// What the client library expects the room list to look like, see its
+    /// tests.
+    const CLIENT_ROOM_LIST_JSON: &str = include_str!("../../client/fixtures/room_list.json");
+
+    #[test]
+    fn room_lists_are_sent_the_way_the_client_expects() {
+        let rooms = vec![RoomResponseEntry {
+            room_id: 1,
+            room_name: "general".to_string(),
+            topic: None,
+            archived: false,
+            unread_count: 3,
+            mention_count: 1,
+        }];
+        let expected: Value = serde_json::from_str(CLIENT_ROOM_LIST_JSON).unwrap();
+        assert_eq!(serde_json::to_value(&rooms).unwrap(), expected);
+    }
+}
@@ -1,17 +1,28 @@
+use std::io::SeekFrom;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use axum::body::Body;
-use axum::debug_handler;
-use axum::extract::{Multipart, Path, State};
-use axum::http::{HeaderMap, StatusCode, header};
+use axum::extract::{Multipart, Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
 use axum::response::{IntoResponse, Redirect, Response};
+use axum::{Json, debug_handler};
+use axum_valid::Valid;
+use chrono::{DateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
 use tracing::instrument;
 use uuid::Uuid;
+use validator::Validate;
 
 use crate::auth::Session;
+use crate::endpoints::account::UserProfile;
+use crate::endpoints::chat::{self, RoomEvent};
+use crate::repository::account::Account;
+use crate::repository::room::Room;
+use crate::repository::upload::{ThumbnailError, Upload, image_dimensions};
 use crate::state::SharedState;
 
 #[instrument(skip_all, err(Debug))]
@@ -20,7 +31,7 @@ pub async fn upload_handler(
     State(state): State<SharedState>,
     Session(uploader): Session,
     mut multipart: Multipart,
-) -> Result<Redirect, StatusCode> {
+) -> Result<Redirect, UploadRejection> {
     let mut file_data = None;
     let mut room_id = None;
 
@@ -61,11 +72,39 @@ pub async fn upload_handler(
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::NOT_FOUND)?;
+    let is_member = room
+        .has_member(&state.db_pool, &uploader.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        tracing::warn!("User is not a member of this room, rejecting upload");
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    if room.is_archived() {
+        tracing::warn!("Room is archived, rejecting upload");
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let needed = data.len() as u64;
+    self::check_quotas(&state, &uploader.username, Some(&room), needed, false).await?;
 
     let upload = room
-        .upload(&state.db_pool, &original_filename, &data)
+        .upload(
+            &state.db_pool,
+            &uploader.username,
+            &original_filename,
+            &data,
+        )
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // NOTE: Parallel uploads may all have passed the check above, so it is
+    // repeated with this one stored, and the upload undone if it doesn't fit.
+    if let Err(rejection) =
+        self::check_quotas(&state, &uploader.username, Some(&room), needed, true).await
+    {
+        self::discard(&state, &upload).await;
+        return Err(rejection);
+    }
 
     let uuid = Uuid::from_str(&upload.uuid).unwrap();
     let message = room
@@ -77,19 +116,259 @@ pub async fn upload_handler(
         .to_echoed_message(&state)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let _recv_count = state
-        .broadcast_tx
-        .send(echoed_message)
+    let _recv_count = chat::broadcast(&state, RoomEvent::Message(Box::new(echoed_message)))
+        .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Redirect::to(&format!("/chat/{room_id}")))
 }
 
+/// Sets the requester's avatar to the uploaded image. The original is kept as
+/// a regular upload, and shown through its thumbnails.
+#[instrument(skip_all, fields(uploader.username = uploader.username), err(Debug))]
+#[debug_handler]
+pub async fn avatar_handler(
+    State(state): State<SharedState>,
+    Session(uploader): Session,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<UserProfile>), UploadRejection> {
+    let mut file_data = None;
+    while let Some(f) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        if f.name() == Some("file") {
+            const DEFAULT_FILENAME: &str = "avatar";
+            let filename = PathBuf::from(f.file_name().unwrap_or(DEFAULT_FILENAME));
+            let data = f.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+            file_data = Some((filename, data));
+        }
+    }
+    let (original_filename, data) = file_data.ok_or(StatusCode::BAD_REQUEST)?;
+    if image_dimensions(&data).is_none() {
+        tracing::debug!("Rejecting avatar: not an image");
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    }
+
+    let needed = data.len() as u64;
+    self::check_quotas(&state, &uploader.username, None, needed, false).await?;
+
+    let upload = state
+        .repository
+        .uploads
+        .create_unattached(&uploader.username, &original_filename, &data)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Err(rejection) = self::check_quotas(&state, &uploader.username, None, needed, true).await
+    {
+        self::discard(&state, &upload).await;
+        return Err(rejection);
+    }
+
+    // NOTE: Generating the thumbnail right away makes sure that the image can
+    // actually be decoded, rather than just having a recognizable header.
+    if let Err(error) = upload.thumbnail(Account::AVATAR_SIZE).await {
+        tracing::debug!(?error, "Rejecting avatar: failed to resize");
+        self::discard(&state, &upload).await;
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    }
+
+    let accounts = &state.repository.accounts;
+    accounts
+        .set_avatar(&uploader.username, Some(&upload.uuid))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let account = accounts
+        .find(&uploader.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((StatusCode::CREATED, Json(account.into())))
+}
+
+/// Checks that `needed` more bytes fit into the uploader's storage quota and,
+/// for uploads into a room, the room's. Once the upload is `stored`, its own
+/// size is already part of the usage and is left out again.
+async fn check_quotas(
+    state: &SharedState,
+    uploader: &str,
+    room: Option<&Room>,
+    needed: u64,
+    stored: bool,
+) -> Result<(), UploadRejection> {
+    let own_size = if stored { needed } else { 0 };
+    let uploads = &state.repository.uploads;
+
+    let account_used = uploads
+        .usage_by_account(uploader)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    QuotaScope::Account(uploader.to_string()).check(
+        account_used.saturating_sub(own_size),
+        state.settings.account_storage_quota,
+        needed,
+    )?;
+
+    if let Some(room) = room {
+        let room_used = uploads
+            .usage_by_room(room.id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        QuotaScope::Room(room.name.clone()).check(
+            room_used.saturating_sub(own_size),
+            state.settings.room_storage_quota,
+            needed,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Removes an upload that was stored but then rejected.
+async fn discard(state: &SharedState, upload: &Upload) {
+    let _ = upload.remove_files().await;
+    let _ = state.repository.uploads.delete(&upload.uuid).await;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[must_use]
+pub enum UploadRejection {
+    #[error("{0}")]
+    Status(StatusCode),
+
+    #[error(
+        "Storage quota of {scope} exceeded: {used} of {quota} bytes are in use, \
+         the upload needs {needed} more"
+    )]
+    QuotaExceeded {
+        scope: QuotaScope,
+        used: u64,
+        quota: u64,
+        needed: u64,
+    },
+}
+
+#[derive(Debug)]
+#[must_use]
+pub enum QuotaScope {
+    Account(String),
+    Room(String),
+}
+
+impl QuotaScope {
+    fn check(self, used: u64, quota: u64, needed: u64) -> Result<(), UploadRejection> {
+        if used.saturating_add(needed) <= quota {
+            return Ok(());
+        }
+
+        tracing::debug!(scope = ?self, used, quota, needed, "Rejecting upload: quota exceeded");
+        Err(UploadRejection::QuotaExceeded {
+            scope: self,
+            used,
+            quota,
+            needed,
+        })
+    }
+}
+
+impl std::fmt::Display for QuotaScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Account(username) => write!(f, "account '{username}'"),
+            Self::Room(room_name) => write!(f, "room '{room_name}'"),
+        }
+    }
+}
+
+impl From<StatusCode> for UploadRejection {
+    fn from(status_code: StatusCode) -> Self {
+        Self::Status(status_code)
+    }
+}
+
+impl IntoResponse for UploadRejection {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Status(status_code) => status_code.into_response(),
+            Self::QuotaExceeded { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()).into_response()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct StorageUsage {
+    pub used: u64,
+    pub quota: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct RoomStorageUsage {
+    pub room_id: i64,
+    pub room_name: String,
+    #[serde(flatten)]
+    pub usage: StorageUsage,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct StorageUsageResponse {
+    pub account: StorageUsage,
+    pub rooms: Vec<RoomStorageUsage>,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username), err(Debug))]
+#[debug_handler]
+pub async fn storage_usage(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+) -> Result<Json<StorageUsageResponse>, StatusCode> {
+    let uploads = &state.repository.uploads;
+    let account = StorageUsage {
+        used: uploads
+            .usage_by_account(&requester.username)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        quota: state.settings.account_storage_quota,
+    };
+
+    let mut rooms = vec![];
+    for room in state
+        .repository
+        .rooms
+        .find_by_member(&requester.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        let used = uploads
+            .usage_by_room(room.id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        rooms.push(RoomStorageUsage {
+            room_id: room.id,
+            room_name: room.name,
+            usage: StorageUsage {
+                used,
+                quota: state.settings.room_storage_quota,
+            },
+        });
+    }
+
+    Ok(Json(StorageUsageResponse { account, rooms }))
+}
+
 #[instrument(skip_all, err(Debug))]
 #[debug_handler]
 pub async fn download_handler(
     State(state): State<SharedState>,
+    Session(requester): Session,
     Path(uuid): Path<String>,
+    request_headers: HeaderMap,
 ) -> Result<Response, StatusCode> {
     let uuid = Uuid::from_str(&uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
     let upload = state
@@ -99,26 +378,473 @@ pub async fn download_handler(
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::NOT_FOUND)?;
+    self::check_access(&state, &upload, &requester.username).await?;
+    if upload.is_deleted() {
+        return Err(StatusCode::GONE);
+    }
     let store_path = upload
         .store_path()
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    match File::open(store_path).await {
-        Err(error) => {
-            tracing::error!(?error, "Failed to handle file download request");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    let mut file = File::open(store_path)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to handle file download request"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let length = metadata.len();
+    let last_modified = metadata
+        .modified()
+        .map(DateTime::<Utc>::from)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let validators = Validators::new(&upload.uuid, length, last_modified);
+
+    let filename = upload.filename.to_string_lossy();
+    let disposition = format!("attachment; filename = \"{filename}\"",);
+    let mut headers = validators.to_headers();
+    headers.insert(header::CONTENT_DISPOSITION, disposition.parse().unwrap());
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    if validators.is_not_modified(&request_headers) {
+        tracing::debug!("Client copy is fresh, responding with 304");
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let requested_range = request_headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| validators.if_range_holds(&request_headers))
+        .map(|value| ByteRange::parse(value, length));
+
+    match requested_range {
+        Some(Err(RangeError::Unsatisfiable)) => {
+            tracing::debug!(length, "Requested range is not satisfiable");
+            let content_range = format!("bytes */{length}");
+            headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+            Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response())
         }
 
-        Ok(file) => {
-            let stream = ReaderStream::new(file);
+        Some(Ok(range)) => {
+            tracing::debug!(?range, "Serving partial content");
+            file.seek(SeekFrom::Start(range.start))
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let stream = ReaderStream::new(file.take(range.len()));
             let body = Body::from_stream(stream);
 
-            let filename = upload.filename.to_string_lossy();
-            let value = format!("attachment; filename = \"{filename}\"",);
-            let headers =
-                HeaderMap::from_iter([(header::CONTENT_DISPOSITION, value.parse().unwrap())]);
+            let content_range = format!("bytes {}-{}/{length}", range.start, range.end);
+            headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+            headers.insert(header::CONTENT_LENGTH, range.len().into());
+
+            Ok((StatusCode::PARTIAL_CONTENT, headers, body).into_response())
+        }
+
+        None | Some(Err(RangeError::Unsupported)) => {
+            let stream = ReaderStream::new(file);
+            let body = Body::from_stream(stream);
+            headers.insert(header::CONTENT_LENGTH, length.into());
 
             Ok((headers, body).into_response())
         }
     }
 }
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct ThumbnailQuery {
+    #[validate(range(min = 1, max = 4096))]
+    size: Option<u32>,
+}
+
+#[instrument(skip_all, fields(query = ?query), err(Debug))]
+#[debug_handler]
+pub async fn thumbnail_handler(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(uuid): Path<String>,
+    Valid(Query(query)): Valid<Query<ThumbnailQuery>>,
+    request_headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+
+    let uuid = Uuid::from_str(&uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let upload = state
+        .repository
+        .uploads
+        .find(uuid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    self::check_access(&state, &upload, &requester.username).await?;
+
+    let thumbnail_path = upload
+        .thumbnail(query.size.unwrap_or(DEFAULT_THUMBNAIL_SIZE))
+        .await
+        .map_err(|error| match error {
+            ThumbnailError::NotAnImage => StatusCode::NOT_FOUND,
+            ThumbnailError::Deleted => StatusCode::GONE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    let file = File::open(&thumbnail_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let last_modified = metadata
+        .modified()
+        .map(DateTime::<Utc>::from)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let cache_key = thumbnail_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let validators = Validators::new(&cache_key, metadata.len(), last_modified);
+
+    let mut headers = validators.to_headers();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    if validators.is_not_modified(&request_headers) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    headers.insert(header::CONTENT_LENGTH, metadata.len().into());
+    let body = Body::from_stream(ReaderStream::new(file));
+    Ok((headers, body).into_response())
+}
+
+/// Files sent to a room are only served to its members. Uploads that don't
+/// belong to any room are served to anyone signed in if they are an avatar.
+async fn check_access(
+    state: &SharedState,
+    upload: &Upload,
+    username: &str,
+) -> Result<(), StatusCode> {
+    let Some(room_id) = upload.room_id else {
+        // NOTE: Files sent to a room lose it when the room is deleted, and
+        // stay around until they are collected.
+        let is_avatar = state
+            .repository
+            .uploads
+            .is_avatar(&upload.uuid)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if !is_avatar {
+            tracing::warn!("Upload belongs to no room and is not an avatar, rejecting");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        return Ok(());
+    };
+    let is_member = match state
+        .repository
+        .rooms
+        .find_by_id(room_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        Some(room) => room
+            .has_member(&state.db_pool, username)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => false,
+    };
+    if !is_member {
+        tracing::warn!("User is not a member of the upload's room, rejecting");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// Cache validators of a stored file, used to answer conditional requests.
+#[derive(Debug)]
+struct Validators {
+    etag: String,
+    last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+    fn new(key: &str, length: u64, last_modified: DateTime<Utc>) -> Self {
+        // NOTE: Uploads and thumbnails are never modified in place, so a unique key
+        // and the length are enough to identify a particular version of a file.
+        let etag = format!("\"{key}-{length:x}\"");
+        let last_modified = last_modified.with_nanosecond(0).unwrap_or(last_modified);
+        Self {
+            etag,
+            last_modified,
+        }
+    }
+
+    fn to_headers(&self) -> HeaderMap {
+        let last_modified = self
+            .last_modified
+            .format(Self::HTTP_DATE_FORMAT)
+            .to_string();
+        HeaderMap::from_iter([
+            (header::ETAG, self.etag.parse().unwrap()),
+            (header::LAST_MODIFIED, last_modified.parse().unwrap()),
+        ])
+    }
+
+    /// Evaluates `If-None-Match` and, in its absence, `If-Modified-Since`.
+    fn is_not_modified(&self, request_headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH) {
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+            return if_none_match.trim() == "*"
+                || if_none_match
+                    .split(',')
+                    .map(|tag| tag.trim().trim_start_matches("W/"))
+                    .any(|tag| tag == self.etag);
+        }
+
+        request_headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_http_date)
+            .is_some_and(|since| self.last_modified <= since)
+    }
+
+    /// A `Range` header is only honoured if `If-Range` is absent or still
+    /// matches the stored file. Entity tags are compared strongly here.
+    fn if_range_holds(&self, request_headers: &HeaderMap) -> bool {
+        let Some(if_range) = request_headers.get(header::IF_RANGE) else {
+            return true;
+        };
+        let Ok(if_range) = if_range.to_str() else {
+            return false;
+        };
+
+        match if_range.trim() {
+            tag if tag.starts_with('"') => tag == self.etag,
+            tag if tag.starts_with("W/") => false,
+            date => parse_http_date(date).is_some_and(|date| date == self.last_modified),
+        }
+    }
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// An inclusive range of bytes, as found in `Range` and `Content-Range`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeError {
+    /// The header is malformed or asks for several ranges at once. Such
+    /// requests are answered with the full file, as the RFC allows.
+    Unsupported,
+    /// The range lies entirely past the end of the file.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    fn parse(header_value: &str, length: u64) -> Result<Self, RangeError> {
+        let spec = header_value
+            .trim()
+            .strip_prefix("bytes=")
+            .ok_or(RangeError::Unsupported)?;
+        if spec.contains(',') {
+            return Err(RangeError::Unsupported);
+        }
+
+        let (start, end) = spec.split_once('-').ok_or(RangeError::Unsupported)?;
+        let parse = |s: &str| s.trim().parse::<u64>().map_err(|_| RangeError::Unsupported);
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", "") => return Err(RangeError::Unsupported),
+            ("", suffix) => {
+                let suffix = parse(suffix)?;
+                if suffix == 0 || length == 0 {
+                    return Err(RangeError::Unsatisfiable);
+                }
+                (length.saturating_sub(suffix), length - 1)
+            }
+            (start, "") => (parse(start)?, length.saturating_sub(1)),
+            (start, end) => (parse(start)?, parse(end)?.min(length.saturating_sub(1))),
+        };
+
+        if start > end {
+            // NOTE: A last position before the first one makes the header invalid,
+            // while a first position past the end makes the range unsatisfiable.
+            return Err(if start >= length {
+                RangeError::Unsatisfiable
+            } else {
+                RangeError::Unsupported
+            });
+        }
+        if start >= length {
+            return Err(RangeError::Unsatisfiable);
+        }
+
+        Ok(Self { start, end })
+    }
+
+    const fn len(self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository;
+
+    fn range(header_value: &str, length: u64) -> Result<(u64, u64), RangeError> {
+        ByteRange::parse(header_value, length).map(|range| (range.start, range.end))
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(range("bytes=0-99", 1000), Ok((0, 99)));
+        assert_eq!(range(" bytes=10-10 ", 1000), Ok((10, 10)));
+        assert_eq!(range("bytes=900-", 1000), Ok((900, 999)));
+        assert_eq!(range("bytes=-100", 1000), Ok((900, 999)));
+        assert_eq!(ByteRange::parse("bytes=10-19", 1000).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(range("bytes=900-5000", 1000), Ok((900, 999)));
+        assert_eq!(range("bytes=-5000", 1000), Ok((0, 999)));
+    }
+
+    #[test]
+    fn unsupported_ranges_are_ignored() {
+        for header_value in [
+            "",
+            "bytes=",
+            "bytes=-",
+            "items=0-9",
+            "bytes=0-9,20-29",
+            "bytes=a-b",
+            "bytes=9-0",
+            "bytes=0-9-",
+        ] {
+            assert_eq!(
+                range(header_value, 1000),
+                Err(RangeError::Unsupported),
+                "{header_value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(range("bytes=1000-", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(
+            range("bytes=2000-3000", 1000),
+            Err(RangeError::Unsatisfiable)
+        );
+        assert_eq!(range("bytes=-0", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(range("bytes=0-", 0), Err(RangeError::Unsatisfiable));
+        assert_eq!(range("bytes=-10", 0), Err(RangeError::Unsatisfiable));
+    }
+
+    #[test]
+    fn uploads_within_the_quota_are_accepted() {
+        assert!(
+            QuotaScope::Account("alice".into())
+                .check(0, 100, 100)
+                .is_ok()
+        );
+        assert!(
+            QuotaScope::Room("general".into())
+                .check(60, 100, 40)
+                .is_ok()
+        );
+        assert!(
+            QuotaScope::Room("general".into())
+                .check(100, 100, 0)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn uploads_over_the_quota_are_rejected() {
+        let rejection = QuotaScope::Account("alice".into())
+            .check(60, 100, 41)
+            .unwrap_err();
+        assert!(matches!(
+            rejection,
+            UploadRejection::QuotaExceeded {
+                scope: QuotaScope::Account(ref username),
+                used: 60,
+                quota: 100,
+                needed: 41,
+            } if username == "alice"
+        ));
+        assert_eq!(
+            rejection.to_string(),
+            "Storage quota of account 'alice' exceeded: 60 of 100 bytes are in use, \
+             the upload needs 41 more"
+        );
+        assert_eq!(
+            rejection.into_response().status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn huge_uploads_do_not_overflow_the_check() {
+        assert!(
+            QuotaScope::Room("general".into())
+                .check(1, u64::MAX - 1, u64::MAX)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn uploads_without_a_room_are_only_served_as_avatars() {
+        let state = repository::test_state(&[]).await;
+        for username in ["alice", "bob"] {
+            state
+                .repository
+                .accounts
+                .create_placeholder(username, None)
+                .await
+                .unwrap();
+        }
+        let room = state.repository.rooms.create("general").await.unwrap();
+        let uuid = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO file_uploads (uuid, filename, uploader, room_id) VALUES (?, 'a.png', 'alice', ?)")
+            .bind(&uuid)
+            .bind(room.id)
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+        state.repository.rooms.delete(room.id).await.unwrap();
+
+        let upload = state
+            .repository
+            .uploads
+            .find(uuid.parse().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(upload.room_id, None);
+        assert_eq!(
+            check_access(&state, &upload, "bob").await,
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        state
+            .repository
+            .accounts
+            .set_avatar("alice", Some(&uuid))
+            .await
+            .unwrap();
+        assert_eq!(check_access(&state, &upload, "bob").await, Ok(()));
+    }
+}
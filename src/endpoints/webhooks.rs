@@ -0,0 +1,498 @@
+use std::fmt::Write;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::{Form, Json, debug_handler};
+use axum_valid::Valid;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use url::Url;
+use validator::Validate;
+
+use crate::auth::Session;
+use crate::endpoints::chat::publish_message;
+use crate::endpoints::rooms::find_room_as_owner;
+use crate::repository::account::DeletedMessages;
+use crate::repository::webhook::{IncomingWebhook, OutgoingWebhook, WebhookDelivery};
+use crate::state::SharedState;
+
+/// The longest message a webhook may post, after its attachments have been
+/// turned into text.
+const MAX_TEXT_LENGTH: usize = 16 * 1024;
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct CreateWebhookForm {
+    room_id: i64,
+    /// Shown as the display name of the messages' sender.
+    #[validate(length(min = 1, max = 64))]
+    name: String,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct WebhookResponse {
+    pub id: i64,
+    pub name: String,
+    pub url: String,
+    pub bot_username: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<IncomingWebhook> for WebhookResponse {
+    fn from(webhook: IncomingWebhook) -> Self {
+        Self {
+            url: webhook.url(),
+            id: webhook.id,
+            name: webhook.name,
+            bot_username: webhook.bot_username,
+            created_by: webhook.created_by,
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+/// Creates a webhook along with the bot account it posts as.
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn create(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<CreateWebhookForm>>,
+) -> Result<(StatusCode, Json<WebhookResponse>), StatusCode> {
+    let room = find_room_as_owner(&state, form.room_id, &requester.username).await?;
+
+    let bot_username = IncomingWebhook::new_bot_username();
+    state
+        .repository
+        .accounts
+        .create_bot(&bot_username, Some(&form.name))
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to create webhook bot account"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let webhook = match state
+        .repository
+        .webhooks
+        .create_incoming(room.id, &form.name, &bot_username, &requester.username)
+        .await
+    {
+        Ok(webhook) => webhook,
+        Err(error) => {
+            tracing::error!(?error, "Failed to create webhook");
+            // NOTE: Nothing could post as the bot without its webhook.
+            let _ = state
+                .repository
+                .accounts
+                .delete(&bot_username, DeletedMessages::Remove)
+                .await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    tracing::debug!(webhook.id, "Created webhook");
+    Ok((StatusCode::CREATED, Json(webhook.into())))
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn list(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<Json<Vec<WebhookResponse>>, StatusCode> {
+    let room = find_room_as_owner(&state, room_id, &requester.username).await?;
+    let webhooks = state
+        .repository
+        .webhooks
+        .find_incoming_by_room(room.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(webhooks.into_iter().map(Into::into).collect()))
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct DeleteWebhookForm {
+    webhook_id: i64,
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn delete(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<DeleteWebhookForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let webhook = state
+        .repository
+        .webhooks
+        .find_incoming(form.webhook_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    find_room_as_owner(&state, webhook.room_id, &requester.username).await?;
+
+    state
+        .repository
+        .webhooks
+        .delete_incoming(webhook.id)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to delete webhook"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+/// The subset of Slack's incoming webhook payload that makes sense here.
+/// Formatting options like `username`, `icon_url` and `blocks` are ignored.
+#[derive(Deserialize, Debug)]
+#[must_use]
+pub struct WebhookPayload {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    attachments: Vec<WebhookAttachment>,
+}
+
+#[derive(Deserialize, Debug)]
+#[must_use]
+pub struct WebhookAttachment {
+    #[serde(default)]
+    fallback: Option<String>,
+    #[serde(default)]
+    pretext: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    title_link: Option<String>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    fields: Vec<WebhookAttachmentField>,
+    #[serde(default)]
+    footer: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[must_use]
+pub struct WebhookAttachmentField {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    value: Option<String>,
+}
+
+impl WebhookPayload {
+    /// The message's Markdown, with each attachment as a paragraph of its own.
+    fn to_markdown(&self) -> Option<String> {
+        let mut paragraphs: Vec<String> = self.text.iter().cloned().collect();
+        paragraphs.extend(
+            self.attachments
+                .iter()
+                .filter_map(WebhookAttachment::to_markdown),
+        );
+        let markdown = paragraphs.join("\n\n");
+        (!markdown.trim().is_empty()).then_some(markdown)
+    }
+}
+
+impl WebhookAttachment {
+    fn to_markdown(&self) -> Option<String> {
+        let mut lines = vec![];
+        lines.extend(self.pretext.clone());
+        match (&self.title, &self.title_link) {
+            (Some(title), Some(link)) => lines.push(format!("**[{title}]({link})**")),
+            (Some(title), None) => lines.push(format!("**{title}**")),
+            _ => {}
+        }
+        lines.extend(self.text.clone());
+        for field in &self.fields {
+            let mut line = String::new();
+            if let Some(title) = &field.title {
+                let _ = write!(line, "**{title}:** ");
+            }
+            line.push_str(field.value.as_deref().unwrap_or_default());
+            lines.push(line);
+        }
+        lines.extend(self.footer.as_ref().map(|footer| format!("_{footer}_")));
+
+        // NOTE: The fallback is the plain text summary Slack shows wherever it
+        // can't show the attachment itself.
+        if lines.is_empty() {
+            lines.extend(self.fallback.clone());
+        }
+        // NOTE: Two trailing spaces make Markdown keep the line breaks.
+        (!lines.is_empty()).then(|| lines.join("  \n"))
+    }
+}
+
+/// Posts the payload into the webhook's room. Responds like Slack does, with
+/// a short plain text explanation.
+#[instrument(skip_all, fields(webhook.id))]
+#[debug_handler]
+pub async fn receive(
+    State(state): State<SharedState>,
+    Path(token): Path<String>,
+    Json(payload): Json<WebhookPayload>,
+) -> Result<&'static str, (StatusCode, &'static str)> {
+    let internal_error = (StatusCode::INTERNAL_SERVER_ERROR, "internal_error");
+    let Some(webhook) = state
+        .repository
+        .webhooks
+        .find_incoming_by_token(&token)
+        .await
+        .map_err(|_| internal_error)?
+    else {
+        tracing::warn!("Unknown webhook token, rejecting");
+        return Err((StatusCode::NOT_FOUND, "no_service"));
+    };
+    tracing::Span::current().record("webhook.id", webhook.id);
+
+    let room = state
+        .repository
+        .rooms
+        .find_by_id(webhook.room_id)
+        .await
+        .map_err(|_| internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, "channel_not_found"))?;
+    if room.is_archived() {
+        return Err((StatusCode::GONE, "channel_is_archived"));
+    }
+
+    let text = payload
+        .to_markdown()
+        .ok_or((StatusCode::BAD_REQUEST, "no_text"))?;
+    if text.len() > MAX_TEXT_LENGTH {
+        return Err((StatusCode::BAD_REQUEST, "msg_too_long"));
+    }
+
+    publish_message(&state, &room, &webhook.bot_username, Some(text), None)
+        .await
+        .inspect(|()| tracing::debug!(webhook.id, "Posted webhook message"))
+        .map_err(|_| internal_error)?;
+    Ok("ok")
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[must_use]
+pub struct CreateOutgoingWebhookForm {
+    room_id: i64,
+    #[validate(url, length(max = 2048))]
+    url: String,
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct OutgoingWebhookResponse {
+    pub id: i64,
+    pub url: String,
+    /// Verifies the signature of every delivery.
+    pub secret: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<OutgoingWebhook> for OutgoingWebhookResponse {
+    fn from(webhook: OutgoingWebhook) -> Self {
+        Self {
+            id: webhook.id,
+            url: webhook.url,
+            secret: webhook.secret,
+            created_by: webhook.created_by,
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+/// Subscribes a URL to the room's messages, uploads and membership changes.
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn create_outgoing(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<CreateOutgoingWebhookForm>>,
+) -> Result<(StatusCode, Json<OutgoingWebhookResponse>), StatusCode> {
+    let room = find_room_as_owner(&state, form.room_id, &requester.username).await?;
+    let is_http = Url::parse(&form.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+    if !is_http {
+        tracing::warn!("Webhook URL is not http(s), rejecting");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let webhook = state
+        .repository
+        .webhooks
+        .create_outgoing(room.id, &form.url, &requester.username)
+        .await
+        .inspect(|webhook| tracing::debug!(webhook.id, "Created outgoing webhook"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to create outgoing webhook"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((StatusCode::CREATED, Json(webhook.into())))
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn list_outgoing(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<Json<Vec<OutgoingWebhookResponse>>, StatusCode> {
+    let room = find_room_as_owner(&state, room_id, &requester.username).await?;
+    let webhooks = state
+        .repository
+        .webhooks
+        .find_outgoing_by_room(room.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(webhooks.into_iter().map(Into::into).collect()))
+}
+
+#[instrument(skip_all, fields(requester.username = requester.username, form = ?form))]
+#[debug_handler]
+pub async fn delete_outgoing(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Valid(form): Valid<Form<DeleteWebhookForm>>,
+) -> Result<StatusCode, StatusCode> {
+    let webhook = state
+        .repository
+        .webhooks
+        .find_outgoing(form.webhook_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    find_room_as_owner(&state, webhook.room_id, &requester.username).await?;
+
+    state
+        .repository
+        .webhooks
+        .delete_outgoing(webhook.id)
+        .await
+        .inspect_err(|error| tracing::error!(?error, "Failed to delete outgoing webhook"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct DeadLetterResponse {
+    pub delivery_id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub failed_at: Option<NaiveDateTime>,
+}
+
+impl From<WebhookDelivery> for DeadLetterResponse {
+    fn from(delivery: WebhookDelivery) -> Self {
+        Self {
+            delivery_id: delivery.id,
+            webhook_id: delivery.webhook_id,
+            event: delivery.event,
+            payload: delivery.payload,
+            attempts: delivery.attempts,
+            last_error: delivery.last_error,
+            created_at: delivery.created_at,
+            failed_at: delivery.failed_at,
+        }
+    }
+}
+
+/// Deliveries to the room's outgoing webhooks that failed too many times to
+/// be retried again.
+#[instrument(skip_all, fields(requester.username = requester.username, room_id = room_id))]
+#[debug_handler]
+pub async fn dead_letters(
+    State(state): State<SharedState>,
+    Session(requester): Session,
+    Path(room_id): Path<i64>,
+) -> Result<Json<Vec<DeadLetterResponse>>, StatusCode> {
+    let room = find_room_as_owner(&state, room_id, &requester.username).await?;
+    let deliveries = state
+        .repository
+        .webhooks
+        .find_dead_letters(room.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(deliveries.into_iter().map(Into::into).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn to_markdown(payload: serde_json::Value) -> Option<String> {
+        serde_json::from_value::<WebhookPayload>(payload)
+            .unwrap()
+            .to_markdown()
+    }
+
+    #[test]
+    fn plain_text_is_kept_as_it_is() {
+        assert_eq!(
+            to_markdown(json!({ "text": "Deploy *finished*" })).as_deref(),
+            Some("Deploy *finished*")
+        );
+    }
+
+    #[test]
+    fn attachments_become_paragraphs() {
+        let markdown = to_markdown(json!({
+            "text": "New alert",
+            "attachments": [
+                {
+                    "fallback": "CPU high on web-1",
+                    "pretext": "Monitoring says:",
+                    "title": "CPU high",
+                    "title_link": "https://monitoring.example.com/alerts/1",
+                    "text": "Load is above 90%",
+                    "fields": [
+                        { "title": "Host", "value": "web-1" },
+                        { "value": "untitled" }
+                    ],
+                    "footer": "monitoring"
+                },
+                { "title": "Second" }
+            ]
+        }));
+        assert_eq!(
+            markdown.as_deref(),
+            Some(
+                "New alert\n\n\
+                 Monitoring says:  \n\
+                 **[CPU high](https://monitoring.example.com/alerts/1)**  \n\
+                 Load is above 90%  \n\
+                 **Host:** web-1  \n\
+                 untitled  \n\
+                 _monitoring_\n\n\
+                 **Second**"
+            )
+        );
+    }
+
+    #[test]
+    fn fallbacks_stand_in_for_empty_attachments() {
+        assert_eq!(
+            to_markdown(json!({ "attachments": [{ "fallback": "Build #12 passed" }] })).as_deref(),
+            Some("Build #12 passed")
+        );
+        assert_eq!(
+            to_markdown(json!({ "attachments": [{ "fallback": "unused", "text": "shown" }] }))
+                .as_deref(),
+            Some("shown")
+        );
+    }
+
+    #[test]
+    fn empty_payloads_have_no_markdown() {
+        assert_eq!(to_markdown(json!({})), None);
+        assert_eq!(to_markdown(json!({ "text": "  " })), None);
+        assert_eq!(to_markdown(json!({ "attachments": [{}] })), None);
+    }
+}
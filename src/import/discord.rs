@@ -0,0 +1,197 @@
+//! Reads the JSON exports of a single channel made by `DiscordChatExporter`.
+//!
+//! Attachments are only links to Discord's servers, unless the export was
+//! made with its media downloaded, in which case they are paths relative to
+//! the JSON file.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono::DateTime;
+use serde::Deserialize;
+use url::Url;
+
+use super::{
+    AttachmentLocation, ForeignAttachment, ForeignChannel, ForeignExport, ForeignMessage,
+    ForeignUser, ImportError, Platform,
+};
+
+#[derive(Deserialize, Debug)]
+struct Export {
+    channel: Channel,
+    messages: Vec<Message>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Channel {
+    name: String,
+    #[serde(default)]
+    topic: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Message {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: String,
+    #[serde(default)]
+    content: String,
+    author: Author,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    #[serde(default)]
+    reference: Option<Reference>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Author {
+    id: String,
+    name: String,
+    #[serde(default)]
+    nickname: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Attachment {
+    url: String,
+    file_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Reference {
+    #[serde(default)]
+    message_id: Option<String>,
+}
+
+/// Reads the export at `path`.
+pub fn read(path: &Path) -> Result<ForeignExport, ImportError> {
+    let file_name = path.display().to_string();
+    let export: Export = super::parse_json(&file_name, &std::fs::read(path)?)?;
+    let export_directory = path
+        .parent()
+        .and_then(|directory| std::fs::canonicalize(directory).ok());
+
+    let mut users = HashMap::new();
+    let mut messages = vec![];
+    for message in export.messages {
+        let author = message.author;
+        users
+            .entry(author.id.clone())
+            .or_insert_with(|| ForeignUser {
+                id: author.id.clone(),
+                name: author.name,
+                display_name: author.nickname,
+            });
+
+        let sent_at = DateTime::parse_from_rfc3339(&message.timestamp)
+            .map_err(|_| ImportError::Layout(format!("invalid timestamp {}", message.timestamp)))?
+            .naive_utc();
+        // NOTE: Anything but plain messages and replies is a notice, like a pin
+        // or someone joining the server.
+        let is_system = !matches!(message.kind.as_str(), "Default" | "Reply");
+        let text = match message.content {
+            content if !content.is_empty() => Some(content),
+            _ if is_system => Some(message.kind),
+            _ => None,
+        };
+        let attachments = message
+            .attachments
+            .into_iter()
+            .map(|attachment| ForeignAttachment {
+                location: self::local_file(export_directory.as_deref(), &attachment.url)
+                    .map(AttachmentLocation::File),
+                filename: attachment.file_name,
+            })
+            .collect();
+
+        messages.push(ForeignMessage {
+            id: message.id,
+            author_id: author.id,
+            text,
+            sent_at,
+            reply_to: message.reference.and_then(|reference| reference.message_id),
+            is_system,
+            attachments,
+        });
+    }
+
+    Ok(ForeignExport {
+        platform: Platform::Discord,
+        users,
+        channels: vec![ForeignChannel {
+            name: export.channel.name,
+            topic: export.channel.topic.filter(|topic| !topic.is_empty()),
+            messages,
+        }],
+        archive: None,
+    })
+}
+
+/// Resolves an attachment's URL to a downloaded file next to the export, if
+/// it is one. Those URLs are relative and percent-encoded.
+///
+/// Only files within the export's directory are considered, so that an
+/// export can't have files from elsewhere on the server posted into a room.
+fn local_file(export_directory: Option<&Path>, url: &str) -> Option<PathBuf> {
+    if Url::parse(url).is_ok() {
+        return None;
+    }
+    let export_directory = export_directory?;
+    let path = Url::from_directory_path(export_directory)
+        .ok()?
+        .join(url)
+        .ok()?
+        .to_file_path()
+        .ok()?;
+    // NOTE: Canonicalizing resolves `..` as well as symlinks pointing out of
+    // the directory.
+    let path = std::fs::canonicalize(path).ok()?;
+    (path.starts_with(export_directory) && path.is_file()).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_files_next_to_the_export() {
+        let directory = tempfile::tempdir().unwrap();
+        let export_directory = std::fs::canonicalize(directory.path()).unwrap();
+        std::fs::create_dir(export_directory.join("media")).unwrap();
+        let file = export_directory.join("media/cat picture.png");
+        std::fs::write(&file, b"meow").unwrap();
+
+        assert_eq!(
+            local_file(Some(&export_directory), "media/cat%20picture.png"),
+            Some(file)
+        );
+        assert_eq!(
+            local_file(Some(&export_directory), "media/missing.png"),
+            None
+        );
+        assert_eq!(local_file(Some(&export_directory), "media"), None);
+        assert_eq!(
+            local_file(Some(&export_directory), "https://cdn.example.com/a.png"),
+            None
+        );
+    }
+
+    #[test]
+    fn refuses_files_outside_the_export() {
+        let directory = tempfile::tempdir().unwrap();
+        let parent = std::fs::canonicalize(directory.path()).unwrap();
+        let export_directory = parent.join("export");
+        std::fs::create_dir(&export_directory).unwrap();
+        let secret = parent.join("secret.txt");
+        std::fs::write(&secret, b"hunter2").unwrap();
+
+        let absolute = secret.to_str().unwrap();
+        for url in ["../secret.txt", "media/../../secret.txt", absolute] {
+            assert_eq!(local_file(Some(&export_directory), url), None, "{url}");
+        }
+    }
+}
@@ -0,0 +1,466 @@
+//! Bringing chat history over from other chat services.
+//!
+//! Each supported service has a reader that turns its export format into a
+//! [`ForeignExport`], which [`import`] then writes into rooms. Nobody gets
+//! notified about imported messages, and importing the same export twice
+//! creates its rooms twice.
+//!
+//! Imports aren't a single transaction, as that would keep everyone else from
+//! chatting for as long as it runs. Instead, a failed import removes the
+//! rooms, messages and attachments it has written so far.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use axum::body::Bytes;
+use chrono::NaiveDateTime;
+use tracing::instrument;
+use zip::ZipArchive;
+
+use crate::repository::Repository;
+use crate::repository::room::{ImportedMessage, MemberRole, Room};
+use crate::repository::upload::{FileUploadError, Upload};
+
+pub mod discord;
+pub mod slack;
+
+/// The largest attachment that is imported, the same as the largest upload
+/// the server accepts.
+const MAX_ATTACHMENT_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("Failed to parse {file}: {source}")]
+    Json {
+        file: String,
+        source: serde_json::Error,
+    },
+
+    #[error("Unexpected export layout: {0}")]
+    Layout(String),
+
+    #[error("Account '{0}' doesn't exist")]
+    UnknownAccount(String),
+
+    #[error("Account '{0}' belongs to someone, map the user to it explicitly instead")]
+    NotAPlaceholder(String),
+
+    #[error("Room {0} doesn't exist")]
+    UnknownRoom(i64),
+
+    #[error("Only a single channel can be imported into an existing room, found {0}")]
+    TooManyChannels(usize),
+
+    #[error("Attachment {0} is larger than an upload may be")]
+    AttachmentTooLarge(String),
+
+    #[error(transparent)]
+    Upload(#[from] FileUploadError),
+
+    #[error("Internal database error")]
+    Database(#[from] sqlx::Error),
+}
+
+/// The service an export comes from. Placeholder accounts are named after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Slack,
+    Discord,
+}
+
+/// Everything read from an export, ready to be imported.
+#[derive(Debug)]
+#[must_use]
+pub struct ForeignExport {
+    pub platform: Platform,
+    /// Users by their ID on the foreign service.
+    pub users: HashMap<String, ForeignUser>,
+    pub channels: Vec<ForeignChannel>,
+    /// The zip archive the export came in, if any, for reading attachments.
+    pub archive: Option<ZipArchive<File>>,
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ForeignUser {
+    pub id: String,
+    pub name: String,
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ForeignChannel {
+    pub name: String,
+    pub topic: Option<String>,
+    /// Oldest first.
+    pub messages: Vec<ForeignMessage>,
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ForeignMessage {
+    pub id: String,
+    pub author_id: String,
+    pub text: Option<String>,
+    pub sent_at: NaiveDateTime,
+    /// The ID of the message this one replies to, within the same channel.
+    pub reply_to: Option<String>,
+    /// Whether this is a notice from the service, like someone joining.
+    pub is_system: bool,
+    pub attachments: Vec<ForeignAttachment>,
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ForeignAttachment {
+    pub filename: String,
+    /// Where the file is, if the export includes it at all.
+    pub location: Option<AttachmentLocation>,
+}
+
+#[derive(Debug, Clone)]
+pub enum AttachmentLocation {
+    /// An entry in the export's zip archive.
+    Archived(String),
+    /// A file next to the export.
+    File(PathBuf),
+}
+
+/// How the export's contents map onto this server.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ImportOptions {
+    /// Owns the rooms created for the imported channels.
+    pub owner: String,
+    /// Accounts to attribute a foreign user's messages to, by the user's ID or
+    /// name. Everyone else gets a placeholder account.
+    pub user_map: HashMap<String, String>,
+    /// An existing room to import a single channel into, instead of creating
+    /// a new one.
+    pub room_id: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+#[must_use]
+pub struct ImportReport {
+    pub rooms: usize,
+    pub messages: usize,
+    pub attachments: usize,
+    pub missing_attachments: usize,
+    pub placeholders: usize,
+}
+
+impl ForeignExport {
+    fn read_attachment(&mut self, location: &AttachmentLocation) -> Result<Vec<u8>, ImportError> {
+        let mut data = vec![];
+        // NOTE: One more byte than allowed is read to tell whether there is more.
+        let limit = MAX_ATTACHMENT_BYTES + 1;
+        let name = match location {
+            AttachmentLocation::Archived(name) => {
+                let archive = self.archive.as_mut().ok_or_else(|| {
+                    ImportError::Layout(format!("no archive to read {name} from"))
+                })?;
+                archive.by_name(name)?.take(limit).read_to_end(&mut data)?;
+                name.clone()
+            }
+            AttachmentLocation::File(path) => {
+                File::open(path)?.take(limit).read_to_end(&mut data)?;
+                path.display().to_string()
+            }
+        };
+        if data.len() as u64 > MAX_ATTACHMENT_BYTES {
+            return Err(ImportError::AttachmentTooLarge(name));
+        }
+        Ok(data)
+    }
+
+    /// The user with the ID, or a stand-in named after the ID if the export
+    /// doesn't list them.
+    fn author(&self, id: &str) -> ForeignUser {
+        self.users.get(id).cloned().unwrap_or_else(|| ForeignUser {
+            id: id.to_string(),
+            name: id.to_string(),
+            display_name: None,
+        })
+    }
+
+    /// Reads the message's attachments. Those that can't be read are noted in
+    /// the returned text instead.
+    fn read_attachments<'m>(
+        &mut self,
+        message: &'m ForeignMessage,
+    ) -> (Option<String>, Vec<(&'m str, Vec<u8>)>) {
+        let mut text = message.text.clone();
+        let mut files = vec![];
+        for attachment in &message.attachments {
+            let data = attachment.location.as_ref().and_then(|location| {
+                self.read_attachment(location)
+                    .inspect_err(|error| {
+                        tracing::warn!(?error, attachment.filename, "Failed to read attachment");
+                    })
+                    .ok()
+            });
+            if let Some(data) = data {
+                files.push((attachment.filename.as_str(), data));
+            } else {
+                let note = format!("[attachment not included: {}]", attachment.filename);
+                text = Some(text.map_or(note.clone(), |text| format!("{text}\n{note}")));
+            }
+        }
+        (text, files)
+    }
+}
+
+/// Decides which account each foreign user's messages go to.
+struct AccountMapper<'a> {
+    repository: &'a Repository,
+    platform: Platform,
+    user_map: &'a HashMap<String, String>,
+    resolved: HashMap<String, (String, bool)>,
+    placeholders: usize,
+}
+
+impl AccountMapper<'_> {
+    /// The account to attribute the user's messages to, and whether it belongs
+    /// to an actual person (rather than being a placeholder).
+    async fn resolve(&mut self, user: &ForeignUser) -> Result<(String, bool), ImportError> {
+        if let Some(resolved) = self.resolved.get(&user.id) {
+            return Ok(resolved.clone());
+        }
+
+        let mapped = self
+            .user_map
+            .get(&user.id)
+            .or_else(|| self.user_map.get(&user.name));
+        let resolved = if let Some(username) = mapped {
+            (username.clone(), true)
+        } else {
+            let prefix = match self.platform {
+                Platform::Slack => "slack",
+                Platform::Discord => "discord",
+            };
+            let username = format!("{prefix}-{}", user.id);
+            let display_name = user.display_name.as_deref().unwrap_or(&user.name);
+            let created = self
+                .repository
+                .accounts
+                .create_placeholder(&username, Some(display_name))
+                .await?;
+            // NOTE: Anyone can register `slack-...`, so an existing account
+            //       is only reused if it's one of ours.
+            if !created {
+                let existing = self.repository.accounts.find(&username).await?;
+                if !existing.is_some_and(|account| account.is_placeholder) {
+                    return Err(ImportError::NotAPlaceholder(username));
+                }
+            }
+            self.placeholders += usize::from(created);
+            (username, false)
+        };
+
+        self.resolved.insert(user.id.clone(), resolved.clone());
+        Ok(resolved)
+    }
+}
+
+/// Writes the export's channels into rooms, see the [module docs](self).
+#[instrument(skip_all, fields(platform = ?export.platform, channels = export.channels.len()), err(Debug))]
+pub async fn import(
+    repository: &Repository,
+    connection: &sqlx::SqlitePool,
+    mut export: ForeignExport,
+    options: &ImportOptions,
+) -> Result<ImportReport, ImportError> {
+    for username in options.user_map.values().chain([&options.owner]) {
+        if repository.accounts.find(username).await?.is_none() {
+            return Err(ImportError::UnknownAccount(username.clone()));
+        }
+    }
+    if options.room_id.is_some() && export.channels.len() != 1 {
+        return Err(ImportError::TooManyChannels(export.channels.len()));
+    }
+
+    let mut written = Written::default();
+    let result = import_channels(repository, connection, &mut export, options, &mut written).await;
+    if result.is_err() {
+        written.undo(repository, connection).await;
+    }
+    result
+}
+
+/// Does the actual work for [`import`], noting down everything it writes.
+async fn import_channels(
+    repository: &Repository,
+    connection: &sqlx::SqlitePool,
+    export: &mut ForeignExport,
+    options: &ImportOptions,
+    written: &mut Written,
+) -> Result<ImportReport, ImportError> {
+    let mut report = ImportReport::default();
+    let mut mapper = AccountMapper {
+        repository,
+        platform: export.platform,
+        user_map: &options.user_map,
+        resolved: HashMap::new(),
+        placeholders: 0,
+    };
+
+    for channel in std::mem::take(&mut export.channels) {
+        let room = match options.room_id {
+            Some(room_id) => repository
+                .rooms
+                .find_by_id(room_id)
+                .await?
+                .ok_or(ImportError::UnknownRoom(room_id))?,
+            None => {
+                report.rooms += 1;
+                create_room(repository, connection, &channel, &options.owner, written).await?
+            }
+        };
+        tracing::info!(room.id, channel = channel.name, "Importing channel");
+
+        let mut imported_ids: HashMap<String, i64> = HashMap::new();
+        for message in channel.messages {
+            let author = export.author(&message.author_id);
+            let (sender, is_person) = mapper.resolve(&author).await?;
+            if is_person && !room.has_member(connection, &sender).await? {
+                room.add_member(connection, &sender).await?;
+                written.members.push((room.clone(), sender.clone()));
+            }
+
+            let reply_to = message
+                .reply_to
+                .as_ref()
+                .and_then(|id| imported_ids.get(id).copied());
+            let (text, files) = export.read_attachments(&message);
+            report.missing_attachments += message.attachments.len() - files.len();
+
+            let mut first_id = None;
+            if text.is_some() || files.is_empty() {
+                let imported = room
+                    .import_message(
+                        connection,
+                        ImportedMessage {
+                            sender: &sender,
+                            text: text.as_deref(),
+                            sent_at: message.sent_at,
+                            reply_to,
+                            file_upload_uuid: None,
+                            is_system: message.is_system,
+                        },
+                    )
+                    .await?;
+                written.messages.push(imported.id);
+                first_id = Some(imported.id);
+                report.messages += 1;
+            }
+            for (filename, data) in files {
+                let upload = room
+                    .upload(connection, &sender, Path::new(filename), &Bytes::from(data))
+                    .await?;
+                let uuid = upload.uuid.clone();
+                written.uploads.push(upload);
+                let imported = room
+                    .import_message(
+                        connection,
+                        ImportedMessage {
+                            sender: &sender,
+                            text: None,
+                            sent_at: message.sent_at,
+                            reply_to,
+                            file_upload_uuid: Some(&uuid),
+                            is_system: false,
+                        },
+                    )
+                    .await?;
+                written.messages.push(imported.id);
+                first_id.get_or_insert(imported.id);
+                report.messages += 1;
+                report.attachments += 1;
+            }
+            if let Some(first_id) = first_id {
+                imported_ids.insert(message.id, first_id);
+            }
+        }
+    }
+
+    report.placeholders = mapper.placeholders;
+    Ok(report)
+}
+
+/// Creates a room for the channel, owned by `owner`.
+async fn create_room(
+    repository: &Repository,
+    connection: &sqlx::SqlitePool,
+    channel: &ForeignChannel,
+    owner: &str,
+    written: &mut Written,
+) -> Result<Room, ImportError> {
+    let room = repository.rooms.create(&channel.name).await?;
+    written.rooms.push(room.id);
+    room.add_member(connection, owner).await?;
+    room.set_role(connection, owner, MemberRole::Owner).await?;
+    room.set_topic(connection, channel.topic.as_deref()).await?;
+    Ok(room)
+}
+
+/// What an import has written so far, so that a failed one can be undone.
+#[derive(Debug, Default)]
+struct Written {
+    rooms: Vec<i64>,
+    members: Vec<(Room, String)>,
+    messages: Vec<i64>,
+    uploads: Vec<Upload>,
+}
+
+impl Written {
+    /// Removes everything again, newest first. Placeholder accounts are kept,
+    /// the next import reuses them.
+    ///
+    /// Failures aren't returned, as the import has failed already, but the
+    /// repository logs each of them.
+    async fn undo(self, repository: &Repository, connection: &sqlx::SqlitePool) {
+        tracing::warn!(
+            rooms = self.rooms.len(),
+            messages = self.messages.len(),
+            attachments = self.uploads.len(),
+            "Import failed, removing what it has written so far"
+        );
+        let mut failures = 0;
+        for upload in self.uploads.iter().rev() {
+            // NOTE: Removing the upload removes its message as well.
+            let removed = repository.uploads.delete(&upload.uuid).await.is_ok()
+                && upload.remove_files().await.is_ok();
+            failures += usize::from(!removed);
+        }
+        for message_id in self.messages.into_iter().rev() {
+            failures += usize::from(repository.messages.delete(message_id).await.is_err());
+        }
+        for (room, member) in self.members.iter().rev() {
+            failures += usize::from(room.remove_member(connection, member).await.is_err());
+        }
+        for room_id in self.rooms.into_iter().rev() {
+            failures += usize::from(repository.rooms.delete(room_id).await.is_err());
+        }
+        if failures > 0 {
+            tracing::error!(failures, "Failed to remove part of the import, see above");
+        }
+    }
+}
+
+/// Parses a JSON file from an export, naming the file if that fails.
+fn parse_json<T: serde::de::DeserializeOwned>(file: &str, data: &[u8]) -> Result<T, ImportError> {
+    serde_json::from_slice(data).map_err(|source| ImportError::Json {
+        file: file.to_string(),
+        source,
+    })
+}
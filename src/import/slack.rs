@@ -0,0 +1,377 @@
+//! Reads the zip archives made by Slack's workspace export.
+//!
+//! The archive holds `users.json`, `channels.json` (and `groups.json` for
+//! private channels) and a directory per channel with a JSON file of messages
+//! per day. Slack itself only links to uploaded files, but some export tools
+//! include them under `__uploads/<file id>/<name>`, which is where they are
+//! picked up from.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime};
+use serde::Deserialize;
+use zip::ZipArchive;
+
+use super::{
+    AttachmentLocation, ForeignAttachment, ForeignChannel, ForeignExport, ForeignMessage,
+    ForeignUser, ImportError, Platform,
+};
+
+/// Message subtypes that record something happening in the channel, rather
+/// than someone saying something.
+const SYSTEM_SUBTYPES: &[&str] = &[
+    "channel_join",
+    "channel_leave",
+    "channel_topic",
+    "channel_purpose",
+    "channel_name",
+    "channel_archive",
+    "channel_unarchive",
+    "group_join",
+    "group_leave",
+    "group_topic",
+    "group_purpose",
+    "group_name",
+];
+
+#[derive(Deserialize, Debug)]
+struct User {
+    id: String,
+    name: String,
+    #[serde(default)]
+    real_name: Option<String>,
+    #[serde(default)]
+    profile: Option<Profile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Profile {
+    #[serde(default)]
+    display_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Channel {
+    name: String,
+    #[serde(default)]
+    topic: Option<Topic>,
+    #[serde(default)]
+    purpose: Option<Topic>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Topic {
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    #[serde(default)]
+    subtype: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    bot_id: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    #[serde(default)]
+    thread_ts: Option<String>,
+    #[serde(default)]
+    files: Vec<SlackFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SlackFile {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// Reads the export at `path`, optionally limited to the named channels.
+pub fn read(path: &Path, only_channels: &[String]) -> Result<ForeignExport, ImportError> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let file_names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    let archived_files: HashSet<&str> = file_names.iter().map(String::as_str).collect();
+
+    let mut users = self::read_users(&mut archive)?;
+    let user_names: HashMap<String, String> = users
+        .values()
+        .map(|user| {
+            let name = user
+                .display_name
+                .clone()
+                .unwrap_or_else(|| user.name.clone());
+            (user.id.clone(), name)
+        })
+        .collect();
+
+    let mut channels: Vec<Channel> = self::read_json(&mut archive, "channels.json")?;
+    if archived_files.contains("groups.json") {
+        channels.extend(self::read_json::<Vec<Channel>>(
+            &mut archive,
+            "groups.json",
+        )?);
+    }
+    if !only_channels.is_empty() {
+        channels.retain(|channel| only_channels.contains(&channel.name));
+    }
+
+    let mut foreign_channels = vec![];
+    for channel in channels {
+        let mut days: Vec<&String> = file_names
+            .iter()
+            .filter(|name| {
+                name.strip_prefix(&channel.name)
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .is_some_and(|day| {
+                        !day.contains('/')
+                            && Path::new(day)
+                                .extension()
+                                .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+                    })
+            })
+            .collect();
+        days.sort();
+
+        let mut messages = vec![];
+        for day in days {
+            let day_messages: Vec<Message> = self::read_json(&mut archive, day)?;
+            for mut message in day_messages {
+                let author_id = message
+                    .user
+                    .take()
+                    .or_else(|| message.bot_id.take())
+                    .unwrap_or_else(|| "unknown".to_string());
+                if let Some(username) = message
+                    .username
+                    .take()
+                    .filter(|_| !users.contains_key(&author_id))
+                {
+                    users.insert(
+                        author_id.clone(),
+                        ForeignUser {
+                            id: author_id.clone(),
+                            name: username,
+                            display_name: None,
+                        },
+                    );
+                }
+                messages.push(self::convert_message(
+                    message,
+                    author_id,
+                    &archived_files,
+                    &user_names,
+                )?);
+            }
+        }
+        messages.sort_by_key(|message| message.sent_at);
+
+        let topic = channel
+            .topic
+            .filter(|topic| !topic.value.is_empty())
+            .or(channel.purpose)
+            .map(|topic| topic.value)
+            .filter(|topic| !topic.is_empty());
+        foreign_channels.push(ForeignChannel {
+            name: channel.name,
+            topic,
+            messages,
+        });
+    }
+
+    Ok(ForeignExport {
+        platform: Platform::Slack,
+        users,
+        channels: foreign_channels,
+        archive: Some(archive),
+    })
+}
+
+fn read_users(archive: &mut ZipArchive<File>) -> Result<HashMap<String, ForeignUser>, ImportError> {
+    let users: Vec<User> = self::read_json(archive, "users.json")?;
+    Ok(users
+        .into_iter()
+        .map(|user| {
+            let display_name = user
+                .profile
+                .and_then(|profile| profile.display_name)
+                .filter(|name| !name.is_empty())
+                .or(user.real_name)
+                .filter(|name| !name.is_empty());
+            let foreign_user = ForeignUser {
+                id: user.id.clone(),
+                name: user.name,
+                display_name,
+            };
+            (user.id, foreign_user)
+        })
+        .collect())
+}
+
+fn convert_message(
+    message: Message,
+    author_id: String,
+    archived_files: &HashSet<&str>,
+    user_names: &HashMap<String, String>,
+) -> Result<ForeignMessage, ImportError> {
+    let is_system = message
+        .subtype
+        .as_deref()
+        .is_some_and(|subtype| SYSTEM_SUBTYPES.contains(&subtype));
+    let attachments = message
+        .files
+        .into_iter()
+        .filter_map(|file| {
+            let filename = file.name?;
+            let archived = file
+                .id
+                .map(|id| format!("__uploads/{id}/{filename}"))
+                .filter(|archived| archived_files.contains(archived.as_str()));
+            Some(ForeignAttachment {
+                filename,
+                location: archived.map(AttachmentLocation::Archived),
+            })
+        })
+        .collect();
+    let text = self::convert_markup(&message.text, user_names);
+
+    Ok(ForeignMessage {
+        sent_at: self::parse_timestamp(&message.ts)?,
+        reply_to: message
+            .thread_ts
+            .filter(|thread_ts| *thread_ts != message.ts),
+        id: message.ts,
+        author_id,
+        text: (!text.is_empty()).then_some(text),
+        is_system,
+        attachments,
+    })
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(
+    archive: &mut ZipArchive<File>,
+    name: &str,
+) -> Result<T, ImportError> {
+    let mut data = vec![];
+    archive
+        .by_name(name)
+        .map_err(|_| ImportError::Layout(format!("{name} is missing")))?
+        .read_to_end(&mut data)?;
+    super::parse_json(name, &data)
+}
+
+/// Slack timestamps are seconds since the epoch, with microseconds after the
+/// dot (that also make them unique within a channel).
+fn parse_timestamp(ts: &str) -> Result<NaiveDateTime, ImportError> {
+    let invalid = || ImportError::Layout(format!("invalid timestamp {ts}"));
+    let (seconds, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    let seconds = seconds.parse().map_err(|_| invalid())?;
+    let micros: u32 = micros.parse().map_err(|_| invalid())?;
+    DateTime::from_timestamp(seconds, micros.saturating_mul(1000))
+        .map(|time| time.naive_utc())
+        .ok_or_else(invalid)
+}
+
+/// Turns Slack's markup into the Markdown used here. Slack wraps mentions
+/// and links in angle brackets, like `<@U123>`, `<#C123|general>` or
+/// `<https://example.com|label>`, and escapes literal ones as HTML entities.
+fn convert_markup(text: &str, user_names: &HashMap<String, String>) -> String {
+    let mut converted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let Some(length) = rest[start..].find('>') else {
+            break;
+        };
+        converted.push_str(&rest[..start]);
+        let markup = &rest[start + 1..start + length];
+        let (target, label) = match markup.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (markup, None),
+        };
+        let replacement = match (target.chars().next(), label) {
+            (Some('@'), _) => {
+                let id = &target[1..];
+                format!("@{}", user_names.get(id).map_or(id, String::as_str))
+            }
+            (Some('#'), Some(label)) => format!("#{label}"),
+            (Some('!'), _) => format!("@{}", label.unwrap_or_else(|| &target[1..])),
+            (_, Some(label)) => format!("[{label}]({target})"),
+            (_, None) => target.to_string(),
+        };
+        converted.push_str(&replacement);
+        rest = &rest[start + length + 1..];
+    }
+    converted.push_str(rest);
+
+    converted
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        let time = parse_timestamp("1700000000.123456").unwrap();
+        assert_eq!(time.to_string(), "2023-11-14 22:13:20.123456");
+        let time = parse_timestamp("1700000000").unwrap();
+        assert_eq!(time.to_string(), "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        for ts in [
+            "",
+            "yesterday",
+            "1700000000.",
+            "1700000000.12x",
+            "99999999999999999",
+        ] {
+            assert!(
+                matches!(parse_timestamp(ts), Err(ImportError::Layout(_))),
+                "{ts}"
+            );
+        }
+    }
+
+    #[test]
+    fn converts_mentions_and_links() {
+        let user_names = HashMap::from([("U123".to_string(), "alice".to_string())]);
+        let convert = |text| convert_markup(text, &user_names);
+
+        assert_eq!(convert("hi <@U123>"), "hi @alice");
+        assert_eq!(convert("hi <@U999>"), "hi @U999");
+        assert_eq!(convert("see <#C123|general>"), "see #general");
+        assert_eq!(convert("<!here> <!subteam^S1|devs>"), "@here @devs");
+        assert_eq!(
+            convert("<https://example.com|the site> or <https://example.org>"),
+            "[the site](https://example.com) or https://example.org"
+        );
+    }
+
+    #[test]
+    fn unescapes_entities_after_converting() {
+        let user_names = HashMap::new();
+        assert_eq!(
+            convert_markup(
+                "a &lt;b&gt; &amp;lt; <https://x.test?a=1&amp;b=2>",
+                &user_names
+            ),
+            "a <b> &lt; https://x.test?a=1&b=2"
+        );
+        assert_eq!(
+            convert_markup("unclosed < bracket", &user_names),
+            "unclosed < bracket"
+        );
+    }
+}
@@ -1,28 +1,35 @@
 #![allow(clippy::missing_errors_doc)]
 
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 use axum::Router;
 use axum::extract::DefaultBodyLimit;
 use axum::middleware::from_extractor_with_state;
 use axum::response::Redirect;
 use axum::routing::{any, get, post};
-use clap::Parser;
-use repository::Repository;
+use clap::{Parser, ValueEnum};
+use ipnet::IpNet;
 use sqlx::SqlitePool;
 use tokio::net::TcpListener;
-use tokio::sync::broadcast;
 use tracing::instrument;
 
 use crate::state::SharedState;
 
 const GIGABYTE: usize = 1024 * 1024 * 1024;
 
+pub mod archive;
 pub mod auth;
+pub mod commands;
 pub mod endpoints;
+pub mod import;
 pub mod layers;
+pub mod markdown;
+pub mod presence;
 pub mod repository;
+pub mod room_actions;
 pub mod state;
+pub mod workers;
 
 #[derive(Parser, Clone, Debug)]
 #[must_use]
@@ -35,33 +42,171 @@ pub struct Settings {
 
     #[arg(long, default_value_t = 256)]
     pub broadcast_channel_capacity: usize,
+
+    #[arg(long, default_value_t = 5 * GIGABYTE as u64)]
+    pub account_storage_quota: u64,
+
+    #[arg(long, default_value_t = 20 * GIGABYTE as u64)]
+    pub room_storage_quota: u64,
+
+    #[arg(long, default_value_t = 60 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub upload_gc_interval_secs: u64,
+
+    /// Networks that link previews may be fetched from even though they are
+    /// not public, e.g. `127.0.0.1/32` for a local stand-in server.
+    #[arg(long, value_delimiter = ',')]
+    pub link_preview_allowed_networks: Vec<IpNet>,
+
+    /// Networks that outgoing webhooks may deliver to even though they are
+    /// not public, e.g. `127.0.0.1/32` for a local receiver.
+    #[arg(long, value_delimiter = ',')]
+    pub webhook_allowed_networks: Vec<IpNet>,
+
+    /// How long to wait before retrying a failed webhook delivery. The wait
+    /// doubles with every further attempt.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub webhook_retry_base_secs: u64,
+
+    #[arg(long, value_enum, default_value_t = RegistrationPolicy::Open)]
+    pub registration_policy: RegistrationPolicy,
+
+    /// Accounts allowed to perform administrative tasks, such as minting
+    /// signup codes.
+    #[arg(long = "admin", value_delimiter = ',')]
+    pub admins: Vec<String>,
+}
+
+/// Who may create a new account.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[must_use]
+pub enum RegistrationPolicy {
+    /// Anyone.
+    Open,
+    /// Only people holding a signup code minted by an admin.
+    InviteCode,
+    /// Nobody.
+    Closed,
+}
+
+impl Settings {
+    #[must_use]
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|admin| admin == username)
+    }
 }
 
 #[instrument]
 pub async fn run(settings: Settings) -> Result<(), color_eyre::eyre::Report> {
     let db_pool = SqlitePool::connect(&settings.database_url).await?;
-    let (broadcast_tx, _) = broadcast::channel(settings.broadcast_channel_capacity);
-    let state = SharedState {
-        repository: Repository::new(db_pool.clone()),
-        db_pool,
-        broadcast_tx,
-    };
+    let state = SharedState::new(db_pool, settings.clone());
+
+    let upload_gc_interval = Duration::from_secs(settings.upload_gc_interval_secs);
+    workers::upload_gc::spawn(state.clone(), upload_gc_interval);
+    workers::link_preview::spawn(state.clone());
+    workers::webhook_delivery::spawn(state.clone());
 
     let upload_router = Router::new()
         .route("/upload", post(endpoints::upload::upload_handler))
         .route("/upload/{uuid}", get(endpoints::upload::download_handler))
+        .route(
+            "/api/account/avatar",
+            post(endpoints::upload::avatar_handler),
+        )
+        .route(
+            "/upload/{uuid}/thumbnail",
+            get(endpoints::upload::thumbnail_handler),
+        )
         .layer(DefaultBodyLimit::max(GIGABYTE));
 
     let room_api_router = Router::new()
         .route("/create", post(endpoints::rooms::create))
         .route("/invite", post(endpoints::rooms::invite))
         .route("/kick", post(endpoints::rooms::kick_out))
+        .route("/retention", post(endpoints::rooms::set_retention))
+        .route("/role", post(endpoints::rooms::set_role))
+        .route("/rename", post(endpoints::rooms::rename))
+        .route("/topic", post(endpoints::rooms::set_topic))
+        .route("/description", post(endpoints::rooms::set_description))
+        .route("/archive", post(endpoints::rooms::set_archived))
+        .route("/visibility", post(endpoints::rooms::set_visibility))
+        .route("/invite-codes", post(endpoints::invites::create))
+        .route("/invite-codes/revoke", post(endpoints::invites::revoke))
+        .route("/{room_id}/invite-codes", get(endpoints::invites::list))
+        .route("/webhooks", post(endpoints::webhooks::create))
+        .route("/webhooks/delete", post(endpoints::webhooks::delete))
+        .route("/{room_id}/webhooks", get(endpoints::webhooks::list))
+        .route(
+            "/outgoing-webhooks",
+            post(endpoints::webhooks::create_outgoing),
+        )
+        .route(
+            "/outgoing-webhooks/delete",
+            post(endpoints::webhooks::delete_outgoing),
+        )
+        .route(
+            "/{room_id}/outgoing-webhooks",
+            get(endpoints::webhooks::list_outgoing),
+        )
+        .route(
+            "/{room_id}/outgoing-webhooks/dead-letters",
+            get(endpoints::webhooks::dead_letters),
+        )
+        .route("/{room_id}/join", post(endpoints::rooms::join))
+        .route("/{room_id}/leave", post(endpoints::rooms::leave))
+        .route("/delete", post(endpoints::rooms::delete))
+        .route("/{room_id}/pins", get(endpoints::rooms::pins))
+        .route("/{room_id}/messages", get(endpoints::rooms::messages))
+        .route("/{room_id}/export", get(endpoints::export::room))
+        .route("/{room_id}/members", get(endpoints::rooms::members))
         .route("/list", get(endpoints::rooms::list));
 
+    let message_api_router = Router::new()
+        .route("/react", post(endpoints::messages::react))
+        .route("/unreact", post(endpoints::messages::unreact))
+        .route("/pin", post(endpoints::messages::pin))
+        .route("/unpin", post(endpoints::messages::unpin))
+        .route("/{message_id}/thread", get(endpoints::messages::thread))
+        .route("/{message_id}/read-by", get(endpoints::messages::read_by))
+        .route(
+            "/{message_id}/snippet/{index}",
+            get(endpoints::messages::snippet),
+        );
+
+    let account_api_router = Router::new()
+        .route("/inbox", get(endpoints::account::inbox))
+        .route("/inbox/read", post(endpoints::account::mark_read))
+        .route("/profile", post(endpoints::account::update_profile))
+        .route("/avatar/remove", post(endpoints::account::remove_avatar))
+        .route("/export", get(endpoints::account::export))
+        .route("/delete", post(endpoints::account::delete));
+
+    let admin_api_router = Router::new()
+        .route(
+            "/signup-codes",
+            get(endpoints::admin::list_signup_codes).post(endpoints::admin::create_signup_code),
+        )
+        .route(
+            "/bots",
+            get(endpoints::admin::list_bots).post(endpoints::admin::create_bot),
+        )
+        .route("/bots/token", post(endpoints::admin::reset_bot_token));
+
+    let storage_api_router = Router::new().route("/usage", get(endpoints::upload::storage_usage));
+
     let protected_router = Router::new()
         .merge(upload_router)
         .nest("/api/room/", room_api_router)
+        .route("/api/rooms/directory", get(endpoints::rooms::directory))
+        .nest("/api/message/", message_api_router)
+        .nest("/api/account/", account_api_router)
+        .route("/api/user/{username}", get(endpoints::account::profile))
+        .nest("/api/storage/", storage_api_router)
+        .nest("/api/admin/", admin_api_router)
         .route("/account/logout", post(endpoints::account::logout))
+        .route(
+            "/invite/{code}",
+            get(endpoints::invites::preview).post(endpoints::invites::accept),
+        )
         .route("/chat/{room_id}", get(endpoints::chat::page))
         .route("/chat/{room_id}/websocket", any(endpoints::chat::websocket))
         .route_layer(from_extractor_with_state::<auth::Session, _>(state.clone()));
@@ -70,7 +215,9 @@ pub async fn run(settings: Settings) -> Result<(), color_eyre::eyre::Report> {
         .merge(protected_router)
         .route("/", get(|| async { Redirect::to("/chat/1") }))
         .route("/account", get(endpoints::account::page))
+        .route("/highlight.css", get(endpoints::chat::highlight_stylesheet))
         .route("/account/form/submit", post(endpoints::account::submit))
+        .route("/hooks/{token}", post(endpoints::webhooks::receive))
         .layer(layers::trace_layer())
         .with_state(state);
 
@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+use ammonia::UrlRelative;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd};
+use syntect::highlighting::ThemeSet;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator, css_for_theme_with_class_style};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// Every class emitted by the highlighter carries this prefix, and the
+/// sanitizer drops any class that doesn't.
+const CLASS_PREFIX: &str = "hl-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed {
+    prefix: CLASS_PREFIX,
+};
+const HIGHLIGHT_THEME: &str = "base16-ocean.dark";
+
+/// Code blocks larger than this are shown without highlighting, as the
+/// highlighter gets rather slow on huge inputs.
+const MAX_HIGHLIGHTED_BYTES: usize = 64 * 1024;
+
+static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// The final word on what ends up in a message's HTML. Whatever the renderer
+/// produces, only these tags, attributes and URL schemes survive.
+static SANITIZER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut builder = ammonia::Builder::empty();
+    builder
+        .tags(HashSet::from([
+            "p",
+            "br",
+            "strong",
+            "em",
+            "code",
+            "pre",
+            "span",
+            "a",
+            "blockquote",
+        ]))
+        .tag_attributes(HashMap::from([
+            ("a", HashSet::from(["href"])),
+            ("pre", HashSet::from(["class"])),
+            ("span", HashSet::from(["class"])),
+        ]))
+        .attribute_filter(|_element, attribute, value| {
+            if attribute != "class" {
+                return Some(value.into());
+            }
+            let classes = value
+                .split_whitespace()
+                .filter(|class| class.starts_with(CLASS_PREFIX))
+                .collect::<Vec<_>>();
+            (!classes.is_empty()).then(|| classes.join(" ").into())
+        })
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .url_relative(UrlRelative::Deny)
+        .link_rel(Some("noopener noreferrer nofollow"));
+    builder
+});
+
+static STYLESHEET: LazyLock<String> = LazyLock::new(|| {
+    let themes = ThemeSet::load_defaults();
+    css_for_theme_with_class_style(&themes.themes[HIGHLIGHT_THEME], CLASS_STYLE).unwrap_or_default()
+});
+
+/// A fenced or indented code block, as written in a message's source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use]
+pub struct CodeBlock {
+    pub language: Option<String>,
+    pub code: String,
+}
+
+impl CodeBlock {
+    /// A file extension fitting the block's language, for downloads.
+    #[must_use]
+    pub fn file_extension(&self) -> &str {
+        self.language
+            .as_deref()
+            .and_then(|token| SYNTAX_SET.find_syntax_by_token(token))
+            .and_then(|syntax| syntax.file_extensions.first())
+            .map_or("txt", String::as_str)
+    }
+
+    fn to_highlighted_html(&self) -> Option<String> {
+        if self.code.len() > MAX_HIGHLIGHTED_BYTES {
+            return None;
+        }
+        let syntax = SYNTAX_SET.find_syntax_by_token(self.language.as_deref()?)?;
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAX_SET, CLASS_STYLE);
+        for line in LinesWithEndings::from(&self.code) {
+            generator
+                .parse_html_for_line_which_includes_newline(line)
+                .ok()?;
+        }
+        Some(format!(
+            "<pre class=\"{CLASS_PREFIX}code\"><code>{}</code></pre>\n",
+            generator.finalize()
+        ))
+    }
+}
+
+/// Renders the Markdown subset supported in messages into sanitized HTML:
+/// bold, italics, code spans, code blocks, links and quotes.
+///
+/// Raw HTML in the source is shown as text, line breaks are kept as they
+/// are, and anything else (headings, lists, images, ...) is reduced to its
+/// text content. Code blocks tagged with a known language are highlighted
+/// with classes from [`stylesheet`].
+#[must_use]
+pub fn render(source: &str) -> String {
+    let mut events = Vec::new();
+    let mut open_block: Option<CodeBlock> = None;
+
+    for event in Parser::new_ext(source, Options::empty()) {
+        match (event, &mut open_block) {
+            (Event::Start(Tag::CodeBlock(kind)), None) => {
+                open_block = Some(CodeBlock {
+                    language: block_language(&kind),
+                    code: String::new(),
+                });
+            }
+            (Event::Text(text), Some(block)) => block.code.push_str(&text),
+            (Event::End(TagEnd::CodeBlock), Some(_)) => {
+                let Some(block) = open_block.take() else {
+                    continue;
+                };
+                match block.to_highlighted_html() {
+                    Some(html) => events.push(Event::Html(html.into())),
+                    None => events.extend(plain_code_block(block)),
+                }
+            }
+            // NOTE: Images would be rendered with their text as an attribute of
+            // an `img`, which the sanitizer drops altogether.
+            (_, Some(_)) | (Event::Start(Tag::Image { .. }) | Event::End(TagEnd::Image), None) => {}
+            (Event::Html(html) | Event::InlineHtml(html), None) => events.push(Event::Text(html)),
+            (Event::SoftBreak, None) => events.push(Event::HardBreak),
+            (Event::Start(Tag::Heading { .. }), None) => events.push(Event::Start(Tag::Paragraph)),
+            (Event::End(TagEnd::Heading(_)), None) => events.push(Event::End(TagEnd::Paragraph)),
+            (other, None) => events.push(other),
+        }
+    }
+
+    let mut unsafe_html = String::with_capacity(source.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut unsafe_html, events.into_iter());
+    SANITIZER.clean(&unsafe_html).to_string()
+}
+
+/// Escapes the characters that have a meaning inline, so that `text` shows
+/// up as it is when rendered, e.g. when a command puts it in a message.
+#[must_use]
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '&') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Extracts the code blocks from a message's source, in order of appearance.
+#[must_use]
+pub fn code_blocks(source: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut open_block: Option<CodeBlock> = None;
+
+    for event in Parser::new_ext(source, Options::empty()) {
+        match (event, &mut open_block) {
+            (Event::Start(Tag::CodeBlock(kind)), None) => {
+                open_block = Some(CodeBlock {
+                    language: block_language(&kind),
+                    code: String::new(),
+                });
+            }
+            (Event::Text(text), Some(block)) => block.code.push_str(&text),
+            (Event::End(TagEnd::CodeBlock), Some(_)) => blocks.extend(open_block.take()),
+            _ => {}
+        }
+    }
+
+    blocks
+}
+
+/// CSS for the classes used by highlighted code blocks.
+#[must_use]
+pub fn stylesheet() -> &'static str {
+    &STYLESHEET
+}
+
+fn block_language(kind: &CodeBlockKind<'_>) -> Option<String> {
+    match kind {
+        CodeBlockKind::Fenced(info) => info.split_whitespace().next().map(str::to_lowercase),
+        CodeBlockKind::Indented => None,
+    }
+}
+
+fn plain_code_block(block: CodeBlock) -> [Event<'static>; 3] {
+    [
+        Event::Start(Tag::CodeBlock(CodeBlockKind::Indented)),
+        Event::Text(CowStr::from(block.code)),
+        Event::End(TagEnd::CodeBlock),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_supported_subset() {
+        assert_eq!(
+            render("**bold** _italic_ `code`"),
+            "<p><strong>bold</strong> <em>italic</em> <code>code</code></p>\n"
+        );
+        assert_eq!(
+            render("> quoted"),
+            "<blockquote>\n<p>quoted</p>\n</blockquote>\n"
+        );
+        assert_eq!(render("one\ntwo"), "<p>one<br>\ntwo</p>\n");
+    }
+
+    #[test]
+    fn escaped_text_is_rendered_as_it_is() {
+        let text = r"¯\_(ツ)_/¯ *not bold* `not code` [not](a link) <b>&amp;";
+        assert_eq!(
+            render(&escape(text)),
+            "<p>¯\\_(ツ)_/¯ *not bold* `not code` [not](a link) &lt;b&gt;&amp;amp;</p>\n"
+        );
+    }
+
+    #[test]
+    fn raw_html_is_shown_as_text() {
+        assert_eq!(
+            render("<script>alert(1)</script>"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+        assert_eq!(
+            render("hi <img src=x onerror=alert(1)>"),
+            "<p>hi &lt;img src=x onerror=alert(1)&gt;</p>\n"
+        );
+    }
+
+    #[test]
+    fn links_keep_only_safe_schemes() {
+        assert_eq!(
+            render("[site](https://example.com)"),
+            "<p><a href=\"https://example.com\" rel=\"noopener noreferrer nofollow\">site</a></p>\n"
+        );
+        for source in [
+            "[x](javascript:alert(1))",
+            "[x](data:text/html,hi)",
+            "[x](/relative)",
+        ] {
+            assert_eq!(
+                render(source),
+                "<p><a rel=\"noopener noreferrer nofollow\">x</a></p>\n",
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn everything_else_is_reduced_to_text() {
+        assert_eq!(render("# Heading"), "<p>Heading</p>\n");
+        assert_eq!(render("![alt](https://example.com/a.png)"), "<p>alt</p>\n");
+        assert_eq!(render("- item"), "\nitem\n\n");
+    }
+
+    #[test]
+    fn code_blocks_are_extracted_in_order() {
+        let source =
+            "intro\n\n```Rust extra words\nfn main() {}\n```\n\n    indented\n\n```\nplain\n```";
+        assert_eq!(
+            code_blocks(source),
+            [
+                CodeBlock {
+                    language: Some("rust".to_string()),
+                    code: "fn main() {}\n".to_string(),
+                },
+                CodeBlock {
+                    language: None,
+                    code: "indented\n".to_string(),
+                },
+                CodeBlock {
+                    language: None,
+                    code: "plain\n".to_string(),
+                },
+            ]
+        );
+        assert_eq!(code_blocks("no `code` blocks here"), []);
+    }
+
+    #[test]
+    fn file_extensions_follow_the_language() {
+        let block = |language: Option<&str>| CodeBlock {
+            language: language.map(str::to_string),
+            code: String::new(),
+        };
+        assert_eq!(block(Some("rust")).file_extension(), "rs");
+        assert_eq!(block(Some("python")).file_extension(), "py");
+        assert_eq!(block(Some("no-such-language")).file_extension(), "txt");
+        assert_eq!(block(None).file_extension(), "txt");
+    }
+
+    #[test]
+    fn known_languages_are_highlighted_with_classes() {
+        let html = render("```rust\nlet x = 1;\n```");
+        assert!(
+            html.starts_with("<pre class=\"hl-code\"><code><span class=\"hl-source hl-rust\">"),
+            "{html}"
+        );
+        assert!(
+            html.contains("<span class=\"hl-storage hl-type hl-rust\">let</span>"),
+            "{html}"
+        );
+        assert!(!html.contains("style="), "{html}");
+        assert!(stylesheet().contains(".hl-comment"));
+    }
+
+    #[test]
+    fn other_code_blocks_are_left_plain() {
+        assert_eq!(
+            render("```no-such-language\n<b>x</b>\n```"),
+            "<pre><code>&lt;b&gt;x&lt;/b&gt;\n</code></pre>\n"
+        );
+        assert_eq!(render("    indented"), "<pre><code>indented</code></pre>\n");
+
+        let huge = format!(
+            "```rust\n{}\n```",
+            "let x = 1;\n".repeat(MAX_HIGHLIGHTED_BYTES)
+        );
+        let html = render(&huge);
+        assert!(
+            html.starts_with("<pre><code>let x = 1;\n"),
+            "{}",
+            &html[..64]
+        );
+        assert!(!html.contains("hl-"));
+    }
+}
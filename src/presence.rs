@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[must_use]
+pub struct MemberPresence {
+    pub username: String,
+    pub status: PresenceStatus,
+}
+
+/// Identifies a single websocket in the [`PresenceTracker`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SocketId(u64);
+
+/// Keeps track of the open websockets of every account, deriving a presence
+/// status from them. Nothing here is persisted: a restart makes everyone
+/// offline until they reconnect.
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct PresenceTracker {
+    next_socket_id: Arc<AtomicU64>,
+    /// Open sockets per account, along with whether each of them is away.
+    sockets: Arc<Mutex<HashMap<String, HashMap<SocketId, bool>>>>,
+}
+
+impl PresenceTracker {
+    #[must_use]
+    pub fn status(&self, username: &str) -> PresenceStatus {
+        Self::derive_status(self.lock().get(username))
+    }
+
+    /// Registers a new socket for `username`. Returns the socket's ID and the
+    /// account's new status, if it has changed.
+    #[must_use]
+    pub fn connect(&self, username: &str) -> (SocketId, Option<PresenceStatus>) {
+        let socket_id = SocketId(self.next_socket_id.fetch_add(1, Ordering::Relaxed));
+        let change = self.update(username, |account_sockets| {
+            account_sockets.insert(socket_id, false);
+        });
+        (socket_id, change)
+    }
+
+    /// Returns the account's new status, if it has changed.
+    #[must_use]
+    pub fn disconnect(&self, username: &str, socket_id: SocketId) -> Option<PresenceStatus> {
+        self.update(username, |account_sockets| {
+            account_sockets.remove(&socket_id);
+        })
+    }
+
+    /// Returns the account's new status, if it has changed.
+    #[must_use]
+    pub fn set_away(
+        &self,
+        username: &str,
+        socket_id: SocketId,
+        away: bool,
+    ) -> Option<PresenceStatus> {
+        self.update(username, |account_sockets| {
+            if let Some(socket_away) = account_sockets.get_mut(&socket_id) {
+                *socket_away = away;
+            }
+        })
+    }
+
+    fn update(
+        &self,
+        username: &str,
+        change: impl FnOnce(&mut HashMap<SocketId, bool>),
+    ) -> Option<PresenceStatus> {
+        let mut sockets = self.lock();
+        let old_status = Self::derive_status(sockets.get(username));
+
+        let account_sockets = sockets.entry(username.to_string()).or_default();
+        change(account_sockets);
+        if account_sockets.is_empty() {
+            sockets.remove(username);
+        }
+
+        let new_status = Self::derive_status(sockets.get(username));
+        drop(sockets);
+        (old_status != new_status).then_some(new_status)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, HashMap<SocketId, bool>>> {
+        // NOTE: The map is always left consistent, so a panic while holding the
+        // lock doesn't need to take presence tracking down with it.
+        self.sockets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn derive_status(account_sockets: Option<&HashMap<SocketId, bool>>) -> PresenceStatus {
+        match account_sockets {
+            None => PresenceStatus::Offline,
+            Some(sockets) if sockets.values().all(|&away| away) => PresenceStatus::Away,
+            Some(_) => PresenceStatus::Online,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connecting_and_disconnecting_changes_the_status() {
+        let presence = PresenceTracker::default();
+        assert_eq!(presence.status("alice"), PresenceStatus::Offline);
+
+        let (socket, change) = presence.connect("alice");
+        assert_eq!(change, Some(PresenceStatus::Online));
+        assert_eq!(presence.status("alice"), PresenceStatus::Online);
+        assert_eq!(presence.status("bob"), PresenceStatus::Offline);
+
+        assert_eq!(
+            presence.disconnect("alice", socket),
+            Some(PresenceStatus::Offline)
+        );
+        assert_eq!(presence.status("alice"), PresenceStatus::Offline);
+    }
+
+    #[test]
+    fn accounts_stay_online_while_any_socket_is_open() {
+        let presence = PresenceTracker::default();
+        let (first, _) = presence.connect("alice");
+        let (second, change) = presence.connect("alice");
+        assert_ne!(first, second);
+        assert_eq!(change, None);
+
+        assert_eq!(presence.disconnect("alice", first), None);
+        assert_eq!(presence.status("alice"), PresenceStatus::Online);
+        assert_eq!(
+            presence.disconnect("alice", second),
+            Some(PresenceStatus::Offline)
+        );
+    }
+
+    #[test]
+    fn accounts_are_away_once_every_socket_is() {
+        let presence = PresenceTracker::default();
+        let (first, _) = presence.connect("alice");
+        let (second, _) = presence.connect("alice");
+
+        assert_eq!(presence.set_away("alice", first, true), None);
+        assert_eq!(presence.status("alice"), PresenceStatus::Online);
+        assert_eq!(
+            presence.set_away("alice", second, true),
+            Some(PresenceStatus::Away)
+        );
+        assert_eq!(presence.set_away("alice", second, true), None);
+
+        // NOTE: A new socket starts out active, and so does the account.
+        let (third, change) = presence.connect("alice");
+        assert_eq!(change, Some(PresenceStatus::Online));
+        assert_eq!(
+            presence.disconnect("alice", third),
+            Some(PresenceStatus::Away)
+        );
+
+        assert_eq!(
+            presence.set_away("alice", first, false),
+            Some(PresenceStatus::Online)
+        );
+        assert_eq!(
+            presence.disconnect("alice", first),
+            Some(PresenceStatus::Away)
+        );
+        assert_eq!(
+            presence.disconnect("alice", second),
+            Some(PresenceStatus::Offline)
+        );
+    }
+
+    #[test]
+    fn unknown_sockets_are_ignored() {
+        let presence = PresenceTracker::default();
+        let (socket, _) = presence.connect("alice");
+        let (stranger, _) = presence.connect("bob");
+
+        assert_eq!(presence.set_away("alice", stranger, true), None);
+        assert_eq!(presence.disconnect("alice", stranger), None);
+        assert_eq!(presence.status("alice"), PresenceStatus::Online);
+        assert_eq!(presence.status("bob"), PresenceStatus::Online);
+
+        assert_eq!(
+            presence.disconnect("alice", socket),
+            Some(PresenceStatus::Offline)
+        );
+        assert_eq!(presence.disconnect("alice", socket), None);
+    }
+}
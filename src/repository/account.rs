@@ -2,17 +2,49 @@ use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use chrono::NaiveDateTime;
 use rand_core::OsRng;
+use serde::Deserialize;
 use sqlx::SqlitePool;
 use tracing::instrument;
 use uuid::Uuid;
 
-use super::CODE_NON_UNIQUE;
+use super::invite::generate_code;
+use super::{CODE_NON_UNIQUE, json_list};
 
 #[derive(sqlx::FromRow, Clone, Debug, PartialEq, Eq)]
 pub struct Account {
     pub username: String,
     pub password_hash: String,
     pub registered_at: NaiveDateTime,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_upload_uuid: Option<String>,
+    pub is_placeholder: bool,
+    /// Bots authenticate with a token instead of a password.
+    pub is_bot: bool,
+}
+
+/// What happens to the messages of an account when it is deleted.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletedMessages {
+    /// Keep them, attributed to a placeholder instead of the account.
+    Anonymize,
+    /// Remove them. Replies to them stay, but lose their quote.
+    Remove,
+}
+
+impl Account {
+    /// The size avatars are shown at, in pixels.
+    pub const AVATAR_SIZE: u32 = 128;
+
+    /// Where the account's avatar is served from, resized to
+    /// [`Self::AVATAR_SIZE`].
+    #[must_use]
+    pub fn avatar_url(&self) -> Option<String> {
+        self.avatar_upload_uuid
+            .as_ref()
+            .map(|uuid| format!("/upload/{uuid}/thumbnail?size={}", Self::AVATAR_SIZE))
+    }
 }
 
 #[derive(sqlx::FromRow, Clone, Debug, PartialEq, Eq)]
@@ -41,11 +73,27 @@ impl AccountRepository {
         .await
     }
 
-    #[instrument(skip(self, password))]
+    /// The accounts with the given usernames, in no particular order.
+    #[instrument(skip_all, fields(count = usernames.len()), err(Debug))]
+    pub async fn find_by_usernames(&self, usernames: &[&str]) -> sqlx::Result<Vec<Account>> {
+        let usernames = json_list(usernames)?;
+        sqlx::query_as!(
+            Account,
+            "SELECT * FROM accounts WHERE username IN (SELECT value FROM json_each(?))",
+            usernames
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Creates an account. If a signup code is given, it is spent in the same
+    /// transaction, and the account is only created if the code was unused.
+    #[instrument(skip(self, password, signup_code))]
     pub async fn register(
         &self,
         username: &str,
         password: &str,
+        signup_code: Option<&str>,
     ) -> Result<Account, RegistrationError> {
         let salt = SaltString::generate(&mut OsRng);
         let password_hash = Argon2::default()
@@ -61,10 +109,10 @@ impl AccountRepository {
             password_hash_str,
         );
 
-        query
-            .fetch_one(&self.connection)
+        let mut transaction = self.connection.begin().await?;
+        let account = query
+            .fetch_one(&mut *transaction)
             .await
-            .inspect(|_| tracing::debug!("Sucessfully registered new account"))
             .map_err(|error| match error {
                 sqlx::Error::Database(error)
                     if error.code().is_some_and(|code| CODE_NON_UNIQUE == code) =>
@@ -76,11 +124,56 @@ impl AccountRepository {
                     tracing::error!(?error, "Database error during registration");
                     RegistrationError::Database(error)
                 }
-            })
+            })?;
+
+        if let Some(code) = signup_code {
+            let query = sqlx::query!(
+                r#"
+                    UPDATE signup_codes SET used_by = ?, used_at = CURRENT_TIMESTAMP
+                    WHERE code = ? AND used_at IS NULL
+                "#,
+                username,
+                code,
+            );
+            let result = query.execute(&mut *transaction).await?;
+            if result.rows_affected() == 0 {
+                tracing::debug!("Rejecting registration: invalid signup code");
+                return Err(RegistrationError::InvalidSignupCode);
+            }
+        }
+
+        transaction.commit().await?;
+        tracing::debug!("Sucessfully registered new account");
+        Ok(account)
     }
 
     #[instrument(skip(self, password))]
     pub async fn login(&self, username: &str, password: &str) -> Result<Session, LoginError> {
+        let account = self.verify_password(username, password).await?;
+
+        let session_token = Uuid::new_v4();
+        let session_token_string = session_token.to_string();
+        let created_session = sqlx::query_as!(
+            Session,
+            "INSERT INTO sessions (token, account) VALUES (?, ?) RETURNING *",
+            session_token_string,
+            account.username
+        )
+        .fetch_one(&self.connection)
+        .await?;
+
+        tracing::debug!("Login successful, created new session");
+        Ok(created_session)
+    }
+
+    /// Checks the account's password, e.g. before logging in or before doing
+    /// something drastic like deleting the account.
+    #[instrument(skip(self, password))]
+    pub async fn verify_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Account, LoginError> {
         let query = sqlx::query_as!(
             Account,
             "SELECT * FROM accounts WHERE username = ?",
@@ -100,6 +193,14 @@ impl AccountRepository {
                     LoginError::Database(error)
                 }
             })?;
+        if account.is_placeholder {
+            tracing::debug!("Rejecting login attempt: account is a placeholder");
+            return Err(LoginError::InvalidCredentials);
+        }
+        if account.is_bot {
+            tracing::debug!("Rejecting login attempt: bots log in with a token");
+            return Err(LoginError::InvalidCredentials);
+        }
 
         let stored_hash =
             PasswordHash::try_from(account.password_hash.as_str()).map_err(LoginError::Hash)?;
@@ -116,19 +217,249 @@ impl AccountRepository {
                 }
             })?;
 
-        let session_token = Uuid::new_v4();
-        let session_token_string = session_token.to_string();
-        let created_session = sqlx::query_as!(
+        Ok(account)
+    }
+
+    /// Replaces the account's profile fields. `None` clears a field.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn update_profile(
+        &self,
+        username: &str,
+        display_name: Option<&str>,
+        bio: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE accounts SET display_name = ?, bio = ? WHERE username = ?",
+            display_name,
+            bio,
+            username,
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Points the account's avatar at an upload, or removes it. The previous
+    /// avatar is left for the upload reconciler to clean up.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn set_avatar(
+        &self,
+        username: &str,
+        upload_uuid: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE accounts SET avatar_upload_uuid = ? WHERE username = ?",
+            upload_uuid,
+            username,
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Creates a bot account. It has no password, and can only be used once
+    /// it has been given a token with [`Self::reset_bot_token`].
+    #[instrument(skip(self))]
+    pub async fn create_bot(
+        &self,
+        username: &str,
+        display_name: Option<&str>,
+    ) -> Result<Account, RegistrationError> {
+        let mut transaction = self.connection.begin().await?;
+        let account = sqlx::query_as!(
+            Account,
+            r#"
+                INSERT INTO accounts (username, password_hash, display_name, is_bot)
+                VALUES (?, '!', ?, TRUE)
+                RETURNING *
+            "#,
+            username,
+            display_name,
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|error| match error {
+            sqlx::Error::Database(error)
+                if error.code().is_some_and(|code| CODE_NON_UNIQUE == code) =>
+            {
+                RegistrationError::NameTaken
+            }
+            _ => RegistrationError::Database(error),
+        })?;
+        // NOTE: Bots join the rooms they are meant for, not the public one.
+        sqlx::query!("DELETE FROM room_membership WHERE member = ?", username)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(account)
+    }
+
+    /// All bot accounts, in the order they were created.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_bots(&self) -> Result<Vec<Account>, sqlx::Error> {
+        sqlx::query_as!(
+            Account,
+            "SELECT * FROM accounts WHERE is_bot ORDER BY registered_at, username"
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Gives the bot a new token, revoking any previous one. The token is a
+    /// session that never expires on its own.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn reset_bot_token(&self, username: &str) -> Result<Session, sqlx::Error> {
+        let token_string = Uuid::new_v4().to_string();
+        let mut transaction = self.connection.begin().await?;
+        sqlx::query!(
+            "UPDATE sessions SET expired = 1 WHERE account = ?",
+            username
+        )
+        .execute(&mut *transaction)
+        .await?;
+        let session = sqlx::query_as!(
             Session,
             "INSERT INTO sessions (token, account) VALUES (?, ?) RETURNING *",
-            session_token_string,
-            account.username
+            token_string,
+            username
         )
-        .fetch_one(&self.connection)
+        .fetch_one(&mut *transaction)
         .await?;
+        transaction.commit().await?;
+        Ok(session)
+    }
 
-        tracing::debug!("Login successful, created new session");
-        Ok(created_session)
+    /// Creates an account that nobody can log in as, standing in for someone
+    /// whose messages are kept. Does nothing (and returns `false`) if the
+    /// account exists already.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn create_placeholder(
+        &self,
+        username: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        let mut transaction = self.connection.begin().await?;
+        let inserted = sqlx::query!(
+            r#"
+                INSERT OR IGNORE INTO accounts (username, password_hash, display_name, is_placeholder)
+                VALUES (?, '!', ?, TRUE)
+            "#,
+            username,
+            display_name,
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected()
+            > 0;
+        if inserted {
+            // NOTE: New accounts join the public room automatically.
+            sqlx::query!("DELETE FROM room_membership WHERE member = ?", username)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(inserted)
+    }
+
+    /// Deletes the account along with everything that only matters to it.
+    ///
+    /// Rooms the account owns are handed over to their longest-standing
+    /// moderator, or member if there is none, so that they stay manageable.
+    /// Messages are either removed or kept under a placeholder account, along
+    /// with the pins the account made.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn delete(
+        &self,
+        username: &str,
+        messages: DeletedMessages,
+    ) -> Result<(), sqlx::Error> {
+        let mut transaction = self.connection.begin().await?;
+
+        sqlx::query!(
+            r#"
+                UPDATE room_membership AS heir SET role = 'owner'
+                WHERE heir.member = (
+                    SELECT m.member FROM room_membership m
+                    WHERE m.room_id = heir.room_id AND m.member != ?
+                    ORDER BY m.role = 'moderator' DESC, m.joined_at, m.member
+                    LIMIT 1
+                )
+                AND EXISTS (
+                    SELECT 1 FROM room_membership o
+                    WHERE o.room_id = heir.room_id AND o.member = ? AND o.role = 'owner'
+                )
+                AND NOT EXISTS (
+                    SELECT 1 FROM room_membership o
+                    WHERE o.room_id = heir.room_id AND o.member != ? AND o.role = 'owner'
+                )
+            "#,
+            username,
+            username,
+            username,
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        match messages {
+            DeletedMessages::Anonymize => {
+                let placeholder = format!("deleted-{}", generate_code());
+                sqlx::query!(
+                    r#"
+                        INSERT INTO accounts (username, password_hash, display_name, is_placeholder)
+                        VALUES (?, '!', 'Deleted user', TRUE)
+                    "#,
+                    placeholder,
+                )
+                .execute(&mut *transaction)
+                .await?;
+                // NOTE: New accounts join the public room automatically.
+                sqlx::query!("DELETE FROM room_membership WHERE member = ?", placeholder)
+                    .execute(&mut *transaction)
+                    .await?;
+                sqlx::query!(
+                    "UPDATE messages SET sender = ? WHERE sender = ?",
+                    placeholder,
+                    username,
+                )
+                .execute(&mut *transaction)
+                .await?;
+                sqlx::query!(
+                    "UPDATE pinned_messages SET pinned_by = ? WHERE pinned_by = ?",
+                    placeholder,
+                    username,
+                )
+                .execute(&mut *transaction)
+                .await?;
+                tracing::debug!(placeholder, "Anonymized messages");
+            }
+            DeletedMessages::Remove => {
+                sqlx::query!("DELETE FROM messages WHERE sender = ?", username)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+        }
+
+        sqlx::query!("DELETE FROM accounts WHERE username = ?", username)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        tracing::debug!("Deleted account");
+        Ok(())
+    }
+
+    /// Whether the session may still be used, which it can't once it has
+    /// expired or its account has been deleted.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn is_session_active(&self, session_token: Uuid) -> Result<bool, sqlx::Error> {
+        let token_str = session_token.to_string();
+        let record = sqlx::query!(
+            "SELECT token FROM sessions WHERE token = ? AND expired = 0",
+            token_str
+        )
+        .fetch_optional(&self.connection)
+        .await?;
+        Ok(record.is_some())
     }
 
     #[instrument(skip(self), err(Debug))]
@@ -147,6 +478,9 @@ pub enum RegistrationError {
     #[error("An account with this username already exists")]
     NameTaken,
 
+    #[error("The signup code is invalid or has already been used")]
+    InvalidSignupCode,
+
     #[error("Failed to hash the password")]
     Hash(argon2::password_hash::Error),
 
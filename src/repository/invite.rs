@@ -0,0 +1,241 @@
+use chrono::{NaiveDateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use sqlx::SqlitePool;
+use tracing::instrument;
+
+/// Characters used in invite and signup codes, without the ones that are easy
+/// to mix up.
+const CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const CODE_LENGTH: usize = 10;
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct InviteCode {
+    pub code: String,
+    pub room_id: i64,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub max_uses: Option<i64>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub use_count: i64,
+}
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct InviteUse {
+    pub account: String,
+    pub joined_at: NaiveDateTime,
+}
+
+/// Why an invite code can't be used (anymore).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum InviteUnusable {
+    #[error("This invite has been revoked.")]
+    Revoked,
+    #[error("This invite has expired.")]
+    Expired,
+    #[error("This invite has been used up.")]
+    UsedUp,
+}
+
+pub(super) fn generate_code() -> String {
+    self::generate_secret(CODE_LENGTH)
+}
+
+/// A random string of `length` characters from the code alphabet.
+pub(super) fn generate_secret(length: usize) -> String {
+    // NOTE: The alphabet is short enough for the modulo bias not to matter.
+    (0..length)
+        .map(|_| CODE_ALPHABET[OsRng.next_u32() as usize % CODE_ALPHABET.len()] as char)
+        .collect()
+}
+
+impl InviteCode {
+    pub fn check_usable(&self) -> Result<(), InviteUnusable> {
+        if self.revoked_at.is_some() {
+            return Err(InviteUnusable::Revoked);
+        }
+        if self
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now().naive_utc())
+        {
+            return Err(InviteUnusable::Expired);
+        }
+        if self
+            .max_uses
+            .is_some_and(|max_uses| self.use_count >= max_uses)
+        {
+            return Err(InviteUnusable::UsedUp);
+        }
+        Ok(())
+    }
+
+    /// Records that `account` joined through this invite. Returns `false` if
+    /// the invite became unusable in the meantime, e.g. because the last use
+    /// was claimed by someone else.
+    ///
+    /// Someone who already joined through the invite before (and has since
+    /// left) keeps their use, so they can come back even once the invite is
+    /// used up, as long as it is neither revoked nor expired.
+    #[instrument(skip_all, fields(invite.code = self.code, account), err(Debug))]
+    pub async fn redeem(&self, connection: &SqlitePool, account: &str) -> sqlx::Result<bool> {
+        // NOTE: The no-op update on conflict still counts as an affected row,
+        // which tells a returning account apart from a rejected one.
+        let result = sqlx::query!(
+            r#"
+                INSERT INTO room_invite_uses (code, account)
+                SELECT i.code, ?1 FROM room_invites i
+                WHERE i.code = ?2 AND i.revoked_at IS NULL
+                AND (i.expires_at IS NULL OR i.expires_at > CURRENT_TIMESTAMP)
+                AND (
+                    i.max_uses IS NULL
+                    OR i.max_uses > (SELECT COUNT(*) FROM room_invite_uses u WHERE u.code = i.code)
+                    OR EXISTS(SELECT 1 FROM room_invite_uses u WHERE u.code = i.code AND u.account = ?1)
+                )
+                ON CONFLICT(code, account) DO UPDATE SET joined_at = room_invite_uses.joined_at
+            "#,
+            account,
+            self.code,
+        )
+        .execute(connection)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Who joined through this invite, in the order they did.
+    #[instrument(skip_all, fields(invite.code = self.code), err(Debug))]
+    pub async fn get_uses(&self, connection: &SqlitePool) -> sqlx::Result<Vec<InviteUse>> {
+        sqlx::query_as!(
+            InviteUse,
+            "SELECT account, joined_at FROM room_invite_uses WHERE code = ? ORDER BY joined_at, account",
+            self.code
+        )
+        .fetch_all(connection)
+        .await
+    }
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct InviteRepository {
+    pub(super) connection: SqlitePool,
+}
+
+impl InviteRepository {
+    #[instrument(skip(self), err(Debug))]
+    pub async fn create(
+        &self,
+        room_id: i64,
+        created_by: &str,
+        max_uses: Option<i64>,
+        expires_at: Option<NaiveDateTime>,
+    ) -> sqlx::Result<InviteCode> {
+        let code = self::generate_code();
+        sqlx::query!(
+            r#"
+                INSERT INTO room_invites (code, room_id, created_by, max_uses, expires_at)
+                VALUES (?, ?, ?, ?, ?)
+            "#,
+            code,
+            room_id,
+            created_by,
+            max_uses,
+            expires_at,
+        )
+        .execute(&self.connection)
+        .await?;
+
+        self.find(&code).await?.ok_or(sqlx::Error::RowNotFound)
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find(&self, code: &str) -> sqlx::Result<Option<InviteCode>> {
+        sqlx::query_as!(
+            InviteCode,
+            r#"
+                SELECT
+                    i.code, i.room_id, i.created_by, i.created_at, i.max_uses, i.expires_at, i.revoked_at,
+                    (SELECT COUNT(*) FROM room_invite_uses u WHERE u.code = i.code) AS "use_count!: i64"
+                FROM room_invites i
+                WHERE i.code = ?
+            "#,
+            code
+        )
+        .fetch_optional(&self.connection)
+        .await
+    }
+
+    /// All of the room's invites, newest first, revoked ones included.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_by_room(&self, room_id: i64) -> sqlx::Result<Vec<InviteCode>> {
+        sqlx::query_as!(
+            InviteCode,
+            r#"
+                SELECT
+                    i.code, i.room_id, i.created_by, i.created_at, i.max_uses, i.expires_at, i.revoked_at,
+                    (SELECT COUNT(*) FROM room_invite_uses u WHERE u.code = i.code) AS "use_count!: i64"
+                FROM room_invites i
+                WHERE i.room_id = ?
+                ORDER BY i.created_at DESC, i.rowid DESC
+            "#,
+            room_id
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Returns `false` if the invite was already revoked.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn revoke(&self, code: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE room_invites SET revoked_at = CURRENT_TIMESTAMP WHERE code = ? AND revoked_at IS NULL",
+            code
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::{self, Repository};
+
+    #[tokio::test]
+    async fn members_can_rejoin_through_the_same_invite() {
+        let pool = repository::test_pool().await;
+        let repository = Repository::new(pool.clone());
+        for username in ["alice", "bob", "carol"] {
+            repository
+                .accounts
+                .create_placeholder(username, None)
+                .await
+                .unwrap();
+        }
+        let room = repository.rooms.create("general").await.unwrap();
+        room.add_member(&pool, "alice").await.unwrap();
+        let invite = repository
+            .invites
+            .create(room.id, "alice", Some(1), None)
+            .await
+            .unwrap();
+
+        assert!(invite.redeem(&pool, "bob").await.unwrap());
+        room.add_member(&pool, "bob").await.unwrap();
+        assert!(!invite.redeem(&pool, "carol").await.unwrap());
+
+        room.remove_member(&pool, "bob").await.unwrap();
+        assert!(invite.redeem(&pool, "bob").await.unwrap());
+        let invite = repository
+            .invites
+            .find(&invite.code)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invite.use_count, 1);
+
+        repository.invites.revoke(&invite.code).await.unwrap();
+        assert!(!invite.redeem(&pool, "bob").await.unwrap());
+    }
+}
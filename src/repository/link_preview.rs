@@ -0,0 +1,191 @@
+use chrono::NaiveDateTime;
+use sqlx::SqlitePool;
+use tracing::instrument;
+use url::Url;
+
+use super::json_list;
+use super::message::Message;
+use crate::endpoints::chat::LinkPreviewCard;
+
+/// No message gets more previews than this, no matter how many links it has.
+pub const MAX_PREVIEWS_PER_MESSAGE: usize = 3;
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub fetch_failed: bool,
+    pub fetched_at: NaiveDateTime,
+}
+
+impl LinkPreview {
+    /// A preview is only worth showing if the page told us something about
+    /// itself.
+    #[must_use]
+    pub const fn is_presentable(&self) -> bool {
+        !self.fetch_failed && (self.title.is_some() || self.description.is_some())
+    }
+
+    pub fn to_card(&self) -> LinkPreviewCard {
+        LinkPreviewCard {
+            url: self.url.clone(),
+            title: self.title.clone(),
+            description: self.description.clone(),
+        }
+    }
+}
+
+/// Extracts the `http(s)` links from a message's text, in order of appearance
+/// and without duplicates, up to [`MAX_PREVIEWS_PER_MESSAGE`] of them.
+///
+/// Links are delimited by whitespace and brackets, so that both bare links and
+/// Markdown `[text](link)` or `<link>` forms are picked up, and punctuation
+/// right after a link is not a part of it.
+#[must_use]
+pub fn parse_urls(text: &str) -> Vec<Url> {
+    const DELIMITERS: &[char] = &['<', '>', '(', ')', '[', ']', '"', '\''];
+    const TRAILING_PUNCTUATION: &[char] = &['.', ',', ':', ';', '!', '?'];
+
+    let mut urls: Vec<Url> = vec![];
+    let candidates = text
+        .split(|c: char| c.is_whitespace() || DELIMITERS.contains(&c))
+        .filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .map(|word| word.trim_end_matches(TRAILING_PUNCTUATION))
+        .filter_map(|word| Url::parse(word).ok());
+    for url in candidates {
+        if urls.len() == MAX_PREVIEWS_PER_MESSAGE {
+            break;
+        }
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+impl Message {
+    #[instrument(skip_all, fields(message.id = self.id, url), err(Debug))]
+    pub async fn attach_link_preview(
+        &self,
+        connection: &SqlitePool,
+        url: &str,
+        position: i64,
+    ) -> sqlx::Result<()> {
+        let query = sqlx::query!(
+            "INSERT OR IGNORE INTO message_link_previews (message_id, url, position) VALUES (?, ?, ?)",
+            self.id,
+            url,
+            position,
+        );
+        query.execute(connection).await?;
+        Ok(())
+    }
+
+    /// The previews attached to this message that are worth showing.
+    #[instrument(skip_all, fields(message.id = self.id), err(Debug))]
+    pub async fn get_link_previews(
+        &self,
+        connection: &SqlitePool,
+    ) -> sqlx::Result<Vec<LinkPreviewCard>> {
+        let query = sqlx::query_as!(
+            LinkPreview,
+            r#"
+                SELECT lp.*
+                FROM link_previews lp
+                JOIN message_link_previews mlp ON mlp.url = lp.url
+                WHERE mlp.message_id = ?
+                ORDER BY mlp.position
+            "#,
+            self.id,
+        );
+        let previews = query.fetch_all(connection).await?;
+        Ok(previews
+            .iter()
+            .filter(|preview| preview.is_presentable())
+            .map(LinkPreview::to_card)
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct LinkPreviewRepository {
+    pub(super) connection: SqlitePool,
+}
+
+impl LinkPreviewRepository {
+    /// The previews attached to any of the given messages, paired with the
+    /// message they are attached to and in the order they appear in it.
+    #[instrument(skip_all, fields(count = message_ids.len()), err(Debug))]
+    pub async fn find_for_messages(
+        &self,
+        message_ids: &[i64],
+    ) -> sqlx::Result<Vec<(i64, LinkPreview)>> {
+        let message_ids = json_list(message_ids)?;
+        let query = sqlx::query!(
+            r#"
+                SELECT mlp.message_id, lp.url, lp.title, lp.description, lp.fetch_failed, lp.fetched_at
+                FROM link_previews lp
+                JOIN message_link_previews mlp ON mlp.url = lp.url
+                WHERE mlp.message_id IN (SELECT value FROM json_each(?))
+                ORDER BY mlp.message_id, mlp.position
+            "#,
+            message_ids,
+        );
+        let rows = query.fetch_all(&self.connection).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let preview = LinkPreview {
+                    url: row.url,
+                    title: row.title,
+                    description: row.description,
+                    fetch_failed: row.fetch_failed,
+                    fetched_at: row.fetched_at,
+                };
+                (row.message_id, preview)
+            })
+            .collect())
+    }
+
+    /// Looks up a cached preview fetched after `fresh_since`.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_fresh(
+        &self,
+        url: &str,
+        fresh_since: NaiveDateTime,
+    ) -> sqlx::Result<Option<LinkPreview>> {
+        let query = sqlx::query_as!(
+            LinkPreview,
+            "SELECT * FROM link_previews WHERE url = ? AND fetched_at > ?",
+            url,
+            fresh_since,
+        );
+        query.fetch_optional(&self.connection).await
+    }
+
+    /// Stores the outcome of a fetch, replacing whatever was cached before.
+    #[instrument(skip_all, fields(url = preview.url), err(Debug))]
+    pub async fn save(&self, preview: &LinkPreview) -> sqlx::Result<()> {
+        let query = sqlx::query!(
+            r#"
+                INSERT INTO link_previews (url, title, description, fetch_failed, fetched_at)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT (url) DO UPDATE SET
+                    title = excluded.title,
+                    description = excluded.description,
+                    fetch_failed = excluded.fetch_failed,
+                    fetched_at = excluded.fetched_at
+            "#,
+            preview.url,
+            preview.title,
+            preview.description,
+            preview.fetch_failed,
+            preview.fetched_at,
+        );
+        query.execute(&self.connection).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeSet;
+
+use chrono::NaiveDateTime;
+use sqlx::SqlitePool;
+use tracing::instrument;
+
+use super::message::Message;
+
+/// An unread mention along with the message it was made in.
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct UnreadMention {
+    pub message_id: i64,
+    pub room_id: i64,
+    pub room_name: String,
+    pub sent_at: NaiveDateTime,
+}
+
+/// Extracts the usernames mentioned as `@username` in a message's text.
+///
+/// A mention has to start a word, so that e-mail addresses aren't picked up,
+/// and any punctuation it is immediately followed by is not a part of it.
+#[must_use]
+pub fn parse_mentions(text: &str) -> BTreeSet<&str> {
+    const TRAILING_PUNCTUATION: &[char] = &['.', ',', ':', ';', '!', '?', ')', '\'', '"'];
+
+    text.split(char::is_whitespace)
+        .filter_map(|word| word.trim_start_matches('(').strip_prefix('@'))
+        .map(|username| username.trim_end_matches(TRAILING_PUNCTUATION))
+        .filter(|username| !username.is_empty())
+        .collect()
+}
+
+impl Message {
+    /// Records mentions of room members in this message's text, except for the
+    /// sender mentioning themselves. Returns the mentioned usernames.
+    #[instrument(skip_all, fields(message.id = self.id), err(Debug))]
+    pub async fn record_mentions(&self, connection: &SqlitePool) -> sqlx::Result<Vec<String>> {
+        let Some(text) = &self.text else {
+            return Ok(vec![]);
+        };
+
+        let mut mentioned = vec![];
+        for username in parse_mentions(text) {
+            let recorded = sqlx::query_scalar!(
+                r#"
+                    INSERT OR IGNORE INTO mentions (message_id, account)
+                    SELECT ?, member FROM room_membership
+                    WHERE room_id = ? AND member = ? AND member != ?
+                    RETURNING account
+                "#,
+                self.id,
+                self.room_id,
+                username,
+                self.sender,
+            )
+            .fetch_optional(connection)
+            .await?;
+            mentioned.extend(recorded);
+        }
+
+        tracing::debug!(?mentioned, "Recorded mentions");
+        Ok(mentioned)
+    }
+
+    #[instrument(skip_all, fields(message.id = self.id), err(Debug))]
+    pub async fn get_mentions(&self, connection: &SqlitePool) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar!("SELECT account FROM mentions WHERE message_id = ?", self.id)
+            .fetch_all(connection)
+            .await
+    }
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct MentionRepository {
+    pub(super) connection: SqlitePool,
+}
+
+impl MentionRepository {
+    /// Unread mentions of `account`, newest first. Mentions in rooms the
+    /// account has since left are not included.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_unread(&self, account: &str) -> Result<Vec<UnreadMention>, sqlx::Error> {
+        sqlx::query_as!(
+            UnreadMention,
+            r#"
+                SELECT m.id AS message_id, r.id AS room_id, r.name AS room_name, m.sent_at
+                FROM mentions n
+                JOIN messages m ON m.id = n.message_id
+                JOIN rooms r ON r.id = m.room_id
+                JOIN room_membership rm ON rm.room_id = r.id AND rm.member = n.account
+                WHERE n.account = ? AND n.read_at IS NULL
+                ORDER BY m.sent_at DESC, m.id DESC
+            "#,
+            account
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Marks a single mention as read, or all of them if `message_id` is
+    /// `None`.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn mark_read(&self, account: &str, message_id: Option<i64>) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE mentions SET read_at = CURRENT_TIMESTAMP
+                WHERE account = ? AND read_at IS NULL AND (? IS NULL OR message_id = ?)
+            "#,
+            account,
+            message_id,
+            message_id,
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+}
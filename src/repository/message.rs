@@ -1,8 +1,14 @@
+use std::collections::HashMap;
+
 use chrono::NaiveDateTime;
+use sqlx::SqlitePool;
 use tracing::instrument;
-use uuid::Uuid;
 
-use crate::endpoints::chat::EchoedMessage;
+use super::account::Account;
+use super::upload::Upload;
+use super::{CODE_NON_UNIQUE, json_list};
+use crate::endpoints::chat::{EchoedMessage, LinkPreviewCard, ReactionSummary, ReplyPreview};
+use crate::markdown;
 use crate::state::SharedState;
 
 #[derive(sqlx::FromRow, Clone, Debug)]
@@ -14,36 +20,363 @@ pub struct Message {
     pub text: Option<String>,
     pub sent_at: NaiveDateTime,
     pub file_upload_uuid: Option<String>,
+    pub reply_to: Option<i64>,
+    pub text_html: Option<String>,
+    pub is_system: bool,
+}
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct Reaction {
+    pub message_id: i64,
+    pub account: String,
+    pub emoji: String,
+    pub reacted_at: NaiveDateTime,
 }
 
 impl Message {
+    /// Adds a reaction, returning `false` if the account had already reacted
+    /// to this message with the same emoji.
+    #[instrument(skip(self, connection), fields(message.id = self.id), err(Debug))]
+    pub async fn add_reaction(
+        &self,
+        connection: &SqlitePool,
+        account: &str,
+        emoji: &str,
+    ) -> sqlx::Result<bool> {
+        let query = sqlx::query!(
+            "INSERT INTO message_reactions (message_id, account, emoji) VALUES (?, ?, ?)",
+            self.id,
+            account,
+            emoji,
+        );
+        match query.execute(connection).await {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(error))
+                if error.code().is_some_and(|code| CODE_NON_UNIQUE == code) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Removes a reaction, returning `false` if there was none to remove.
+    #[instrument(skip(self, connection), fields(message.id = self.id), err(Debug))]
+    pub async fn remove_reaction(
+        &self,
+        connection: &SqlitePool,
+        account: &str,
+        emoji: &str,
+    ) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM message_reactions WHERE message_id = ? AND account = ? AND emoji = ?",
+            self.id,
+            account,
+            emoji,
+        )
+        .execute(connection)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all, fields(message.id = self.id), err(Debug))]
+    pub async fn get_reactions(&self, connection: &SqlitePool) -> sqlx::Result<Vec<Reaction>> {
+        sqlx::query_as!(
+            Reaction,
+            "SELECT * FROM message_reactions WHERE message_id = ? ORDER BY reacted_at, rowid",
+            self.id
+        )
+        .fetch_all(connection)
+        .await
+    }
+
+    /// Reactions grouped by emoji, in the order each emoji was first used.
+    #[instrument(skip_all, fields(message.id = self.id), err(Debug))]
+    pub async fn get_reaction_summary(
+        &self,
+        connection: &SqlitePool,
+    ) -> sqlx::Result<Vec<ReactionSummary>> {
+        Ok(summarize_reactions(self.get_reactions(connection).await?))
+    }
+
+    /// The message's text rendered to HTML. Messages stored before rendering
+    /// was cached are rendered on the spot, without storing the result.
+    #[must_use]
+    pub fn rendered_html(&self) -> Option<String> {
+        self.text_html
+            .clone()
+            .or_else(|| self.text.as_deref().map(markdown::render))
+    }
+
     #[instrument(skip_all, err(Debug), fields(message.id = self.id))]
     pub async fn to_echoed_message(self, state: &SharedState) -> sqlx::Result<EchoedMessage> {
-        let file_upload = if let Some(file_upload_uuid) = self.file_upload_uuid {
-            let uuid = file_upload_uuid.parse::<Uuid>().unwrap();
-            state.repository.uploads.find(uuid).await?
-        } else {
-            None
-        };
-
-        let (upload_url, upload_filename) = match file_upload {
-            None => (None, None),
-            Some(upload) => (
-                Some(upload.uuid.to_string()),
-                Some(upload.filename.to_string_lossy().to_string()),
-            ),
-        };
-
-        let echoed_message = EchoedMessage {
+        let mut echoed_messages = Self::to_echoed_messages(vec![self], state).await?;
+        Ok(echoed_messages.remove(0))
+    }
+
+    /// Like [`Self::to_echoed_message`] for many messages at once, in the same
+    /// order. What they refer to is looked up in one query per kind for all of
+    /// them, rather than in several queries per message.
+    #[instrument(skip_all, err(Debug), fields(messages = messages.len()))]
+    pub async fn to_echoed_messages(
+        messages: Vec<Self>,
+        state: &SharedState,
+    ) -> sqlx::Result<Vec<EchoedMessage>> {
+        let message_ids: Vec<i64> = messages.iter().map(|message| message.id).collect();
+        let senders: Vec<&str> = messages
+            .iter()
+            .map(|message| message.sender.as_str())
+            .collect();
+        let parent_ids: Vec<i64> = messages
+            .iter()
+            .filter_map(|message| message.reply_to)
+            .collect();
+        let upload_uuids: Vec<&str> = messages
+            .iter()
+            .filter_map(|message| message.file_upload_uuid.as_deref())
+            .collect();
+
+        let repository = &state.repository;
+        let mut reactions: HashMap<i64, Vec<Reaction>> = HashMap::new();
+        for reaction in repository.messages.find_reactions(&message_ids).await? {
+            reactions
+                .entry(reaction.message_id)
+                .or_default()
+                .push(reaction);
+        }
+        let mut link_previews: HashMap<i64, Vec<LinkPreviewCard>> = HashMap::new();
+        for (message_id, preview) in repository
+            .link_previews
+            .find_for_messages(&message_ids)
+            .await?
+        {
+            if preview.is_presentable() {
+                link_previews
+                    .entry(message_id)
+                    .or_default()
+                    .push(preview.to_card());
+            }
+        }
+        let sender_accounts: HashMap<String, Account> = repository
+            .accounts
+            .find_by_usernames(&senders)
+            .await?
+            .into_iter()
+            .map(|account| (account.username.clone(), account))
+            .collect();
+        let parents: HashMap<i64, Self> = repository
+            .messages
+            .find_by_ids(&parent_ids)
+            .await?
+            .into_iter()
+            .map(|parent| (parent.id, parent))
+            .collect();
+        let uploads: HashMap<String, Upload> = repository
+            .uploads
+            .find_by_uuids(&upload_uuids)
+            .await?
+            .into_iter()
+            .map(|upload| (upload.uuid.clone(), upload))
+            .collect();
+
+        let echoed_messages = messages
+            .into_iter()
+            .map(|message| {
+                let sender_account = sender_accounts.get(&message.sender);
+                let file_upload = message
+                    .file_upload_uuid
+                    .as_ref()
+                    .and_then(|uuid| uploads.get(uuid));
+
+                let (upload_width, upload_height, upload_thumbnail_url) = match file_upload {
+                    Some(upload) if upload.is_image() && !upload.is_deleted() => (
+                        upload.width,
+                        upload.height,
+                        Some(format!("/upload/{}/thumbnail", upload.uuid)),
+                    ),
+                    _ => (None, None, None),
+                };
+                let upload_deleted = file_upload.is_some_and(Upload::is_deleted);
+                let (upload_url, upload_filename) = file_upload.map_or((None, None), |upload| {
+                    (
+                        Some(format!("/upload/{}", upload.uuid)),
+                        Some(upload.filename.to_string_lossy().to_string()),
+                    )
+                });
+
+                EchoedMessage {
+                    id: message.id,
+                    sender_display_name: sender_account
+                        .and_then(|account| account.display_name.clone()),
+                    sender_avatar_url: sender_account.and_then(Account::avatar_url),
+                    sender_is_bot: sender_account.is_some_and(|account| account.is_bot),
+                    text_html: message.rendered_html(),
+                    reactions: summarize_reactions(
+                        reactions.remove(&message.id).unwrap_or_default(),
+                    ),
+                    reply_to: message
+                        .reply_to
+                        .and_then(|parent_id| parents.get(&parent_id))
+                        .map(Self::to_reply_preview),
+                    link_previews: link_previews.remove(&message.id).unwrap_or_default(),
+                    sender: message.sender,
+                    room_id: message.room_id,
+                    text: message.text,
+                    is_system: message.is_system,
+                    sent_at: message.sent_at,
+                    upload_url,
+                    upload_filename,
+                    upload_width,
+                    upload_height,
+                    upload_thumbnail_url,
+                    upload_deleted,
+                }
+            })
+            .collect();
+
+        Ok(echoed_messages)
+    }
+
+    /// A short quote of this message, shown above the replies to it.
+    pub fn to_reply_preview(&self) -> ReplyPreview {
+        const PREVIEW_LENGTH: usize = 100;
+
+        let text = self.text.as_deref().unwrap_or_default();
+        let mut text_preview: String = text.chars().take(PREVIEW_LENGTH).collect();
+        if text.chars().nth(PREVIEW_LENGTH).is_some() {
+            text_preview.push('…');
+        }
+
+        ReplyPreview {
             id: self.id,
-            sender: self.sender,
-            room_id: self.room_id,
-            text: self.text,
-            sent_at: self.sent_at,
-            upload_url,
-            upload_filename,
-        };
-
-        Ok(echoed_message)
+            sender: self.sender.clone(),
+            text_preview,
+            has_upload: self.file_upload_uuid.is_some(),
+        }
+    }
+}
+
+/// Groups reactions by emoji, in the order each emoji was first used.
+fn summarize_reactions(reactions: Vec<Reaction>) -> Vec<ReactionSummary> {
+    let mut summary: Vec<ReactionSummary> = vec![];
+    for reaction in reactions {
+        match summary.iter_mut().find(|s| s.emoji == reaction.emoji) {
+            Some(entry) => {
+                entry.count += 1;
+                entry.reactors.push(reaction.account);
+            }
+            None => summary.push(ReactionSummary {
+                emoji: reaction.emoji,
+                count: 1,
+                reactors: vec![reaction.account],
+            }),
+        }
+    }
+    summary
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct MessageRepository {
+    pub(super) connection: SqlitePool,
+}
+
+impl MessageRepository {
+    /// Everything `sender` has said, in every room, oldest first.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_by_sender(&self, sender: &str) -> Result<Vec<Message>, sqlx::Error> {
+        sqlx::query_as!(
+            Message,
+            "SELECT * FROM messages WHERE sender = ? ORDER BY sent_at, id",
+            sender
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_by_id(&self, message_id: i64) -> Result<Option<Message>, sqlx::Error> {
+        sqlx::query_as!(Message, "SELECT * FROM messages WHERE id = ?", message_id)
+            .fetch_optional(&self.connection)
+            .await
+    }
+
+    /// The messages with the given ids, in no particular order. Ids of messages
+    /// that don't exist are skipped.
+    #[instrument(skip_all, fields(count = message_ids.len()), err(Debug))]
+    pub async fn find_by_ids(&self, message_ids: &[i64]) -> sqlx::Result<Vec<Message>> {
+        let message_ids = json_list(message_ids)?;
+        sqlx::query_as!(
+            Message,
+            "SELECT * FROM messages WHERE id IN (SELECT value FROM json_each(?))",
+            message_ids
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// The reactions to all of the given messages, in the order they were made.
+    #[instrument(skip_all, fields(count = message_ids.len()), err(Debug))]
+    pub async fn find_reactions(&self, message_ids: &[i64]) -> sqlx::Result<Vec<Reaction>> {
+        let message_ids = json_list(message_ids)?;
+        sqlx::query_as!(
+            Reaction,
+            r#"
+                SELECT * FROM message_reactions
+                WHERE message_id IN (SELECT value FROM json_each(?))
+                ORDER BY reacted_at, rowid
+            "#,
+            message_ids
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Removes the message along with its reactions. Replies to it are kept.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn delete(&self, message_id: i64) -> sqlx::Result<()> {
+        sqlx::query!("DELETE FROM messages WHERE id = ?", message_id)
+            .execute(&self.connection)
+            .await?;
+        Ok(())
+    }
+
+    /// Finds the whole thread `message_id` belongs to: the message at its root
+    /// followed by all direct and indirect replies, oldest first.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_thread(&self, message_id: i64) -> Result<Vec<Message>, sqlx::Error> {
+        sqlx::query_as!(
+            Message,
+            r#"
+                WITH RECURSIVE
+                ancestors(id, reply_to) AS (
+                    SELECT id, reply_to FROM messages WHERE id = ?
+                    UNION ALL
+                    SELECT m.id, m.reply_to FROM messages m JOIN ancestors a ON m.id = a.reply_to
+                ),
+                thread(id) AS (
+                    SELECT id FROM ancestors WHERE reply_to IS NULL
+                    UNION ALL
+                    SELECT m.id FROM messages m JOIN thread t ON m.reply_to = t.id
+                )
+                SELECT
+                    m.id AS "id!",
+                    m.sender AS "sender!",
+                    m.room_id AS "room_id!",
+                    m.text,
+                    m.sent_at AS "sent_at!",
+                    m.file_upload_uuid,
+                    m.reply_to,
+                    m.text_html,
+                    m.is_system
+                FROM messages m
+                WHERE m.id IN (SELECT id FROM thread)
+                ORDER BY m.sent_at, m.id
+            "#,
+            message_id
+        )
+        .fetch_all(&self.connection)
+        .await
     }
 }
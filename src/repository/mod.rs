@@ -1,16 +1,27 @@
 pub const CODE_NON_UNIQUE: &str = "2067";
 
 pub mod account;
+pub mod invite;
+pub mod link_preview;
+pub mod mention;
 pub mod message;
 pub mod room;
+pub mod signup_code;
 pub mod upload;
+pub mod webhook;
 
 #[derive(Debug, Clone)]
 #[must_use]
 pub struct Repository {
     pub accounts: account::AccountRepository,
+    pub invites: invite::InviteRepository,
+    pub link_previews: link_preview::LinkPreviewRepository,
+    pub mentions: mention::MentionRepository,
+    pub messages: message::MessageRepository,
     pub rooms: room::RoomRepository,
+    pub signup_codes: signup_code::SignupCodeRepository,
     pub uploads: upload::UploadRepository,
+    pub webhooks: webhook::WebhookRepository,
 }
 
 impl Repository {
@@ -18,15 +29,75 @@ impl Repository {
         let accounts = account::AccountRepository {
             connection: connection.clone(),
         };
+        let invites = invite::InviteRepository {
+            connection: connection.clone(),
+        };
+        let link_previews = link_preview::LinkPreviewRepository {
+            connection: connection.clone(),
+        };
+        let mentions = mention::MentionRepository {
+            connection: connection.clone(),
+        };
+        let messages = message::MessageRepository {
+            connection: connection.clone(),
+        };
         let rooms = room::RoomRepository {
             connection: connection.clone(),
         };
-        let uploads = upload::UploadRepository { connection };
+        let signup_codes = signup_code::SignupCodeRepository {
+            connection: connection.clone(),
+        };
+        let uploads = upload::UploadRepository {
+            connection: connection.clone(),
+        };
+        let webhooks = webhook::WebhookRepository { connection };
 
         Self {
             accounts,
+            invites,
+            link_previews,
+            mentions,
+            messages,
             rooms,
+            signup_codes,
             uploads,
+            webhooks,
         }
     }
 }
+
+/// Encodes values to bind as a single list, since there are no array
+/// parameters. Queries unpack it with `IN (SELECT value FROM json_each(?))`.
+pub(crate) fn json_list<T: serde::Serialize>(values: &[T]) -> sqlx::Result<String> {
+    serde_json::to_string(values).map_err(|error| sqlx::Error::Encode(error.into()))
+}
+
+/// A fresh in-memory database with every migration applied, for tests.
+#[cfg(test)]
+pub(crate) async fn test_pool() -> sqlx::SqlitePool {
+    // NOTE: Every connection to `:memory:` gets a database of its own, so
+    // there must only ever be the one.
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .min_connections(1)
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("database/migrations")
+        .run(&pool)
+        .await
+        .unwrap();
+    pool
+}
+
+/// A state backed by [`test_pool`], with the settings parsed from `args`.
+#[cfg(test)]
+pub(crate) async fn test_state(args: &[&str]) -> crate::state::SharedState {
+    use clap::Parser;
+
+    let settings =
+        crate::Settings::parse_from(std::iter::once("os3-chat").chain(args.iter().copied()));
+    crate::state::SharedState::new(test_pool().await, settings)
+}
@@ -1,31 +1,92 @@
-use std::fs::File;
-use std::io::Write;
 use std::path::Path;
 
 use axum::body::Bytes;
 use chrono::NaiveDateTime;
+use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use tracing::instrument;
 use uuid::Uuid;
 
 use super::account::Account;
 use super::message::Message;
-use super::upload::Upload;
+use super::upload::{self, FileUploadError, Upload};
+use crate::markdown;
+
+/// What a member is allowed to do in a room, beyond chatting.
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    Owner,
+    Moderator,
+    Member,
+}
+
+impl MemberRole {
+    /// Owners and moderators look after the room's content, e.g. pins.
+    #[must_use]
+    pub const fn can_moderate(self) -> bool {
+        matches!(self, Self::Owner | Self::Moderator)
+    }
+}
+
+/// Who can find and enter a room without being invited.
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RoomVisibility {
+    /// Hidden from the directory, entered by invitation only.
+    Private,
+    /// Shown in the directory, but still entered by invitation only.
+    Listed,
+    /// Shown in the directory, and anyone can join.
+    Open,
+}
+
+impl std::fmt::Display for RoomVisibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Private => write!(f, "private"),
+            Self::Listed => write!(f, "listed"),
+            Self::Open => write!(f, "open"),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct Pin {
+    pub message_id: i64,
+    pub pinned_by: String,
+    pub pinned_at: NaiveDateTime,
+}
 
 #[derive(sqlx::FromRow, Clone, Debug)]
 pub struct Room {
     pub id: i64,
     pub name: String,
     pub created_at: NaiveDateTime,
+    pub attachment_retention_days: Option<i64>,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub archived_at: Option<NaiveDateTime>,
+    pub visibility: RoomVisibility,
 }
 
 impl Room {
+    #[must_use]
+    pub const fn is_archived(&self) -> bool {
+        self.archived_at.is_some()
+    }
+
     #[instrument(skip_all, fields(room.id = self.id, room.name = self.name), err(Debug))]
     pub async fn get_members(&self, connection: &SqlitePool) -> Result<Vec<Account>, sqlx::Error> {
         let query = sqlx::query_as!(
             Account,
             r#"
-                SELECT a.username, a.password_hash, a.registered_at
+                SELECT a.username, a.password_hash, a.registered_at, a.display_name, a.bio,
+                    a.avatar_upload_uuid, a.is_placeholder, a.is_bot
                 FROM accounts a
                 LEFT JOIN room_membership m
                 ON a.username = m.member
@@ -36,6 +97,56 @@ impl Room {
         query.fetch_all(connection).await
     }
 
+    #[instrument(skip_all, fields(room.id = self.id, username = username), err(Debug))]
+    pub async fn has_member(&self, connection: &SqlitePool, username: &str) -> sqlx::Result<bool> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM room_membership WHERE member = ? AND room_id = ?
+                ) AS "is_member!: bool"
+            "#,
+            username,
+            self.id
+        )
+        .fetch_one(connection)
+        .await
+    }
+
+    /// The member's role in this room, or `None` if they aren't a member.
+    #[instrument(skip_all, fields(room.id = self.id, username = username), err(Debug))]
+    pub async fn get_role(
+        &self,
+        connection: &SqlitePool,
+        username: &str,
+    ) -> sqlx::Result<Option<MemberRole>> {
+        sqlx::query_scalar!(
+            r#"SELECT role AS "role: MemberRole" FROM room_membership WHERE member = ? AND room_id = ?"#,
+            username,
+            self.id
+        )
+        .fetch_optional(connection)
+        .await
+    }
+
+    /// Returns `false` if `username` is not a member of this room.
+    #[instrument(skip_all, fields(room.id = self.id, username = username, role = ?role), err(Debug))]
+    pub async fn set_role(
+        &self,
+        connection: &SqlitePool,
+        username: &str,
+        role: MemberRole,
+    ) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE room_membership SET role = ? WHERE member = ? AND room_id = ?",
+            role,
+            username,
+            self.id
+        )
+        .execute(connection)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
     #[instrument(skip_all, fields(username = username), err(Debug))]
     pub async fn add_member(&self, connection: &SqlitePool, username: &str) -> sqlx::Result<()> {
         sqlx::query!(
@@ -60,11 +171,304 @@ impl Room {
         Ok(())
     }
 
+    /// Pins a message of this room. Returns `None` if it was already pinned,
+    /// or if the message is in another room.
+    #[instrument(skip_all, fields(room.id = self.id, message_id, pinned_by), err(Debug))]
+    pub async fn pin(
+        &self,
+        connection: &SqlitePool,
+        message_id: i64,
+        pinned_by: &str,
+    ) -> sqlx::Result<Option<Pin>> {
+        sqlx::query_as!(
+            Pin,
+            r#"
+                INSERT OR IGNORE INTO pinned_messages (room_id, message_id, pinned_by)
+                SELECT room_id, id, ? FROM messages WHERE id = ? AND room_id = ?
+                RETURNING message_id, pinned_by, pinned_at
+            "#,
+            pinned_by,
+            message_id,
+            self.id
+        )
+        .fetch_optional(connection)
+        .await
+    }
+
+    /// Returns `false` if the message wasn't pinned in the first place.
+    #[instrument(skip_all, fields(room.id = self.id, message_id), err(Debug))]
+    pub async fn unpin(&self, connection: &SqlitePool, message_id: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM pinned_messages WHERE room_id = ? AND message_id = ?",
+            self.id,
+            message_id
+        )
+        .execute(connection)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The room's pins, most recently pinned first.
+    #[instrument(skip_all, fields(room.id = self.id), err(Debug))]
+    pub async fn get_pins(&self, connection: &SqlitePool) -> sqlx::Result<Vec<Pin>> {
+        sqlx::query_as!(
+            Pin,
+            r#"
+                SELECT message_id, pinned_by, pinned_at FROM pinned_messages
+                WHERE room_id = ?
+                ORDER BY pinned_at DESC, message_id DESC
+            "#,
+            self.id
+        )
+        .fetch_all(connection)
+        .await
+    }
+
+    #[instrument(skip_all, fields(room.id = self.id, name), err(Debug))]
+    pub async fn rename(&self, connection: &SqlitePool, name: &str) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE rooms SET name = ? WHERE id = ?", name, self.id)
+            .execute(connection)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(room.id = self.id, topic = ?topic), err(Debug))]
+    pub async fn set_topic(
+        &self,
+        connection: &SqlitePool,
+        topic: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE rooms SET topic = ? WHERE id = ?", topic, self.id)
+            .execute(connection)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(room.id = self.id), err(Debug))]
+    pub async fn set_description(
+        &self,
+        connection: &SqlitePool,
+        description: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE rooms SET description = ? WHERE id = ?",
+            description,
+            self.id
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(room.id = self.id, visibility = %visibility), err(Debug))]
+    pub async fn set_visibility(
+        &self,
+        connection: &SqlitePool,
+        visibility: RoomVisibility,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE rooms SET visibility = ? WHERE id = ?",
+            visibility,
+            self.id
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Archives the room, or brings it back if `archived` is `false`.
+    #[instrument(skip_all, fields(room.id = self.id, archived), err(Debug))]
+    pub async fn set_archived(&self, connection: &SqlitePool, archived: bool) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE rooms
+                SET archived_at = CASE WHEN ? THEN COALESCE(archived_at, CURRENT_TIMESTAMP) END
+                WHERE id = ?
+            "#,
+            archived,
+            self.id
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(room.id = self.id, days = ?days), err(Debug))]
+    pub async fn set_attachment_retention(
+        &self,
+        connection: &SqlitePool,
+        days: Option<i64>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE rooms SET attachment_retention_days = ? WHERE id = ?",
+            days,
+            self.id
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Moves the member's read marker forward to `message_id`, also marking
+    /// their mentions up to that message as read. Returns `false` if the
+    /// marker didn't move, for example because the message is in another room.
+    #[instrument(skip_all, fields(room.id = self.id, member = member, message_id = message_id), err(Debug))]
+    pub async fn mark_read(
+        &self,
+        connection: &SqlitePool,
+        member: &str,
+        message_id: i64,
+    ) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+                UPDATE room_membership SET last_read_message_id = ?
+                WHERE member = ? AND room_id = ?
+                AND (last_read_message_id IS NULL OR last_read_message_id < ?)
+                AND EXISTS(SELECT 1 FROM messages WHERE id = ? AND room_id = ?)
+            "#,
+            message_id,
+            member,
+            self.id,
+            message_id,
+            message_id,
+            self.id,
+        )
+        .execute(connection)
+        .await?;
+
+        sqlx::query!(
+            r#"
+                UPDATE mentions SET read_at = CURRENT_TIMESTAMP
+                WHERE account = ? AND read_at IS NULL
+                AND message_id IN (SELECT id FROM messages WHERE room_id = ? AND id <= ?)
+            "#,
+            member,
+            self.id,
+            message_id,
+        )
+        .execute(connection)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Members whose read marker is at or past `message_id`.
+    #[instrument(skip_all, fields(room.id = self.id, message_id = message_id), err(Debug))]
+    pub async fn get_read_by(
+        &self,
+        connection: &SqlitePool,
+        message_id: i64,
+    ) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT member FROM room_membership
+                WHERE room_id = ? AND last_read_message_id >= ?
+                ORDER BY member
+            "#,
+            self.id,
+            message_id,
+        )
+        .fetch_all(connection)
+        .await
+    }
+
     #[instrument(skip_all, fields(room.id = self.id, room.name = self.name), err(Debug))]
     pub async fn get_messages(&self, connection: &SqlitePool) -> Result<Vec<Message>, sqlx::Error> {
-        sqlx::query_as!(Message, "SELECT * FROM messages WHERE room_id = ?", self.id)
-            .fetch_all(connection)
-            .await
+        sqlx::query_as!(
+            Message,
+            "SELECT * FROM messages WHERE room_id = ? ORDER BY sent_at, id",
+            self.id
+        )
+        .fetch_all(connection)
+        .await
+    }
+
+    /// All of the room's messages in the order they were sent, along with
+    /// what an export needs to know about their senders and attachments.
+    /// Rows are streamed rather than loaded all at once.
+    pub fn stream_export_rows<'a>(
+        &'a self,
+        connection: &'a SqlitePool,
+    ) -> impl Stream<Item = sqlx::Result<ExportRow>> + 'a {
+        sqlx::query!(
+            r#"
+                SELECT
+                    m.id, m.sender, m.room_id, m.text, m.sent_at, m.file_upload_uuid,
+                    m.reply_to, m.text_html, m.is_system,
+                    a.display_name AS "sender_display_name?",
+                    u.uuid AS "upload_uuid?",
+                    u.filename AS "upload_filename?",
+                    u.width AS "upload_width?",
+                    u.height AS "upload_height?",
+                    u.size AS "upload_size?",
+                    u.uploader AS "upload_uploader?",
+                    u.room_id AS "upload_room_id?",
+                    u.deleted_at AS "upload_deleted_at?",
+                    u.uploaded_at AS "upload_uploaded_at?"
+                FROM messages m
+                LEFT JOIN accounts a ON a.username = m.sender
+                LEFT JOIN file_uploads u ON u.uuid = m.file_upload_uuid
+                WHERE m.room_id = ?
+                ORDER BY m.sent_at, m.id
+            "#,
+            self.id
+        )
+        .fetch(connection)
+        .map_ok(|row| {
+            let upload = row
+                .upload_uuid
+                .zip(row.upload_filename)
+                .zip(row.upload_size)
+                .map(|((uuid, filename), size)| Upload {
+                    uuid,
+                    filename: filename.into(),
+                    width: row.upload_width,
+                    height: row.upload_height,
+                    size,
+                    uploader: row.upload_uploader,
+                    room_id: row.upload_room_id,
+                    deleted_at: row.upload_deleted_at,
+                    uploaded_at: row.upload_uploaded_at,
+                });
+            ExportRow {
+                message: Message {
+                    id: row.id,
+                    sender: row.sender,
+                    room_id: row.room_id,
+                    text: row.text,
+                    sent_at: row.sent_at,
+                    file_upload_uuid: row.file_upload_uuid,
+                    reply_to: row.reply_to,
+                    text_html: row.text_html,
+                    is_system: row.is_system,
+                },
+                sender_display_name: row.sender_display_name,
+                upload,
+            }
+        })
+    }
+
+    /// Up to `limit` of the room's latest messages, or of those before the
+    /// message with the ID `before`. Oldest first, going by their IDs.
+    #[instrument(skip_all, fields(room.id = self.id, before, limit), err(Debug))]
+    pub async fn get_messages_before(
+        &self,
+        connection: &SqlitePool,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        let before = before.unwrap_or(i64::MAX);
+        let mut messages = sqlx::query_as!(
+            Message,
+            "SELECT * FROM messages WHERE room_id = ? AND id < ? ORDER BY id DESC LIMIT ?",
+            self.id,
+            before,
+            limit
+        )
+        .fetch_all(connection)
+        .await?;
+        messages.reverse();
+        Ok(messages)
     }
 
     #[instrument(skip(self, connection, text), err(Debug))]
@@ -73,13 +477,45 @@ impl Room {
         connection: &SqlitePool,
         sender: &str,
         text: Option<String>,
+        reply_to: Option<i64>,
     ) -> Result<Message, sqlx::Error> {
+        let text_html = text.as_deref().map(markdown::render);
         let query = sqlx::query_as!(
             Message,
-            "INSERT INTO messages (sender, room_id, text) VALUES (?, ?, ?) RETURNING *",
+            r#"
+                INSERT INTO messages (sender, room_id, text, text_html, reply_to)
+                VALUES (?, ?, ?, ?, ?)
+                RETURNING *
+            "#,
             sender,
             self.id,
             text,
+            text_html,
+            reply_to,
+        );
+        let message = query.fetch_one(connection).await?;
+        message.record_mentions(connection).await?;
+        Ok(message)
+    }
+
+    /// Records a change made to the room by `actor` in its timeline.
+    #[instrument(skip(self, connection), err(Debug))]
+    pub async fn send_system_message(
+        &self,
+        connection: &SqlitePool,
+        actor: &str,
+        text: &str,
+    ) -> Result<Message, sqlx::Error> {
+        let query = sqlx::query_as!(
+            Message,
+            r#"
+                INSERT INTO messages (sender, room_id, text, is_system)
+                VALUES (?, ?, ?, TRUE)
+                RETURNING *
+            "#,
+            actor,
+            self.id,
+            text,
         );
         query.fetch_one(connection).await
     }
@@ -93,55 +529,62 @@ impl Room {
         file_uuid: Uuid,
     ) -> Result<Message, sqlx::Error> {
         let uuid_str = file_uuid.to_string();
+        let text_html = text.as_deref().map(markdown::render);
         let query = sqlx::query_as!(
             Message,
-            "INSERT INTO messages (sender, room_id, text, file_upload_uuid) VALUES (?, ?, ?, ?) RETURNING *",
+            r#"
+                INSERT INTO messages (sender, room_id, text, text_html, file_upload_uuid)
+                VALUES (?, ?, ?, ?, ?)
+                RETURNING *
+            "#,
             sender,
             self.id,
             text,
+            text_html,
             uuid_str,
         );
         query.fetch_one(connection).await
     }
 
-    #[instrument(skip_all, fields(filename = ?filename), err(Debug))]
-    pub async fn upload(
+    /// Adds a message brought over from elsewhere, keeping its original time.
+    /// Unlike new messages, it doesn't notify anyone it mentions.
+    #[instrument(skip_all, fields(room.id = self.id, sender = message.sender), err(Debug))]
+    pub async fn import_message(
         &self,
         connection: &SqlitePool,
-        filename: &Path,
-        data: &Bytes,
-    ) -> Result<Upload, FileUploadError> {
-        const DEFAULT_STORE_DIRECTORY: &str = "database/file_uploads/";
-
-        let uuid = Uuid::new_v4();
-        let filename = filename.to_string_lossy();
-        let store_path = format!("{DEFAULT_STORE_DIRECTORY}/{uuid}_{filename}");
-
-        let mut store_file = File::create(&store_path)
-            .inspect(|_| tracing::debug!(store_path, "Created store file"))
-            .inspect_err(|error| tracing::error!(?error, "Failed to create store file"))?;
-        store_file
-            .write_all(data)
-            .inspect(|()| tracing::debug!(store_path, "Wrote to store path"))
-            .inspect_err(|error| tracing::error!(?error, "Failed to write to store path"))?;
-
-        let uuid_string = uuid.to_string();
-
-        let upload = sqlx::query_as!(
-            Upload,
+        message: ImportedMessage<'_>,
+    ) -> Result<Message, sqlx::Error> {
+        let text_html = message.text.map(markdown::render);
+        sqlx::query_as!(
+            Message,
             r#"
-                INSERT INTO file_uploads (uuid, filename)
-                VALUES (?, ?)
+                INSERT INTO messages
+                    (sender, room_id, text, text_html, sent_at, reply_to, file_upload_uuid, is_system)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
                 RETURNING *
             "#,
-            uuid_string,
-            filename,
+            message.sender,
+            self.id,
+            message.text,
+            text_html,
+            message.sent_at,
+            message.reply_to,
+            message.file_upload_uuid,
+            message.is_system,
         )
         .fetch_one(connection)
         .await
-        .map_err(FileUploadError::Database)?;
+    }
 
-        Ok(upload)
+    #[instrument(skip_all, fields(filename = ?filename, uploader = uploader), err(Debug))]
+    pub async fn upload(
+        &self,
+        connection: &SqlitePool,
+        uploader: &str,
+        filename: &Path,
+        data: &Bytes,
+    ) -> Result<Upload, FileUploadError> {
+        upload::store(connection, uploader, filename, data, Some(self.id)).await
     }
 }
 
@@ -156,18 +599,41 @@ impl RoomRepository {
     pub async fn create(&self, name: &str) -> Result<Room, sqlx::Error> {
         sqlx::query_as!(
             Room,
-            "INSERT INTO rooms (name) VALUES (?) RETURNING *",
+            r#"
+                INSERT INTO rooms (name) VALUES (?)
+                RETURNING
+                    id, name, created_at, attachment_retention_days, topic, description,
+                    archived_at, visibility AS "visibility: _"
+            "#,
             name
         )
         .fetch_one(&self.connection)
         .await
     }
 
+    /// Deletes the room along with its messages and memberships.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn delete(&self, room_id: i64) -> sqlx::Result<()> {
+        sqlx::query!("DELETE FROM rooms WHERE id = ?", room_id)
+            .execute(&self.connection)
+            .await?;
+        Ok(())
+    }
+
     #[instrument(skip(self), err(Debug))]
     pub async fn find_by_id(&self, room_id: i64) -> Result<Option<Room>, sqlx::Error> {
-        sqlx::query_as!(Room, "SELECT * FROM rooms WHERE id = ?", room_id)
-            .fetch_optional(&self.connection)
-            .await
+        sqlx::query_as!(
+            Room,
+            r#"
+                SELECT
+                    id, name, created_at, attachment_retention_days, topic, description,
+                    archived_at, visibility AS "visibility: _"
+                FROM rooms WHERE id = ?
+            "#,
+            room_id
+        )
+        .fetch_optional(&self.connection)
+        .await
     }
 
     #[instrument(skip(self), err(Debug))]
@@ -175,7 +641,9 @@ impl RoomRepository {
         sqlx::query_as!(
             Room,
             r#"
-                SELECT id, name, created_at
+                SELECT
+                    id, name, created_at, attachment_retention_days, topic, description,
+                    archived_at, visibility AS "visibility: _"
                 FROM rooms r
                 LEFT JOIN room_membership m ON r.id = m.room_id
                 WHERE m.member = ?
@@ -185,11 +653,182 @@ impl RoomRepository {
         .fetch_all(&self.connection)
         .await
     }
+
+    /// The rooms `member` is in, with what they are allowed to do there.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_memberships(&self, member: &str) -> Result<Vec<Membership>, sqlx::Error> {
+        sqlx::query_as!(
+            Membership,
+            r#"
+                SELECT r.id AS room_id, r.name AS room_name, m.role AS "role: _", m.joined_at
+                FROM room_membership m
+                JOIN rooms r ON r.id = m.room_id
+                WHERE m.member = ?
+                ORDER BY m.joined_at, r.id
+            "#,
+            member
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Rooms shown in the directory whose name or topic contains `search`,
+    /// biggest first. Archived rooms are left out.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_listed(
+        &self,
+        search: &str,
+        member: &str,
+        limit: i64,
+    ) -> Result<Vec<ListedRoom>, sqlx::Error> {
+        let escaped = search
+            .replace('!', "!!")
+            .replace('%', "!%")
+            .replace('_', "!_");
+        let pattern = format!("%{escaped}%");
+        sqlx::query_as!(
+            ListedRoom,
+            r#"
+                SELECT
+                    r.id,
+                    r.name,
+                    r.topic,
+                    r.visibility AS "visibility: RoomVisibility",
+                    (SELECT COUNT(*) FROM room_membership m WHERE m.room_id = r.id) AS "member_count!: i64",
+                    EXISTS(
+                        SELECT 1 FROM room_membership m WHERE m.room_id = r.id AND m.member = ?
+                    ) AS "is_member!: bool"
+                FROM rooms r
+                WHERE r.visibility != 'private' AND r.archived_at IS NULL
+                AND (r.name LIKE ? ESCAPE '!' OR r.topic LIKE ? ESCAPE '!')
+                ORDER BY 5 DESC, r.id -- i.e. by member count
+                LIMIT ?
+            "#,
+            member,
+            pattern,
+            pattern,
+            limit
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Unread messages and mentions in each room `member` is in. Messages sent
+    /// by the member themselves never count as unread.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_activity(&self, member: &str) -> Result<Vec<RoomActivity>, sqlx::Error> {
+        sqlx::query_as!(
+            RoomActivity,
+            r#"
+                SELECT
+                    rm.room_id AS "room_id!",
+                    (
+                        SELECT COUNT(*) FROM messages m
+                        WHERE m.room_id = rm.room_id AND m.sender != rm.member
+                        AND m.id > COALESCE(rm.last_read_message_id, 0)
+                    ) AS "unread_count!: i64",
+                    (
+                        SELECT COUNT(*) FROM mentions n
+                        JOIN messages m ON m.id = n.message_id
+                        WHERE m.room_id = rm.room_id AND n.account = rm.member
+                        AND n.read_at IS NULL
+                    ) AS "mention_count!: i64"
+                FROM room_membership rm
+                WHERE rm.member = ?
+            "#,
+            member
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+}
+
+/// A message along with its sender's display name and its attachment, see
+/// [`Room::stream_export_rows`].
+#[derive(Debug)]
+#[must_use]
+pub struct ExportRow {
+    pub message: Message,
+    pub sender_display_name: Option<String>,
+    pub upload: Option<Upload>,
 }
 
-#[derive(thiserror::Error, Debug)]
-#[error(transparent)]
-pub enum FileUploadError {
-    Io(#[from] std::io::Error),
-    Database(sqlx::Error),
+/// A message as it was sent somewhere else, see [`Room::import_message`].
+#[derive(Clone, Copy, Debug)]
+#[must_use]
+pub struct ImportedMessage<'a> {
+    pub sender: &'a str,
+    pub text: Option<&'a str>,
+    pub sent_at: NaiveDateTime,
+    pub reply_to: Option<i64>,
+    pub file_upload_uuid: Option<&'a str>,
+    pub is_system: bool,
+}
+
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+#[must_use]
+pub struct Membership {
+    pub room_id: i64,
+    pub room_name: String,
+    pub role: MemberRole,
+    pub joined_at: NaiveDateTime,
+}
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct ListedRoom {
+    pub id: i64,
+    pub name: String,
+    pub topic: Option<String>,
+    pub visibility: RoomVisibility,
+    pub member_count: i64,
+    pub is_member: bool,
+}
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct RoomActivity {
+    pub room_id: i64,
+    pub unread_count: i64,
+    pub mention_count: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::{self, Repository};
+
+    #[tokio::test]
+    async fn read_markers_outlive_their_message() {
+        let pool = repository::test_pool().await;
+        let repository = Repository::new(pool.clone());
+        for username in ["alice", "bob"] {
+            repository
+                .accounts
+                .create_placeholder(username, None)
+                .await
+                .unwrap();
+        }
+        let room = repository.rooms.create("general").await.unwrap();
+        room.add_member(&pool, "alice").await.unwrap();
+        room.add_member(&pool, "bob").await.unwrap();
+
+        let mut sent = vec![];
+        for text in ["one", "two", "three"] {
+            let message = room
+                .send_new_message(&pool, "bob", Some(text.to_string()), None)
+                .await
+                .unwrap();
+            sent.push(message.id);
+        }
+        assert!(room.mark_read(&pool, "alice", sent[1]).await.unwrap());
+        repository.messages.delete(sent[1]).await.unwrap();
+
+        let activity = repository.rooms.find_activity("alice").await.unwrap();
+        let [activity] = &activity[..] else {
+            panic!("expected activity in a single room, got {activity:?}");
+        };
+        assert_eq!(activity.room_id, room.id);
+        assert_eq!(activity.unread_count, 1);
+        assert_eq!(room.get_read_by(&pool, sent[0]).await.unwrap(), ["alice"]);
+    }
 }
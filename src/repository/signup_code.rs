@@ -0,0 +1,47 @@
+use chrono::NaiveDateTime;
+use sqlx::SqlitePool;
+use tracing::instrument;
+
+use super::invite::generate_code;
+
+/// A single-use code that lets someone register while registration is
+/// restricted to invited people.
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct SignupCode {
+    pub code: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub used_by: Option<String>,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct SignupCodeRepository {
+    pub(super) connection: SqlitePool,
+}
+
+impl SignupCodeRepository {
+    #[instrument(skip(self), err(Debug))]
+    pub async fn create(&self, created_by: &str) -> sqlx::Result<SignupCode> {
+        let code = generate_code();
+        let query = sqlx::query_as!(
+            SignupCode,
+            "INSERT INTO signup_codes (code, created_by) VALUES (?, ?) RETURNING *",
+            code,
+            created_by,
+        );
+        query.fetch_one(&self.connection).await
+    }
+
+    /// All codes ever minted, newest first.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_all(&self) -> sqlx::Result<Vec<SignupCode>> {
+        let query = sqlx::query_as!(
+            SignupCode,
+            "SELECT * FROM signup_codes ORDER BY created_at DESC, rowid DESC"
+        );
+        query.fetch_all(&self.connection).await
+    }
+}
@@ -1,24 +1,199 @@
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use axum::body::Bytes;
+use chrono::NaiveDateTime;
+use image::{ImageFormat, ImageReader};
 use sqlx::SqlitePool;
+use tracing::instrument;
 use uuid::Uuid;
 
+use super::json_list;
+
+pub const STORE_DIRECTORY: &str = "./database/file_uploads";
+pub const THUMBNAIL_DIRECTORY: &str = "./database/thumbnails";
+
+/// Bounding box sizes (in pixels) that thumbnails are generated for. Requests
+/// for other sizes are served the next larger one, so that the cache stays
+/// bounded no matter what clients ask for.
+pub const THUMBNAIL_SIZES: [u32; 4] = [64, 128, 256, 512];
+
 #[derive(sqlx::FromRow, Debug)]
 #[must_use]
 pub struct Upload {
     pub uuid: String,
     pub filename: PathBuf,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub size: i64,
+    pub uploader: Option<String>,
+    pub room_id: Option<i64>,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub uploaded_at: Option<NaiveDateTime>,
 }
 
 impl Upload {
     pub fn store_path(&self) -> Result<PathBuf, std::io::Error> {
         let path_str = format!(
-            "./database/file_uploads/{}_{}",
+            "{STORE_DIRECTORY}/{}_{}",
             self.uuid,
             self.filename.to_string_lossy()
         );
         PathBuf::from(path_str).canonicalize()
     }
+
+    #[must_use]
+    pub const fn is_image(&self) -> bool {
+        self.width.is_some() && self.height.is_some()
+    }
+
+    /// Whether the stored file is gone and only the tombstone row is left.
+    #[must_use]
+    pub const fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Removes the stored file and all of its cached thumbnails from disk.
+    /// Files that are already missing are not an error.
+    #[instrument(skip(self), fields(upload.uuid = self.uuid), err(Debug))]
+    pub async fn remove_files(&self) -> Result<(), std::io::Error> {
+        let ignore_missing = |result: std::io::Result<()>| match result {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        };
+
+        if let Ok(store_path) = self.store_path() {
+            ignore_missing(tokio::fs::remove_file(store_path).await)?;
+        }
+        for size in THUMBNAIL_SIZES {
+            let thumbnail_path = format!("{THUMBNAIL_DIRECTORY}/{}_{size}.png", self.uuid);
+            ignore_missing(tokio::fs::remove_file(thumbnail_path).await)?;
+        }
+
+        tracing::debug!("Removed stored files");
+        Ok(())
+    }
+
+    /// Returns the path to a PNG thumbnail of this upload fitting into a
+    /// `size`x`size` box, generating and caching it on first use.
+    #[instrument(skip(self), fields(upload.uuid = self.uuid), err(Debug))]
+    pub async fn thumbnail(&self, size: u32) -> Result<PathBuf, ThumbnailError> {
+        if self.is_deleted() {
+            return Err(ThumbnailError::Deleted);
+        }
+        if !self.is_image() {
+            return Err(ThumbnailError::NotAnImage);
+        }
+
+        let size = THUMBNAIL_SIZES
+            .into_iter()
+            .find(|&supported| supported >= size)
+            .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1]);
+        let thumbnail_path =
+            PathBuf::from(format!("{THUMBNAIL_DIRECTORY}/{}_{size}.png", self.uuid));
+        if tokio::fs::try_exists(&thumbnail_path).await? {
+            tracing::trace!(?thumbnail_path, "Thumbnail is cached");
+            return Ok(thumbnail_path);
+        }
+
+        let store_path = self.store_path()?;
+        let cache_path = thumbnail_path.clone();
+        let uuid = self.uuid.clone();
+        tokio::task::spawn_blocking(move || -> Result<(), ThumbnailError> {
+            let original = ImageReader::open(store_path)?
+                .with_guessed_format()?
+                .decode()?;
+            // NOTE: The thumbnail is only moved into place once it is complete,
+            // so that a concurrent request or a crash never leaves a truncated
+            // one behind to be served from then on.
+            let mut partial = tempfile::Builder::new()
+                .prefix(&format!("{uuid}_{size}."))
+                .suffix(".partial")
+                .tempfile_in(THUMBNAIL_DIRECTORY)?;
+            original
+                .thumbnail(size, size)
+                .write_to(&mut partial, ImageFormat::Png)?;
+            partial.persist(cache_path).map_err(|error| error.error)?;
+            Ok(())
+        })
+        .await
+        .map_err(|_| ThumbnailError::Aborted)??;
+
+        tracing::debug!(?thumbnail_path, "Generated new thumbnail");
+        Ok(thumbnail_path)
+    }
+}
+
+/// Reads the pixel dimensions of `data` if it is an image in a supported
+/// format, without decoding the whole thing.
+#[must_use]
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Writes `data` to the upload store and records it, in `room_id` unless it
+/// doesn't belong to any room (like an avatar).
+#[instrument(skip_all, fields(filename = ?filename, uploader = uploader), err(Debug))]
+pub(super) async fn store(
+    connection: &SqlitePool,
+    uploader: &str,
+    filename: &Path,
+    data: &Bytes,
+    room_id: Option<i64>,
+) -> Result<Upload, FileUploadError> {
+    let uuid = Uuid::new_v4();
+    let filename = filename.to_string_lossy();
+    let store_path = format!("{STORE_DIRECTORY}/{uuid}_{filename}");
+
+    let mut store_file = File::create(&store_path)
+        .inspect(|_| tracing::debug!(store_path, "Created store file"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to create store file"))?;
+    store_file
+        .write_all(data)
+        .inspect(|()| tracing::debug!(store_path, "Wrote to store path"))
+        .inspect_err(|error| tracing::error!(?error, "Failed to write to store path"))?;
+
+    let uuid_string = uuid.to_string();
+    let size = i64::try_from(data.len()).unwrap_or(i64::MAX);
+    let (width, height) = match image_dimensions(data) {
+        Some((width, height)) => (Some(width), Some(height)),
+        None => (None, None),
+    };
+
+    let upload = sqlx::query_as!(
+        Upload,
+        r#"
+            INSERT INTO file_uploads (uuid, filename, width, height, size, uploader, room_id, uploaded_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP)
+            RETURNING *
+        "#,
+        uuid_string,
+        filename,
+        width,
+        height,
+        size,
+        uploader,
+        room_id,
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(FileUploadError::Database);
+
+    if upload.is_err() {
+        // NOTE: Don't leave a file behind that no row points to. Should this
+        // fail as well, the upload reconciler will pick it up later.
+        let _ = std::fs::remove_file(&store_path)
+            .inspect(|()| tracing::debug!(store_path, "Removed store file"))
+            .inspect_err(|error| tracing::warn!(?error, "Failed to remove store file"));
+    }
+
+    upload
 }
 
 #[derive(Debug, Clone)]
@@ -28,6 +203,16 @@ pub struct UploadRepository {
 }
 
 impl UploadRepository {
+    /// Stores an upload that doesn't belong to any room, such as an avatar.
+    pub async fn create_unattached(
+        &self,
+        uploader: &str,
+        filename: &Path,
+        data: &Bytes,
+    ) -> Result<Upload, FileUploadError> {
+        self::store(&self.connection, uploader, filename, data, None).await
+    }
+
     pub async fn find(&self, uuid: Uuid) -> Result<Option<Upload>, sqlx::Error> {
         let uuid_str = uuid.to_string();
         sqlx::query_as!(
@@ -38,4 +223,213 @@ impl UploadRepository {
         .fetch_optional(&self.connection)
         .await
     }
+
+    /// The uploads with the given UUIDs, in no particular order.
+    #[instrument(skip_all, fields(count = uuids.len()), err(Debug))]
+    pub async fn find_by_uuids(&self, uuids: &[&str]) -> Result<Vec<Upload>, sqlx::Error> {
+        let uuids = json_list(uuids)?;
+        sqlx::query_as!(
+            Upload,
+            "SELECT * FROM file_uploads WHERE uuid IN (SELECT value FROM json_each(?))",
+            uuids
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_all(&self) -> Result<Vec<Upload>, sqlx::Error> {
+        sqlx::query_as!(Upload, "SELECT * FROM file_uploads")
+            .fetch_all(&self.connection)
+            .await
+    }
+
+    /// Live uploads made by `uploader`, whether in a room or not.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_by_uploader(&self, uploader: &str) -> Result<Vec<Upload>, sqlx::Error> {
+        sqlx::query_as!(
+            Upload,
+            "SELECT * FROM file_uploads WHERE uploader = ? AND deleted_at IS NULL",
+            uploader
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Uploads that no message or profile refers to anymore, for example
+    /// because the message was removed along with its room, or the avatar was
+    /// replaced. Uploads stored less than `grace_period` ago are left out, as
+    /// whatever refers to them may not be saved yet.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_unreferenced(
+        &self,
+        grace_period: Duration,
+    ) -> Result<Vec<Upload>, sqlx::Error> {
+        let cutoff = format!("-{} seconds", grace_period.as_secs());
+        sqlx::query_as!(
+            Upload,
+            r#"
+                SELECT f.* FROM file_uploads f
+                WHERE NOT EXISTS (SELECT 1 FROM messages m WHERE m.file_upload_uuid = f.uuid)
+                AND NOT EXISTS (SELECT 1 FROM accounts a WHERE a.avatar_upload_uuid = f.uuid)
+                AND (f.uploaded_at IS NULL OR f.uploaded_at < datetime('now', ?))
+            "#,
+            cutoff
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Whether the upload is someone's avatar.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn is_avatar(&self, uuid: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM accounts WHERE avatar_upload_uuid = ?
+                ) AS "is_avatar!: bool"
+            "#,
+            uuid
+        )
+        .fetch_one(&self.connection)
+        .await
+    }
+
+    /// Live uploads that are older than the attachment retention period of
+    /// the room they were sent to.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_expired(&self) -> Result<Vec<Upload>, sqlx::Error> {
+        sqlx::query_as!(
+            Upload,
+            r#"
+                SELECT f.* FROM file_uploads f
+                JOIN messages m ON m.file_upload_uuid = f.uuid
+                JOIN rooms r ON r.id = m.room_id
+                WHERE f.deleted_at IS NULL
+                AND r.attachment_retention_days IS NOT NULL
+                AND m.sent_at < datetime('now', '-' || r.attachment_retention_days || ' days')
+            "#
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Marks the upload as deleted, keeping its row for the messages that
+    /// refer to it.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn tombstone(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE file_uploads SET deleted_at = CURRENT_TIMESTAMP WHERE uuid = ?",
+            uuid
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Records the size of an upload stored before sizes were, so that it
+    /// counts against storage quotas.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn fill_in_size(&self, uuid: &str, size: i64) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE file_uploads SET size = ? WHERE uuid = ? AND size = 0",
+            size,
+            uuid
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes the upload's row entirely. Note that this also removes any
+    /// messages that still refer to it.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn delete(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM file_uploads WHERE uuid = ?", uuid)
+            .execute(&self.connection)
+            .await?;
+        Ok(())
+    }
+
+    /// Total size of all files uploaded by `username`, in bytes.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn usage_by_account(&self, username: &str) -> Result<u64, sqlx::Error> {
+        let used = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(size), 0) AS "used!: i64" FROM file_uploads WHERE uploader = ? AND deleted_at IS NULL"#,
+            username
+        )
+        .fetch_one(&self.connection)
+        .await?;
+        Ok(u64::try_from(used).unwrap_or_default())
+    }
+
+    /// Total size of all files uploaded into the room, in bytes.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn usage_by_room(&self, room_id: i64) -> Result<u64, sqlx::Error> {
+        let used = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(size), 0) AS "used!: i64" FROM file_uploads WHERE room_id = ? AND deleted_at IS NULL"#,
+            room_id
+        )
+        .fetch_one(&self.connection)
+        .await?;
+        Ok(u64::try_from(used).unwrap_or_default())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error(transparent)]
+pub enum FileUploadError {
+    Io(#[from] std::io::Error),
+    Database(sqlx::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ThumbnailError {
+    #[error("The upload is not an image")]
+    NotAnImage,
+
+    #[error("The upload has been deleted")]
+    Deleted,
+
+    #[error("Thumbnail generation was aborted")]
+    Aborted,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository;
+
+    #[tokio::test]
+    async fn fresh_uploads_are_not_unreferenced_yet() {
+        let pool = repository::test_pool().await;
+        let repository = UploadRepository {
+            connection: pool.clone(),
+        };
+        sqlx::query(
+            "INSERT INTO file_uploads (uuid, filename, uploaded_at) VALUES ('fresh', 'a.txt', CURRENT_TIMESTAMP)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO file_uploads (uuid, filename) VALUES ('legacy', 'b.txt')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let unreferenced: Vec<String> = repository
+            .find_unreferenced(Duration::from_secs(15 * 60))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|upload| upload.uuid)
+            .collect();
+        assert_eq!(unreferenced, ["legacy"]);
+    }
 }
@@ -0,0 +1,311 @@
+use chrono::NaiveDateTime;
+use sqlx::SqlitePool;
+use tracing::instrument;
+
+use super::invite::{generate_code, generate_secret};
+
+/// Long enough for the token to be unguessable.
+const TOKEN_LENGTH: usize = 32;
+
+/// A URL that outside services post messages into a room through.
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct IncomingWebhook {
+    pub id: i64,
+    pub room_id: i64,
+    pub name: String,
+    pub token: String,
+    pub bot_username: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// An outside URL that the room's events are delivered to.
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct OutgoingWebhook {
+    pub id: i64,
+    pub room_id: i64,
+    pub url: String,
+    pub secret: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// A single event on its way to an outgoing webhook.
+#[derive(sqlx::FromRow, Clone, Debug)]
+#[must_use]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i64,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+    /// Set once the delivery has been given up on.
+    pub failed_at: Option<NaiveDateTime>,
+}
+
+impl IncomingWebhook {
+    /// A fresh name for the bot account a new webhook posts as.
+    #[must_use]
+    pub fn new_bot_username() -> String {
+        format!("webhook-{}", generate_code())
+    }
+
+    #[must_use]
+    pub fn url(&self) -> String {
+        format!("/hooks/{}", self.token)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct WebhookRepository {
+    pub(super) connection: SqlitePool,
+}
+
+impl WebhookRepository {
+    /// Creates a webhook posting as `bot_username`, which must already exist.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn create_incoming(
+        &self,
+        room_id: i64,
+        name: &str,
+        bot_username: &str,
+        created_by: &str,
+    ) -> sqlx::Result<IncomingWebhook> {
+        let token = generate_secret(TOKEN_LENGTH);
+        let query = sqlx::query_as!(
+            IncomingWebhook,
+            r#"
+                INSERT INTO incoming_webhooks (room_id, name, token, bot_username, created_by)
+                VALUES (?, ?, ?, ?, ?)
+                RETURNING *
+            "#,
+            room_id,
+            name,
+            token,
+            bot_username,
+            created_by,
+        );
+        query.fetch_one(&self.connection).await
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_incoming(&self, id: i64) -> sqlx::Result<Option<IncomingWebhook>> {
+        sqlx::query_as!(
+            IncomingWebhook,
+            "SELECT * FROM incoming_webhooks WHERE id = ?",
+            id
+        )
+        .fetch_optional(&self.connection)
+        .await
+    }
+
+    #[instrument(skip_all, err(Debug))]
+    pub async fn find_incoming_by_token(
+        &self,
+        token: &str,
+    ) -> sqlx::Result<Option<IncomingWebhook>> {
+        sqlx::query_as!(
+            IncomingWebhook,
+            "SELECT * FROM incoming_webhooks WHERE token = ?",
+            token
+        )
+        .fetch_optional(&self.connection)
+        .await
+    }
+
+    /// The room's webhooks, oldest first.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_incoming_by_room(&self, room_id: i64) -> sqlx::Result<Vec<IncomingWebhook>> {
+        sqlx::query_as!(
+            IncomingWebhook,
+            "SELECT * FROM incoming_webhooks WHERE room_id = ? ORDER BY id",
+            room_id
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Deletes the webhook, leaving the messages it posted in place.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn delete_incoming(&self, id: i64) -> sqlx::Result<()> {
+        sqlx::query!("DELETE FROM incoming_webhooks WHERE id = ?", id)
+            .execute(&self.connection)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    pub async fn create_outgoing(
+        &self,
+        room_id: i64,
+        url: &str,
+        created_by: &str,
+    ) -> sqlx::Result<OutgoingWebhook> {
+        let secret = generate_secret(TOKEN_LENGTH);
+        let query = sqlx::query_as!(
+            OutgoingWebhook,
+            r#"
+                INSERT INTO outgoing_webhooks (room_id, url, secret, created_by)
+                VALUES (?, ?, ?, ?)
+                RETURNING *
+            "#,
+            room_id,
+            url,
+            secret,
+            created_by,
+        );
+        query.fetch_one(&self.connection).await
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_outgoing(&self, id: i64) -> sqlx::Result<Option<OutgoingWebhook>> {
+        sqlx::query_as!(
+            OutgoingWebhook,
+            "SELECT * FROM outgoing_webhooks WHERE id = ?",
+            id
+        )
+        .fetch_optional(&self.connection)
+        .await
+    }
+
+    /// The room's outgoing webhooks, oldest first.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_outgoing_by_room(&self, room_id: i64) -> sqlx::Result<Vec<OutgoingWebhook>> {
+        sqlx::query_as!(
+            OutgoingWebhook,
+            "SELECT * FROM outgoing_webhooks WHERE room_id = ? ORDER BY id",
+            room_id
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Deletes the webhook along with its pending and failed deliveries.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn delete_outgoing(&self, id: i64) -> sqlx::Result<()> {
+        sqlx::query!("DELETE FROM outgoing_webhooks WHERE id = ?", id)
+            .execute(&self.connection)
+            .await?;
+        Ok(())
+    }
+
+    /// Queues the event for every outgoing webhook of the room. Returns how
+    /// many deliveries were queued.
+    #[instrument(skip(self, payload), err(Debug))]
+    pub async fn enqueue_deliveries(
+        &self,
+        room_id: i64,
+        event: &str,
+        payload: &str,
+    ) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+                INSERT INTO webhook_deliveries (webhook_id, event, payload)
+                SELECT id, ?, ? FROM outgoing_webhooks WHERE room_id = ?
+            "#,
+            event,
+            payload,
+            room_id,
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Pending deliveries whose next attempt is due, oldest first.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_due_deliveries(&self, limit: i64) -> sqlx::Result<Vec<WebhookDelivery>> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"
+                SELECT * FROM webhook_deliveries
+                WHERE delivered_at IS NULL AND failed_at IS NULL
+                AND next_attempt_at <= CURRENT_TIMESTAMP
+                ORDER BY id
+                LIMIT ?
+            "#,
+            limit
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    pub async fn mark_delivered(&self, id: i64) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE webhook_deliveries
+                SET attempts = attempts + 1, delivered_at = CURRENT_TIMESTAMP, last_error = NULL
+                WHERE id = ?
+            "#,
+            id
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Records a failed attempt, to be retried at `next_attempt_at`, or never
+    /// again if that is `None`.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn record_failure(
+        &self,
+        id: i64,
+        error: &str,
+        next_attempt_at: Option<NaiveDateTime>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE webhook_deliveries
+                SET attempts = attempts + 1, last_error = ?,
+                    next_attempt_at = COALESCE(?, next_attempt_at),
+                    failed_at = CASE WHEN ? IS NULL THEN CURRENT_TIMESTAMP END
+                WHERE id = ?
+            "#,
+            error,
+            next_attempt_at,
+            next_attempt_at,
+            id,
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes deliveries that went through before `cutoff`. Returns how many
+    /// there were.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn delete_delivered_before(&self, cutoff: NaiveDateTime) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM webhook_deliveries WHERE delivered_at < ?",
+            cutoff
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Deliveries to the room's webhooks that were given up on, newest first.
+    #[instrument(skip(self), err(Debug))]
+    pub async fn find_dead_letters(&self, room_id: i64) -> sqlx::Result<Vec<WebhookDelivery>> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"
+                SELECT d.* FROM webhook_deliveries d
+                JOIN outgoing_webhooks w ON w.id = d.webhook_id
+                WHERE w.room_id = ? AND d.failed_at IS NOT NULL
+                ORDER BY d.failed_at DESC, d.id DESC
+            "#,
+            room_id
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+}
@@ -0,0 +1,317 @@
+//! Changes to a room that can be made both through its endpoint and through a
+//! slash command, so that either way they are checked, recorded in the
+//! room's timeline and broadcast alike.
+
+use axum::http::StatusCode;
+use tracing::instrument;
+
+use crate::endpoints::chat::{self, RoomEvent};
+use crate::endpoints::rooms;
+use crate::repository::room::{MemberRole, Room};
+use crate::state::SharedState;
+
+/// Topics are limited to this many characters.
+pub const MAX_TOPIC_CHARS: u64 = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RoomActionError {
+    #[error("{0} is not a member of this room")]
+    NotAMember(String),
+    #[error("there is no account named {0}")]
+    NoSuchAccount(String),
+    #[error("{0}")]
+    Forbidden(&'static str),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("something went wrong, try again later")]
+    Internal,
+}
+
+impl From<sqlx::Error> for RoomActionError {
+    fn from(error: sqlx::Error) -> Self {
+        tracing::error!(?error, "Room action failed");
+        Self::Internal
+    }
+}
+
+impl From<RoomActionError> for StatusCode {
+    fn from(error: RoomActionError) -> Self {
+        match error {
+            RoomActionError::NotAMember(_) | RoomActionError::NoSuchAccount(_) => Self::NOT_FOUND,
+            RoomActionError::Forbidden(_) => Self::FORBIDDEN,
+            RoomActionError::Conflict(_) => Self::CONFLICT,
+            RoomActionError::Internal => Self::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Sets the room's topic, or clears it when there is none or it is empty.
+/// Only the room's owner can do so.
+#[instrument(skip_all, fields(room.id = room.id, actor = actor), err(Debug))]
+pub async fn set_topic(
+    state: &SharedState,
+    room: &Room,
+    actor: &str,
+    topic: Option<&str>,
+) -> Result<(), RoomActionError> {
+    if self::role(state, room, actor).await? != MemberRole::Owner {
+        return Err(RoomActionError::Forbidden(
+            "only the room's owner can change its topic",
+        ));
+    }
+    let topic = topic.map(str::trim).filter(|topic| !topic.is_empty());
+    if topic.is_some_and(|topic| topic.chars().count() as u64 > MAX_TOPIC_CHARS) {
+        return Err(RoomActionError::Conflict(format!(
+            "topics are at most {MAX_TOPIC_CHARS} characters long"
+        )));
+    }
+
+    room.set_topic(&state.db_pool, topic).await?;
+    let change = topic.map_or_else(
+        || format!("{actor} cleared the topic"),
+        |topic| format!("{actor} changed the topic to “{topic}”"),
+    );
+    rooms::announce_change(state, room, actor, &change)
+        .await
+        .map_err(|_| RoomActionError::Internal)
+}
+
+/// Adds someone to the room. Only the room's owner and moderators can do so.
+#[instrument(skip_all, fields(room.id = room.id, actor = actor, username = username), err(Debug))]
+pub async fn invite(
+    state: &SharedState,
+    room: &Room,
+    actor: &str,
+    username: &str,
+) -> Result<(), RoomActionError> {
+    if !self::role(state, room, actor).await?.can_moderate() {
+        return Err(RoomActionError::Forbidden(
+            "only the room's owner and moderators can invite people",
+        ));
+    }
+    if state.repository.accounts.find(username).await?.is_none() {
+        return Err(RoomActionError::NoSuchAccount(username.to_string()));
+    }
+    if room.has_member(&state.db_pool, username).await? {
+        return Err(RoomActionError::Conflict(format!(
+            "{username} is already a member of this room"
+        )));
+    }
+
+    room.add_member(&state.db_pool, username).await?;
+    let _ = chat::broadcast(
+        state,
+        RoomEvent::MemberJoined {
+            room_id: room.id,
+            username: username.to_string(),
+        },
+    )
+    .await;
+    let text = format!("{actor} invited {username}");
+    self::post_system_message(state, room, actor, &text).await?;
+    Ok(())
+}
+
+/// Removes someone other than the owner from the room. Only the room's owner
+/// and moderators can do so.
+#[instrument(skip_all, fields(room.id = room.id, actor = actor, username = username), err(Debug))]
+pub async fn kick(
+    state: &SharedState,
+    room: &Room,
+    actor: &str,
+    username: &str,
+) -> Result<(), RoomActionError> {
+    if !self::role(state, room, actor).await?.can_moderate() {
+        return Err(RoomActionError::Forbidden(
+            "only the room's owner and moderators can remove people",
+        ));
+    }
+    let role = room
+        .get_role(&state.db_pool, username)
+        .await?
+        .ok_or_else(|| RoomActionError::NotAMember(username.to_string()))?;
+    if username == actor {
+        return Err(RoomActionError::Conflict(
+            "leave the room instead of removing yourself".to_string(),
+        ));
+    }
+    if role == MemberRole::Owner {
+        return Err(RoomActionError::Forbidden(
+            "the room's owner can't be removed",
+        ));
+    }
+
+    room.remove_member(&state.db_pool, username).await?;
+    let _ = chat::broadcast(
+        state,
+        RoomEvent::MemberLeft {
+            room_id: room.id,
+            username: username.to_string(),
+        },
+    )
+    .await;
+    let text = format!("{actor} removed {username}");
+    self::post_system_message(state, room, actor, &text).await?;
+    Ok(())
+}
+
+/// Leaves the room. The owner can't leave, since the room would be left
+/// without anyone to look after it; deleting the room is the way out for
+/// them.
+#[instrument(skip_all, fields(room.id = room.id, member = member), err(Debug))]
+pub async fn leave(state: &SharedState, room: &Room, member: &str) -> Result<(), RoomActionError> {
+    if self::role(state, room, member).await? == MemberRole::Owner {
+        return Err(RoomActionError::Conflict(
+            "the room's owner can't leave it, but can delete it".to_string(),
+        ));
+    }
+
+    // NOTE: Recorded while still a member, as the member's sockets for the
+    // room close as soon as they learn about the departure.
+    let text = format!("{member} left the room");
+    self::post_system_message(state, room, member, &text).await?;
+    room.remove_member(&state.db_pool, member).await?;
+    let _ = chat::broadcast(
+        state,
+        RoomEvent::MemberLeft {
+            room_id: room.id,
+            username: member.to_string(),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+/// Records what someone did in the room's timeline.
+pub async fn post_system_message(
+    state: &SharedState,
+    room: &Room,
+    actor: &str,
+    text: &str,
+) -> sqlx::Result<()> {
+    let message = room
+        .send_system_message(&state.db_pool, actor, text)
+        .await?;
+    let echoed_message = message.to_echoed_message(state).await?;
+    let _ = chat::broadcast(state, RoomEvent::Message(Box::new(echoed_message))).await;
+    Ok(())
+}
+
+async fn role(
+    state: &SharedState,
+    room: &Room,
+    username: &str,
+) -> Result<MemberRole, RoomActionError> {
+    room.get_role(&state.db_pool, username)
+        .await?
+        .ok_or_else(|| RoomActionError::NotAMember(username.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository;
+
+    /// A state with a room owned by `alice`, that `bob` is a member of and
+    /// `carol` isn't.
+    async fn state_with_room() -> (SharedState, Room) {
+        let state = repository::test_state(&[]).await;
+        for username in ["alice", "bob", "carol"] {
+            state
+                .repository
+                .accounts
+                .create_placeholder(username, None)
+                .await
+                .unwrap();
+        }
+        let room = state.repository.rooms.create("general").await.unwrap();
+        room.add_member(&state.db_pool, "alice").await.unwrap();
+        room.set_role(&state.db_pool, "alice", MemberRole::Owner)
+            .await
+            .unwrap();
+        room.add_member(&state.db_pool, "bob").await.unwrap();
+        (state, room)
+    }
+
+    async fn timeline(state: &SharedState, room: &Room) -> Vec<String> {
+        room.get_messages(&state.db_pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| message.text)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn membership_changes_are_checked_and_recorded() {
+        let (state, room) = state_with_room().await;
+
+        assert!(matches!(
+            invite(&state, &room, "bob", "carol").await,
+            Err(RoomActionError::Forbidden(_))
+        ));
+        assert!(matches!(
+            invite(&state, &room, "alice", "dave").await,
+            Err(RoomActionError::NoSuchAccount(_))
+        ));
+        invite(&state, &room, "alice", "carol").await.unwrap();
+        assert!(matches!(
+            invite(&state, &room, "alice", "carol").await,
+            Err(RoomActionError::Conflict(_))
+        ));
+
+        assert!(matches!(
+            kick(&state, &room, "alice", "alice").await,
+            Err(RoomActionError::Conflict(_))
+        ));
+        kick(&state, &room, "alice", "carol").await.unwrap();
+        assert!(!room.has_member(&state.db_pool, "carol").await.unwrap());
+
+        assert!(matches!(
+            leave(&state, &room, "alice").await,
+            Err(RoomActionError::Conflict(_))
+        ));
+        leave(&state, &room, "bob").await.unwrap();
+        assert!(!room.has_member(&state.db_pool, "bob").await.unwrap());
+
+        assert_eq!(
+            timeline(&state, &room).await,
+            [
+                "alice invited carol",
+                "alice removed carol",
+                "bob left the room"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_sets_a_topic_within_the_limit() {
+        let (state, room) = state_with_room().await;
+
+        assert!(matches!(
+            set_topic(&state, &room, "bob", Some("Mine now")).await,
+            Err(RoomActionError::Forbidden(_))
+        ));
+        let too_long = "a".repeat(300);
+        assert!(matches!(
+            set_topic(&state, &room, "alice", Some(&too_long)).await,
+            Err(RoomActionError::Conflict(_))
+        ));
+        set_topic(&state, &room, "alice", Some("  Release day "))
+            .await
+            .unwrap();
+
+        let room = state
+            .repository
+            .rooms
+            .find_by_id(room.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(room.topic.as_deref(), Some("Release day"));
+        assert_eq!(
+            timeline(&state, &room).await,
+            ["alice changed the topic to “Release day”"]
+        );
+    }
+}
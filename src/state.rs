@@ -1,7 +1,11 @@
+use std::sync::Arc;
+
 use sqlx::SqlitePool;
-use tokio::sync::broadcast;
+use tokio::sync::{Notify, broadcast};
 
-use crate::endpoints::chat::EchoedMessage;
+use crate::Settings;
+use crate::endpoints::chat::{Notification, RoomEvent};
+use crate::presence::PresenceTracker;
 use crate::repository::Repository;
 
 #[derive(Debug, Clone)]
@@ -9,5 +13,26 @@ use crate::repository::Repository;
 pub struct SharedState {
     pub repository: Repository,
     pub db_pool: SqlitePool,
-    pub broadcast_tx: broadcast::Sender<EchoedMessage>,
+    pub broadcast_tx: broadcast::Sender<RoomEvent>,
+    pub notification_tx: broadcast::Sender<Notification>,
+    pub presence: PresenceTracker,
+    /// Wakes the webhook delivery worker once there are new deliveries.
+    pub webhooks_queued: Arc<Notify>,
+    pub settings: Settings,
+}
+
+impl SharedState {
+    pub fn new(db_pool: SqlitePool, settings: Settings) -> Self {
+        let (broadcast_tx, _) = broadcast::channel(settings.broadcast_channel_capacity);
+        let (notification_tx, _) = broadcast::channel(settings.broadcast_channel_capacity);
+        Self {
+            repository: Repository::new(db_pool.clone()),
+            db_pool,
+            broadcast_tx,
+            notification_tx,
+            presence: PresenceTracker::default(),
+            webhooks_queued: Arc::default(),
+            settings,
+        }
+    }
 }
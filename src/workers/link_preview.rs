@@ -0,0 +1,485 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{TimeDelta, Utc};
+use ipnet::IpNet;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::redirect::Policy;
+use scraper::{Html, Selector};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::instrument;
+use url::{Host, Url};
+
+use crate::endpoints::chat::{EchoedMessage, RoomEvent};
+use crate::repository::link_preview::{self, LinkPreview};
+use crate::state::SharedState;
+
+/// How long a fetched preview (or a failure to fetch one) is reused for.
+const CACHE_TTL: TimeDelta = TimeDelta::hours(24);
+/// The time budget for fetching a single page, redirects included.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Only this much of a page is read, which is plenty to reach its `<head>`.
+const MAX_BODY_BYTES: usize = 512 * 1024;
+const MAX_REDIRECTS: usize = 3;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 300;
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("only http(s) links are previewed")]
+    UnsupportedScheme,
+    #[error("link has no host")]
+    MissingHost,
+    #[error("{0} is not a public address")]
+    Forbidden(IpAddr),
+    #[error("host did not resolve to any address")]
+    Unresolved,
+    #[error("failed to resolve host")]
+    Resolve(#[source] std::io::Error),
+    #[error("too many redirects")]
+    TooManyRedirects,
+    #[error("redirect without a valid location")]
+    BadRedirect,
+    #[error("server responded with {0}")]
+    Status(reqwest::StatusCode),
+    #[error("not an HTML page")]
+    NotHtml,
+    #[error("timed out")]
+    Timeout,
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+/// Fetches pages for previews, refusing to talk to anything but public
+/// addresses unless they are explicitly allowed.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct Fetcher {
+    allowed_networks: Arc<[IpNet]>,
+}
+
+/// What a page says about itself.
+#[derive(Debug, Default, Clone)]
+#[must_use]
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Spawns a task that fetches previews for the links in every new message,
+/// stores them and lets the message's room know about them.
+pub fn spawn(state: SharedState) {
+    let fetcher = Fetcher::new(state.settings.link_preview_allowed_networks.clone());
+    let mut events = state.broadcast_tx.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(RoomEvent::Message(message)) => {
+                    let (state, fetcher) = (state.clone(), fetcher.clone());
+                    tokio::spawn(async move {
+                        let _ = self::unfurl(&state, &fetcher, &message).await;
+                    });
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        skipped,
+                        "Link preview worker lagged behind, skipping events"
+                    );
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Attaches previews to a message and broadcasts them, if there are any.
+#[instrument(skip_all, fields(message.id = message.id), err(Debug))]
+pub async fn unfurl(
+    state: &SharedState,
+    fetcher: &Fetcher,
+    message: &EchoedMessage,
+) -> sqlx::Result<()> {
+    let Some(text) = &message.text else {
+        return Ok(());
+    };
+    let urls = link_preview::parse_urls(text);
+    if urls.is_empty() {
+        return Ok(());
+    }
+    let Some(stored_message) = state.repository.messages.find_by_id(message.id).await? else {
+        return Ok(());
+    };
+
+    for (position, url) in (0..).zip(urls) {
+        let preview = self::cached_or_fetch(state, fetcher, url).await?;
+        stored_message
+            .attach_link_preview(&state.db_pool, &preview.url, position)
+            .await?;
+    }
+
+    let link_previews = stored_message.get_link_previews(&state.db_pool).await?;
+    if link_previews.is_empty() {
+        return Ok(());
+    }
+    tracing::debug!(count = link_previews.len(), "Broadcasting link previews");
+    let _ = state.broadcast_tx.send(RoomEvent::LinkPreviews {
+        room_id: message.room_id,
+        message_id: message.id,
+        link_previews,
+    });
+
+    Ok(())
+}
+
+async fn cached_or_fetch(
+    state: &SharedState,
+    fetcher: &Fetcher,
+    url: Url,
+) -> sqlx::Result<LinkPreview> {
+    let now = Utc::now().naive_utc();
+    let repository = &state.repository.link_previews;
+    if let Some(cached) = repository.find_fresh(url.as_str(), now - CACHE_TTL).await? {
+        return Ok(cached);
+    }
+
+    let metadata = fetcher
+        .fetch(url.clone())
+        .await
+        .inspect_err(|error| tracing::debug!(%url, %error, "Failed to fetch link preview"));
+    let fetch_failed = metadata.is_err();
+    let metadata = metadata.unwrap_or_default();
+    let preview = LinkPreview {
+        url: url.to_string(),
+        title: metadata.title,
+        description: metadata.description,
+        fetch_failed,
+        fetched_at: now,
+    };
+    repository.save(&preview).await?;
+    Ok(preview)
+}
+
+impl Fetcher {
+    pub fn new(allowed_networks: Vec<IpNet>) -> Self {
+        Self {
+            allowed_networks: allowed_networks.into(),
+        }
+    }
+
+    /// Fetches a page and extracts its metadata, following a few redirects.
+    pub async fn fetch(&self, url: Url) -> Result<PageMetadata, FetchError> {
+        tokio::time::timeout(FETCH_TIMEOUT, self.fetch_following_redirects(url))
+            .await
+            .map_err(|_| FetchError::Timeout)?
+    }
+
+    async fn fetch_following_redirects(&self, mut url: Url) -> Result<PageMetadata, FetchError> {
+        for _ in 0..=MAX_REDIRECTS {
+            // NOTE: The client is pinned to the address we checked, so that the
+            // host can't resolve to something else by the time we connect.
+            let address = self.resolve(&url).await?;
+            let mut client = reqwest::Client::builder()
+                .redirect(Policy::none())
+                .user_agent(USER_AGENT)
+                .timeout(FETCH_TIMEOUT);
+            if let Some(Host::Domain(domain)) = url.host() {
+                client = client.resolve(domain, address);
+            }
+            let mut response = client.build()?.get(url.clone()).send().await?;
+
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| url.join(location).ok())
+                    .ok_or(FetchError::BadRedirect)?;
+                url = location;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(FetchError::Status(status));
+            }
+
+            let is_html = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| content_type.starts_with("text/html"));
+            if !is_html {
+                return Err(FetchError::NotHtml);
+            }
+
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                body.extend_from_slice(&chunk);
+                if body.len() >= MAX_BODY_BYTES {
+                    body.truncate(MAX_BODY_BYTES);
+                    break;
+                }
+            }
+            return Ok(PageMetadata::parse(&String::from_utf8_lossy(&body)));
+        }
+
+        Err(FetchError::TooManyRedirects)
+    }
+
+    /// Resolves the link's host, making sure that every address it resolves
+    /// to may be connected to.
+    pub async fn resolve(&self, url: &Url) -> Result<SocketAddr, FetchError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::UnsupportedScheme);
+        }
+        let port = url
+            .port_or_known_default()
+            .ok_or(FetchError::UnsupportedScheme)?;
+        let addresses: Vec<SocketAddr> = match url.host().ok_or(FetchError::MissingHost)? {
+            Host::Ipv4(ip) => vec![SocketAddr::new(ip.into(), port)],
+            Host::Ipv6(ip) => vec![SocketAddr::new(ip.into(), port)],
+            Host::Domain(domain) => tokio::net::lookup_host((domain, port))
+                .await
+                .map_err(FetchError::Resolve)?
+                .collect(),
+        };
+
+        if let Some(forbidden) = addresses
+            .iter()
+            .map(SocketAddr::ip)
+            .find(|ip| !self.is_allowed(*ip))
+        {
+            return Err(FetchError::Forbidden(forbidden));
+        }
+        addresses.first().copied().ok_or(FetchError::Unresolved)
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        is_public(ip) || self.allowed_networks.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Whether an address is routable on the public internet, as opposed to
+/// loopback, private, link-local and other special-purpose ranges.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let is_shared = a == 100 && (64..128).contains(&b);
+            let is_reserved = a >= 240;
+            let is_benchmarking = a == 198 && (18..20).contains(&b);
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || is_shared
+                || is_reserved
+                || is_benchmarking
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // NOTE: NAT64 and 6to4 addresses lead to the IPv4 address embedded
+            // in them, which has to be public itself.
+            if let Some(embedded) = self::embedded_ipv4(ip) {
+                return is_public(IpAddr::V4(embedded));
+            }
+            let is_unique_local = segments[0] & 0xfe00 == 0xfc00;
+            let is_link_local = segments[0] & 0xffc0 == 0xfe80;
+            let is_documentation = segments[0] == 0x2001 && segments[1] == 0xdb8;
+            let is_local_nat64 = segments[0] == 0x64 && segments[1] == 0xff9b && segments[2] == 1;
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || is_unique_local
+                || is_link_local
+                || is_documentation
+                || is_local_nat64)
+        }
+    }
+}
+
+/// The IPv4 address that a NAT64 (`64:ff9b::/96`) or 6to4 (`2002::/16`)
+/// address translates to.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let is_nat64 = ip.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+    let is_6to4 = ip.segments()[0] == 0x2002;
+    if is_nat64 {
+        Some(Ipv4Addr::new(
+            octets[12], octets[13], octets[14], octets[15],
+        ))
+    } else if is_6to4 {
+        Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5]))
+    } else {
+        None
+    }
+}
+
+impl PageMetadata {
+    /// Picks the Open Graph metadata out of a page, falling back to its plain
+    /// `<title>` and description.
+    ///
+    /// Images are left out on purpose: showing them would have every reader's
+    /// browser fetch them from wherever the page points to.
+    pub fn parse(html: &str) -> Self {
+        let document = Html::parse_document(html);
+        let find = |selectors: &[&str]| {
+            selectors.iter().find_map(|selector| {
+                let selector = Selector::parse(selector).ok()?;
+                let element = document.select(&selector).next()?;
+                let value = match element.value().name() {
+                    "meta" => element.value().attr("content")?.to_string(),
+                    _ => element.text().collect(),
+                };
+                let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+                (!value.is_empty()).then_some(value)
+            })
+        };
+
+        let title = find(&[r#"meta[property="og:title"]"#, "title"])
+            .map(|title| truncate(&title, MAX_TITLE_CHARS));
+        let description = find(&[
+            r#"meta[property="og:description"]"#,
+            r#"meta[name="description"]"#,
+        ])
+        .map(|description| truncate(&description, MAX_DESCRIPTION_CHARS));
+
+        Self { title, description }
+    }
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use axum::response::Html;
+    use axum::routing::get;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn is_public_address(address: &str) -> bool {
+        is_public(address.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_public() {
+        for address in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(is_public_address(address), "{address}");
+        }
+    }
+
+    #[test]
+    fn special_purpose_addresses_are_not_public() {
+        for address in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fc00::1",
+            "fe80::1",
+            "2001:db8::1",
+            "ff02::1",
+            "64:ff9b:1::1",
+        ] {
+            assert!(!is_public_address(address), "{address}");
+        }
+    }
+
+    #[test]
+    fn translated_addresses_are_as_public_as_the_embedded_one() {
+        // NOTE: 64:ff9b::7f00:1 and 2002:7f00:1:: both lead to 127.0.0.1.
+        assert!(!is_public_address("64:ff9b::7f00:1"));
+        assert!(!is_public_address("64:ff9b::a9fe:a9fe"));
+        assert!(!is_public_address("2002:7f00:1::"));
+        assert!(!is_public_address("2002:c0a8:101::1"));
+        assert!(is_public_address("64:ff9b::101:101"));
+        assert!(is_public_address("2002:101:101::1"));
+    }
+
+    #[test]
+    fn mapped_addresses_are_checked_as_ipv4() {
+        let fetcher = Fetcher::new(vec![]);
+        assert!(!fetcher.is_allowed("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(fetcher.is_allowed("::ffff:1.1.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_prefers_open_graph() {
+        let metadata = PageMetadata::parse(
+            r#"<html><head>
+                <title>Plain title</title>
+                <meta property="og:title" content="  Open   Graph title ">
+                <meta name="description" content="Plain description">
+                <meta property="og:description" content="Open Graph description">
+                <meta property="og:image" content="/images/cover.png">
+            </head></html>"#,
+        );
+        assert_eq!(metadata.title.as_deref(), Some("Open Graph title"));
+        assert_eq!(
+            metadata.description.as_deref(),
+            Some("Open Graph description")
+        );
+    }
+
+    #[test]
+    fn parse_falls_back_to_plain_metadata() {
+        let long_title = "a".repeat(MAX_TITLE_CHARS + 10);
+        let metadata = PageMetadata::parse(&format!(
+            r#"<title>{long_title}</title>
+                <meta name="description" content="Plain description">"#
+        ));
+        let title = metadata.title.unwrap();
+        assert_eq!(title.chars().count(), MAX_TITLE_CHARS + 1);
+        assert!(title.ends_with('…'));
+        assert_eq!(metadata.description.as_deref(), Some("Plain description"));
+    }
+
+    async fn serve_page() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router =
+            Router::new().route("/", get(|| async { Html("<title>Local stand-in</title>") }));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        Url::parse(&format!("http://{address}/")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn local_servers_are_refused_by_default() {
+        let url = serve_page().await;
+        let result = Fetcher::new(vec![]).fetch(url).await;
+        assert!(
+            matches!(result, Err(FetchError::Forbidden(_))),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn allowed_networks_may_be_fetched_from() {
+        let url = serve_page().await;
+        let fetcher = Fetcher::new(vec!["127.0.0.0/8".parse().unwrap()]);
+        let metadata = fetcher.fetch(url).await.unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Local stand-in"));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod link_preview;
+pub mod upload_gc;
+pub mod webhook_delivery;
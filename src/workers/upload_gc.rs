@@ -0,0 +1,171 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use tracing::instrument;
+
+use crate::repository::upload::{STORE_DIRECTORY, THUMBNAIL_DIRECTORY, Upload};
+use crate::state::SharedState;
+
+/// Files and uploads younger than this are never treated as orphans, since an
+/// upload writes its file before inserting the row for it, and the row before
+/// the message or profile that refers to it.
+const GRACE_PERIOD: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Default)]
+#[must_use]
+pub struct ReconcileReport {
+    pub orphaned_files: usize,
+    pub orphaned_thumbnails: usize,
+    pub missing_files: usize,
+    pub unreferenced_uploads: usize,
+    pub expired_uploads: usize,
+    pub sized_uploads: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub enum ReconcileError {
+    Io(#[from] std::io::Error),
+    Database(#[from] sqlx::Error),
+}
+
+/// Spawns a task that reconciles the upload store with the database every
+/// `interval`, for as long as the server is running.
+pub fn spawn(state: SharedState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let _ = self::reconcile(&state)
+                .await
+                .inspect(|report| tracing::info!(?report, "Reconciled upload store"))
+                .inspect_err(|error| tracing::error!(?error, "Failed to reconcile upload store"));
+        }
+    });
+}
+
+/// Brings the upload store and the `file_uploads` table back in sync:
+///
+/// - files on disk without a live row are removed, as are thumbnails that were
+///   never finished,
+/// - rows whose file is missing become tombstones (or are removed entirely if
+///   no message refers to them),
+/// - uploads that no message or avatar refers to are removed along with their
+///   files,
+/// - attachments past their room's retention period become tombstones,
+/// - uploads from before sizes were recorded get the size of their file.
+#[instrument(skip_all, err(Debug))]
+pub async fn reconcile(state: &SharedState) -> Result<ReconcileReport, ReconcileError> {
+    let repository = &state.repository.uploads;
+    let mut report = ReconcileReport::default();
+
+    let uploads: HashMap<String, Upload> = repository
+        .find_all()
+        .await?
+        .into_iter()
+        .map(|upload| (upload.uuid.clone(), upload))
+        .collect();
+    let is_live = |uuid: &str| uploads.get(uuid).is_some_and(|upload| !upload.is_deleted());
+
+    for (uuid, path) in self::list_store(STORE_DIRECTORY).await? {
+        if !is_live(&uuid) && self::is_past_grace_period(&path).await? {
+            tracing::debug!(?path, "Removing file without an upload row");
+            tokio::fs::remove_file(&path).await?;
+            report.orphaned_files += 1;
+        }
+    }
+    for (uuid, path) in self::list_store(THUMBNAIL_DIRECTORY).await? {
+        // NOTE: Thumbnails are written to a `.partial` file and moved into
+        // place once complete, so only a crash leaves an old one behind.
+        let is_leftover = path
+            .extension()
+            .is_some_and(|extension| extension == "partial")
+            && self::is_past_grace_period(&path).await?;
+        if !is_live(&uuid) || is_leftover {
+            tracing::debug!(
+                ?path,
+                "Removing thumbnail without an upload row or left unfinished"
+            );
+            tokio::fs::remove_file(&path).await?;
+            report.orphaned_thumbnails += 1;
+        }
+    }
+
+    let unreferenced: HashSet<String> = repository
+        .find_unreferenced(GRACE_PERIOD)
+        .await?
+        .into_iter()
+        .map(|upload| upload.uuid)
+        .collect();
+
+    for upload in uploads.values().filter(|upload| !upload.is_deleted()) {
+        let is_referenced = !unreferenced.contains(&upload.uuid);
+        match upload.store_path() {
+            Err(_) if is_referenced => {
+                tracing::debug!(upload.uuid, "Stored file is missing, leaving a tombstone");
+                repository.tombstone(&upload.uuid).await?;
+                report.missing_files += 1;
+            }
+            Err(_) => {
+                tracing::debug!(upload.uuid, "Stored file is missing, removing the upload");
+                repository.delete(&upload.uuid).await?;
+                report.missing_files += 1;
+            }
+            Ok(_) if !is_referenced => {
+                tracing::debug!(
+                    upload.uuid,
+                    "Nothing refers to the upload anymore, removing it"
+                );
+                upload.remove_files().await?;
+                repository.delete(&upload.uuid).await?;
+                report.unreferenced_uploads += 1;
+            }
+            Ok(path) if upload.size == 0 => {
+                let size = tokio::fs::metadata(&path).await?.len();
+                if size > 0 {
+                    tracing::debug!(upload.uuid, size, "Recording the size of an older upload");
+                    repository
+                        .fill_in_size(&upload.uuid, i64::try_from(size).unwrap_or(i64::MAX))
+                        .await?;
+                    report.sized_uploads += 1;
+                }
+            }
+            Ok(_) => { /* The upload is alive and well */ }
+        }
+    }
+
+    for upload in repository.find_expired().await? {
+        tracing::debug!(upload.uuid, "Upload is past its retention period");
+        upload.remove_files().await?;
+        repository.tombstone(&upload.uuid).await?;
+        report.expired_uploads += 1;
+    }
+
+    Ok(report)
+}
+
+/// Lists files in a store directory along with the upload UUIDs their names
+/// start with. Anything not named like a stored file is left alone.
+async fn list_store(directory: &str) -> Result<Vec<(String, std::path::PathBuf)>, std::io::Error> {
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    let mut files = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some((uuid, _)) = file_name.to_str().and_then(|name| name.split_once('_')) else {
+            continue;
+        };
+        if uuid.parse::<uuid::Uuid>().is_ok() && entry.file_type().await?.is_file() {
+            files.push((uuid.to_string(), entry.path()));
+        }
+    }
+    Ok(files)
+}
+
+async fn is_past_grace_period(path: &Path) -> Result<bool, std::io::Error> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    Ok(age > GRACE_PERIOD)
+}
@@ -0,0 +1,503 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use futures::future;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect::Policy;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::instrument;
+use url::{Host, Url};
+
+use crate::endpoints::chat::RoomEvent;
+use crate::repository::webhook::{OutgoingWebhook, WebhookDelivery};
+use crate::state::SharedState;
+use crate::workers::link_preview::{FetchError, Fetcher};
+
+/// The time budget for a single delivery attempt.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries are given up on after this many failed attempts.
+const MAX_ATTEMPTS: i64 = 8;
+/// How many due deliveries are attempted at once.
+const BATCH_SIZE: i64 = 32;
+/// Due retries are looked for at least this often, on top of whenever a new
+/// delivery is queued.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Deliveries that went through are kept around this long.
+const DELIVERED_RETENTION: TimeDelta = TimeDelta::days(7);
+/// How often delivered deliveries past their retention are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of the timestamp header,
+/// a dot and the request body, keyed with the webhook's secret.
+pub const SIGNATURE_HEADER: &str = "X-Os3-Signature";
+/// Seconds since the epoch at the time of the attempt, so that receivers can
+/// reject replayed requests.
+pub const TIMESTAMP_HEADER: &str = "X-Os3-Timestamp";
+pub const EVENT_HEADER: &str = "X-Os3-Event";
+/// Stays the same across retries of a delivery.
+pub const DELIVERY_HEADER: &str = "X-Os3-Delivery";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeliveryError {
+    #[error("invalid webhook URL")]
+    InvalidUrl,
+    #[error(transparent)]
+    Address(#[from] FetchError),
+    #[error("receiver responded with {0}")]
+    Status(reqwest::StatusCode),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+/// The body of every delivery.
+#[derive(Serialize, Debug)]
+#[must_use]
+pub struct WebhookEnvelope<'a> {
+    pub event: &'a str,
+    pub room_id: i64,
+    pub occurred_at: NaiveDateTime,
+    pub data: serde_json::Value,
+}
+
+/// Spawns the tasks that make deliveries and prune old ones.
+///
+/// Deliveries are queued by [`enqueue`] as events happen, and failed ones are
+/// retried with an exponential backoff.
+pub fn spawn(state: SharedState) {
+    let prune_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let _ = self::prune(&prune_state).await;
+        }
+    });
+
+    let fetcher = Fetcher::new(state.settings.webhook_allowed_networks.clone());
+    let retry_base = Duration::from_secs(state.settings.webhook_retry_base_secs);
+    tokio::spawn(async move {
+        loop {
+            let attempted = self::deliver_due(&state, &fetcher, retry_base).await;
+            if attempted
+                .is_ok_and(|count| i64::try_from(count).is_ok_and(|count| count >= BATCH_SIZE))
+            {
+                // NOTE: There may be more due right away.
+                continue;
+            }
+            tokio::select! {
+                () = state.webhooks_queued.notified() => {}
+                () = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+/// The event's name and data as delivered, if webhooks are told about it.
+fn to_webhook_event(event: &RoomEvent) -> Option<(&'static str, serde_json::Value)> {
+    let (name, data) = match event {
+        RoomEvent::Message(message) if message.upload_url.is_some() => {
+            ("upload", serde_json::to_value(message))
+        }
+        RoomEvent::Message(message) => ("message", serde_json::to_value(message)),
+        RoomEvent::MemberJoined { username, .. } => (
+            "member_joined",
+            Ok(serde_json::json!({ "username": username })),
+        ),
+        RoomEvent::MemberLeft { username, .. } => (
+            "member_left",
+            Ok(serde_json::json!({ "username": username })),
+        ),
+        _ => return None,
+    };
+    data.ok().map(|data| (name, data))
+}
+
+/// Queues the event for the room's webhooks, if they are told about it, and
+/// wakes the worker. Returns how many deliveries were queued.
+#[instrument(skip_all, fields(room.id = event.room_id()), err(Debug))]
+pub async fn enqueue(state: &SharedState, event: &RoomEvent) -> sqlx::Result<u64> {
+    let Some((name, data)) = self::to_webhook_event(event) else {
+        return Ok(0);
+    };
+    let envelope = WebhookEnvelope {
+        event: name,
+        room_id: event.room_id(),
+        occurred_at: Utc::now().naive_utc(),
+        data,
+    };
+    let payload =
+        serde_json::to_string(&envelope).map_err(|error| sqlx::Error::Encode(error.into()))?;
+    let queued = state
+        .repository
+        .webhooks
+        .enqueue_deliveries(envelope.room_id, name, &payload)
+        .await?;
+    if queued > 0 {
+        state.webhooks_queued.notify_one();
+    }
+    Ok(queued)
+}
+
+/// Removes deliveries that went through longer than [`DELIVERED_RETENTION`]
+/// ago. Ones that were given up on are kept as dead letters.
+#[instrument(skip_all, err(Debug))]
+async fn prune(state: &SharedState) -> sqlx::Result<()> {
+    let cutoff = Utc::now().naive_utc() - DELIVERED_RETENTION;
+    let pruned = state
+        .repository
+        .webhooks
+        .delete_delivered_before(cutoff)
+        .await?;
+    tracing::debug!(pruned, "Pruned delivered webhook deliveries");
+    Ok(())
+}
+
+/// Attempts every delivery that is due. Returns how many there were.
+#[instrument(skip_all, err(Debug))]
+pub async fn deliver_due(
+    state: &SharedState,
+    fetcher: &Fetcher,
+    retry_base: Duration,
+) -> sqlx::Result<usize> {
+    let deliveries = state
+        .repository
+        .webhooks
+        .find_due_deliveries(BATCH_SIZE)
+        .await?;
+    let attempts = deliveries
+        .iter()
+        .map(|delivery| self::attempt(state, fetcher, delivery, retry_base));
+    for result in future::join_all(attempts).await {
+        result?;
+    }
+    Ok(deliveries.len())
+}
+
+#[instrument(skip_all, fields(delivery.id = delivery.id, webhook.id = delivery.webhook_id), err(Debug))]
+async fn attempt(
+    state: &SharedState,
+    fetcher: &Fetcher,
+    delivery: &WebhookDelivery,
+    retry_base: Duration,
+) -> sqlx::Result<()> {
+    let repository = &state.repository.webhooks;
+    // NOTE: The webhook may have been deleted since the delivery was queued,
+    // which deletes the delivery too.
+    let Some(webhook) = repository.find_outgoing(delivery.webhook_id).await? else {
+        return Ok(());
+    };
+
+    match self::send(fetcher, &webhook, delivery).await {
+        Ok(()) => {
+            tracing::debug!(delivery.event, "Delivered webhook event");
+            repository.mark_delivered(delivery.id).await
+        }
+        Err(error) => {
+            let attempts = delivery.attempts + 1;
+            let next_attempt_at = (attempts < MAX_ATTEMPTS)
+                .then(|| Utc::now().naive_utc() + self::backoff(retry_base, attempts));
+            match next_attempt_at {
+                Some(next_attempt_at) => {
+                    tracing::debug!(%error, attempts, %next_attempt_at, "Webhook delivery failed, retrying later");
+                }
+                None => {
+                    tracing::warn!(%error, attempts, "Webhook delivery failed, giving up");
+                }
+            }
+            repository
+                .record_failure(delivery.id, &error.to_string(), next_attempt_at)
+                .await
+        }
+    }
+}
+
+/// How long to wait after the given number of failed attempts.
+fn backoff(retry_base: Duration, attempts: i64) -> TimeDelta {
+    let doublings = u32::try_from(attempts - 1).unwrap_or(0).min(16);
+    TimeDelta::from_std(retry_base.saturating_mul(1 << doublings)).unwrap_or(TimeDelta::days(1))
+}
+
+async fn send(
+    fetcher: &Fetcher,
+    webhook: &OutgoingWebhook,
+    delivery: &WebhookDelivery,
+) -> Result<(), DeliveryError> {
+    let url = Url::parse(&webhook.url).map_err(|_| DeliveryError::InvalidUrl)?;
+    // NOTE: Same as for link previews, the client is pinned to the checked
+    // address, and redirects aren't followed to somewhere unchecked.
+    let address = fetcher.resolve(&url).await?;
+    let mut client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .user_agent(USER_AGENT)
+        .timeout(DELIVERY_TIMEOUT);
+    if let Some(Host::Domain(domain)) = url.host() {
+        client = client.resolve(domain, address);
+    }
+
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature = self::sign(&webhook.secret, &timestamp, &delivery.payload);
+    let response = client
+        .build()?
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .body(delivery.payload.clone())
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(DeliveryError::Status(status));
+    }
+    Ok(())
+}
+
+/// The value of the signature header for a delivery, see [`SIGNATURE_HEADER`].
+fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    digest
+        .iter()
+        .fold(String::from("sha256="), |mut signature, byte| {
+            let _ = write!(signature, "{byte:02x}");
+            signature
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::repository;
+
+    #[test]
+    fn sign_matches_known_vector() {
+        assert_eq!(
+            sign(
+                "It is a secret to everybody",
+                "1700000000",
+                r#"{"event":"message"}"#
+            ),
+            "sha256=9957ec3ac0b0ba5d77a0cddfafc278afc5de670a3af5d7809037ff601924ee6d"
+        );
+    }
+
+    /// A local stand-in for a receiver, failing the first `failures` requests
+    /// with a 500.
+    #[derive(Clone, Default)]
+    struct Receiver {
+        failures: Arc<AtomicUsize>,
+        requests: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    impl Receiver {
+        async fn serve(failures: usize) -> (Self, String) {
+            let receiver = Self::default();
+            receiver.failures.store(failures, Ordering::SeqCst);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let router = Router::new()
+                .route(
+                    "/hook",
+                    post(|receiver, headers, body| async {
+                        Self::receive(receiver, headers, body)
+                    }),
+                )
+                .with_state(receiver.clone());
+            tokio::spawn(async move { axum::serve(listener, router).await });
+            (receiver, format!("http://{address}/hook"))
+        }
+
+        fn receive(State(receiver): State<Self>, headers: HeaderMap, body: String) -> StatusCode {
+            receiver.requests.lock().unwrap().push((headers, body));
+            let failing = receiver
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok();
+            if failing {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                StatusCode::OK
+            }
+        }
+
+        fn requests(&self) -> Vec<(HeaderMap, String)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    /// A state backed by a fresh in-memory database, with a room whose one
+    /// outgoing webhook points at `url`.
+    async fn state_with_webhook(url: &str) -> (SharedState, OutgoingWebhook) {
+        let state = repository::test_state(&["--webhook-allowed-networks", "127.0.0.0/8"]).await;
+
+        state
+            .repository
+            .accounts
+            .create_placeholder("alice", None)
+            .await
+            .unwrap();
+        let room = state.repository.rooms.create("hooked").await.unwrap();
+        let webhook = state
+            .repository
+            .webhooks
+            .create_outgoing(room.id, url, "alice")
+            .await
+            .unwrap();
+        (state, webhook)
+    }
+
+    /// Makes pending retries due right away, rather than waiting for the
+    /// database clock to tick over.
+    async fn make_retries_due(state: &SharedState) {
+        sqlx::query("UPDATE webhook_deliveries SET next_attempt_at = datetime('now', '-1 second')")
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+    }
+
+    async fn deliver(state: &SharedState) -> usize {
+        let fetcher = Fetcher::new(state.settings.webhook_allowed_networks.clone());
+        deliver_due(state, &fetcher, Duration::ZERO).await.unwrap()
+    }
+
+    fn joined(webhook: &OutgoingWebhook) -> RoomEvent {
+        RoomEvent::MemberJoined {
+            room_id: webhook.room_id,
+            username: "bob".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn local_receivers_are_refused_by_default() {
+        let (receiver, url) = Receiver::serve(0).await;
+        let (state, webhook) = state_with_webhook(&url).await;
+        assert_eq!(enqueue(&state, &joined(&webhook)).await.unwrap(), 1);
+
+        let fetcher = Fetcher::new(vec![]);
+        assert_eq!(
+            deliver_due(&state, &fetcher, Duration::ZERO).await.unwrap(),
+            1
+        );
+        assert!(receiver.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried_with_signed_requests() {
+        let (receiver, url) = Receiver::serve(1).await;
+        let (state, webhook) = state_with_webhook(&url).await;
+        assert_eq!(enqueue(&state, &joined(&webhook)).await.unwrap(), 1);
+
+        assert_eq!(deliver(&state).await, 1);
+        make_retries_due(&state).await;
+        assert_eq!(deliver(&state).await, 1);
+        make_retries_due(&state).await;
+        assert_eq!(deliver(&state).await, 0);
+
+        let requests = receiver.requests();
+        assert_eq!(requests.len(), 2);
+        let [(first, _), (headers, body)] = &requests[..] else {
+            unreachable!();
+        };
+        let header = |name| headers[name].to_str().unwrap();
+        assert_eq!(header(EVENT_HEADER), "member_joined");
+        assert_eq!(header(DELIVERY_HEADER), first[DELIVERY_HEADER]);
+        let timestamp: i64 = header(TIMESTAMP_HEADER).parse().unwrap();
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(
+            header(SIGNATURE_HEADER),
+            sign(&webhook.secret, header(TIMESTAMP_HEADER), body)
+        );
+        let envelope: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(envelope["event"], "member_joined");
+        assert_eq!(envelope["room_id"], webhook.room_id);
+        assert_eq!(envelope["data"]["username"], "bob");
+
+        let dead_letters = state
+            .repository
+            .webhooks
+            .find_dead_letters(webhook.room_id)
+            .await
+            .unwrap();
+        assert!(dead_letters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_given_up_on_after_max_attempts() {
+        let (receiver, url) = Receiver::serve(usize::MAX).await;
+        let (state, webhook) = state_with_webhook(&url).await;
+        assert_eq!(enqueue(&state, &joined(&webhook)).await.unwrap(), 1);
+
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(deliver(&state).await, 1);
+            make_retries_due(&state).await;
+        }
+        assert_eq!(deliver(&state).await, 0);
+        assert_eq!(
+            receiver.requests().len(),
+            usize::try_from(MAX_ATTEMPTS).unwrap()
+        );
+
+        let dead_letters = state
+            .repository
+            .webhooks
+            .find_dead_letters(webhook.room_id)
+            .await
+            .unwrap();
+        let [dead_letter] = &dead_letters[..] else {
+            panic!("expected a single dead letter, got {dead_letters:?}");
+        };
+        assert_eq!(dead_letter.attempts, MAX_ATTEMPTS);
+        assert!(
+            dead_letter
+                .last_error
+                .as_deref()
+                .is_some_and(|error| error.contains("500"))
+        );
+    }
+
+    #[tokio::test]
+    async fn delivered_deliveries_are_pruned() {
+        let (_, url) = Receiver::serve(0).await;
+        let (state, webhook) = state_with_webhook(&url).await;
+        assert_eq!(enqueue(&state, &joined(&webhook)).await.unwrap(), 1);
+        assert_eq!(deliver(&state).await, 1);
+
+        let count = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM webhook_deliveries")
+                .fetch_one(&state.db_pool)
+                .await
+                .unwrap()
+        };
+        prune(&state).await.unwrap();
+        assert_eq!(count().await, 1);
+
+        sqlx::query("UPDATE webhook_deliveries SET delivered_at = datetime('now', '-8 days')")
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+        prune(&state).await.unwrap();
+        assert_eq!(count().await, 0);
+    }
+}